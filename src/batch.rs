@@ -0,0 +1,181 @@
+use std::collections::BTreeSet;
+use std::iter::{self, Peekable};
+use std::vec;
+
+use crate::key::KeyOrder;
+use crate::stats::StatsCollector;
+use crate::{BaseLagBuffer, Event, State, TieBreak, UpdateOutcome};
+
+/// The result of feeding a batch of events into a lag buffer with `update_batch`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BatchOutcome {
+    /// The number of events that became part of the state.
+    pub accepted: usize,
+    /// The number of events that were not accepted, see [`UpdateOutcome::is_accepted`].
+    pub rejected: usize,
+    /// The total number of events replayed while applying the batch.
+    pub replayed: usize,
+    /// `true` if an event of the batch was reported as [`UpdateOutcome::Swapped`], i.e. the
+    /// buffer retired its oldest events while applying the batch.
+    pub swapped: bool,
+}
+
+impl BatchOutcome {
+    // Adds the outcome of a single event of the batch
+    pub(crate) fn record(&mut self, outcome: UpdateOutcome) {
+        self.record_merged(outcome, 1);
+    }
+
+    // Adds the outcome of `events` accepted events that were reconciled together
+    pub(crate) fn record_merged(&mut self, outcome: UpdateOutcome, events: usize) {
+        if outcome.is_accepted() {
+            self.accepted += events;
+        } else {
+            self.rejected += events;
+        }
+        self.replayed += outcome.replayed();
+        self.swapped |= matches!(outcome, UpdateOutcome::Swapped { .. });
+    }
+}
+
+// The parts of a buffer `update_batch` uses besides the `BaseLagBuffer` methods
+pub(crate) trait BatchBuffer<S: State<OrderKey>, OrderKey: Ord + Clone>:
+    BaseLagBuffer<S, OrderKey>
+{
+    // The buffer type recorded on trace spans
+    #[cfg_attr(not(feature = "tracing"), allow(dead_code))]
+    const NAME: &'static str;
+
+    fn tie_break(&self) -> &TieBreak<S, OrderKey>;
+
+    fn key_order(&self) -> KeyOrder<OrderKey>;
+
+    fn stats_collector(&mut self) -> &mut StatsCollector<OrderKey>;
+
+    // The key of the last event folded into the base state. Unlike `base_key`, this is never a
+    // watermark, so it is what duplicates are checked against.
+    fn folded_key(&self) -> Option<OrderKey>;
+
+    // Merges sorted events that are older than the head key into the buffer and replays it once
+    fn merge_batch(&mut self, events: Vec<S::Event>) -> UpdateOutcome;
+}
+
+// Feeds a batch into a buffer, see `update_batch` of the buffers. Late events and events that
+// don't need a replay go through `update`, all others are merged with a single replay.
+pub(crate) fn update_batch<S, OrderKey, B>(
+    buffer: &mut B,
+    events: impl IntoIterator<Item = S::Event>,
+) -> BatchOutcome
+where
+    S: State<OrderKey>,
+    OrderKey: Ord + Clone,
+    B: BatchBuffer<S, OrderKey>,
+{
+    let mut batch = BatchOutcome::default();
+    let events: Vec<_> = events.into_iter().collect();
+    let keys = buffer
+        .key_order()
+        .or_anchor(|| events.first().map(S::Event::get_order_key));
+    let mut events = sort_batch(events, buffer.tie_break(), &keys);
+    trace_span!(
+        _span,
+        "update_batch",
+        buffer = B::NAME,
+        len = buffer.len(),
+        batch = events.len()
+    );
+
+    // Late events sort first
+    while let Some(event) = events.next_if(|e| {
+        buffer
+            .base_key()
+            .is_some_and(|base_key| keys.lt(&e.get_order_key(), &base_key))
+    }) {
+        batch.record(buffer.update(event));
+    }
+
+    let head_key = buffer.head_key();
+    let mut reordered = take_reordered(&mut events, head_key.as_ref(), &keys);
+    let known_keys = buffer
+        .folded_key()
+        .into_iter()
+        .chain(buffer.events().map(S::Event::get_order_key));
+    let duplicates = take_duplicates(&mut reordered, known_keys, buffer.tie_break());
+    if !reordered.is_empty() {
+        let keys: Vec<_> = reordered.iter().map(S::Event::get_order_key).collect();
+        let outcome = buffer.merge_batch(reordered);
+        buffer
+            .stats_collector()
+            .record_merged(outcome, head_key.as_ref(), &keys);
+        batch.record_merged(outcome, keys.len());
+    }
+
+    // Duplicates are rejected once the events they duplicate are buffered
+    for event in duplicates.into_iter().chain(events) {
+        batch.record(buffer.update(event));
+    }
+    batch
+}
+
+// A batch of events sorted by key and tie-break, events that still tie keep their order
+pub(crate) type SortedBatch<E> = Peekable<vec::IntoIter<E>>;
+
+pub(crate) fn sort_batch<S: State<OrderKey>, OrderKey: Ord>(
+    mut events: Vec<S::Event>,
+    tie_break: &TieBreak<S, OrderKey>,
+    keys: &KeyOrder<OrderKey>,
+) -> SortedBatch<S::Event> {
+    events.sort_by(|a, b| tie_break.compare(keys, a, b));
+    events.into_iter().peekable()
+}
+
+// Takes the events that are older than the head key off the front of a sorted batch.
+// These are the events that need a replay.
+pub(crate) fn take_reordered<OrderKey: Ord, E: Event<OrderKey>>(
+    batch: &mut SortedBatch<E>,
+    head_key: Option<&OrderKey>,
+    keys: &KeyOrder<OrderKey>,
+) -> Vec<E> {
+    let Some(head_key) = head_key else {
+        return Vec::new();
+    };
+    iter::from_fn(|| batch.next_if(|event| keys.lt(&event.get_order_key(), head_key))).collect()
+}
+
+// Splits the events off a sorted batch that the tie-break rejects as duplicates of a known key
+// or of an earlier event in the batch. `update` rejects them once the others are buffered.
+pub(crate) fn take_duplicates<S: State<OrderKey>, OrderKey: Ord>(
+    events: &mut Vec<S::Event>,
+    known_keys: impl IntoIterator<Item = OrderKey>,
+    tie_break: &TieBreak<S, OrderKey>,
+) -> Vec<S::Event> {
+    if !matches!(tie_break, TieBreak::RejectDuplicates) {
+        return Vec::new();
+    }
+    let mut keys: BTreeSet<_> = known_keys.into_iter().collect();
+    let (unique, duplicates) = std::mem::take(events)
+        .into_iter()
+        .partition(|event| keys.insert(event.get_order_key()));
+    *events = unique;
+    duplicates
+}
+
+// Merges sorted events into sorted buffered events. Buffered events come first among ties, as if
+// the events had arrived one by one.
+pub(crate) fn merge_by_key<S: State<OrderKey>, OrderKey: Ord>(
+    buffered: impl IntoIterator<Item = S::Event>,
+    events: Vec<S::Event>,
+    tie_break: &TieBreak<S, OrderKey>,
+    keys: &KeyOrder<OrderKey>,
+) -> Vec<S::Event> {
+    let mut buffered = buffered.into_iter().peekable();
+    let mut merged = Vec::with_capacity(buffered.size_hint().0 + events.len());
+    for event in events {
+        merged.extend(iter::from_fn(|| {
+            buffered.next_if(|buffered| tie_break.is_after(keys, buffered, &event))
+        }));
+        merged.push(event);
+    }
+    merged.extend(buffered);
+    merged
+}
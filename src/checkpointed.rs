@@ -0,0 +1,595 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::ops::RangeBounds;
+
+use crate::batch::{self, merge_by_key, BatchBuffer};
+use crate::key::{KeyOrder, RelativeCmp};
+use crate::stats::StatsCollector;
+use crate::trace::KeyFormat;
+use crate::{
+    AmendOutcome, BatchOutcome, Event, KeyDistance, LagBufferStats, LatePolicy, RelativeOrd, State,
+    TieBreak, TooLateError, UpdateOutcome,
+};
+
+/// A lag buffer that keeps a state checkpoint every `INTERVAL` events.
+///
+/// The other buffers replay from a single base state, so a late event in a large window replays
+/// the whole window. `CheckpointedLagBuffer` keeps a ring of checkpoints instead and restarts
+/// reconciliation from the closest checkpoint before the late event, which bounds the replay to
+/// the events after that checkpoint.
+///
+/// # Type Parameters
+///
+/// - `S`: The type of the state, which must implement the [`State`](trait.State.html) trait.
+/// - `SIZE`: The number of events the buffer retains. Must be at least `INTERVAL`.
+/// - `INTERVAL`: The number of events between two checkpoints. Must be greater than 0.
+/// - `OrderKey`: The type of the event's order key. Defaults to `usize`.
+///
+/// # Fields
+///
+/// - `events`: The retained events, ordered by their key.
+/// - `checkpoints`: `checkpoints[i]` is the state before `events[i * INTERVAL]`. The first checkpoint
+///   is the base state every retained event is applied on.
+/// - `current_state`: The state after applying all retained events.
+/// - `base_key`: The key of the last event folded into the base state.
+pub struct CheckpointedLagBuffer<
+    S: State<OrderKey>,
+    const SIZE: usize,
+    const INTERVAL: usize,
+    OrderKey: Ord = usize,
+> {
+    events: VecDeque<S::Event>,
+    checkpoints: VecDeque<S>,
+    current_state: S,
+    base_key: Option<OrderKey>,
+    late_policy: LatePolicy<S, OrderKey>,
+    tie_break: TieBreak<S, OrderKey>,
+    stats: StatsCollector<OrderKey>,
+    key_format: Option<KeyFormat<OrderKey>>,
+    relative_order: Option<RelativeCmp<OrderKey>>,
+}
+
+impl<S: State<OrderKey>, const SIZE: usize, const INTERVAL: usize, OrderKey: Ord + Clone>
+    CheckpointedLagBuffer<S, SIZE, INTERVAL, OrderKey>
+{
+    /// Creates a new `CheckpointedLagBuffer` with the given initial state.
+    ///
+    /// Fails to compile if `INTERVAL` is 0 or larger than `SIZE`.
+    pub fn new(initial_state: S) -> Self {
+        const {
+            assert!(INTERVAL > 0, "INTERVAL must be greater than 0");
+            assert!(INTERVAL <= SIZE, "INTERVAL must not be larger than SIZE");
+        };
+        let mut checkpoints = VecDeque::with_capacity(SIZE / INTERVAL + 2);
+        checkpoints.push_back(initial_state.clone());
+        Self {
+            events: VecDeque::with_capacity(SIZE + 1),
+            checkpoints,
+            current_state: initial_state,
+            base_key: None,
+            late_policy: LatePolicy::default(),
+            tie_break: TieBreak::default(),
+            stats: StatsCollector::default(),
+            key_format: None,
+            relative_order: None,
+        }
+    }
+
+    /// Sets the policy for events that are too old to be reconciled.
+    pub fn with_late_policy(mut self, late_policy: LatePolicy<S, OrderKey>) -> Self {
+        self.late_policy = late_policy;
+        self
+    }
+
+    /// Sets how events with equal order keys are ordered.
+    pub fn with_tie_break(mut self, tie_break: TieBreak<S, OrderKey>) -> Self {
+        self.tie_break = tie_break;
+        self
+    }
+
+    /// Records the order key of every update on its trace span.
+    ///
+    /// Only has an effect with the `tracing` feature.
+    pub fn with_traced_keys(mut self) -> Self
+    where
+        OrderKey: fmt::Debug,
+    {
+        self.key_format = Some(|key| format!("{key:?}"));
+        self
+    }
+
+    /// Compares order keys with [`RelativeOrd::cmp_relative`], anchored at the last folded key.
+    ///
+    /// Use this for keys like [`WrappingSeq`](crate::WrappingSeq), whose `Ord` doesn't follow
+    /// the order in which the events were produced.
+    pub fn with_relative_keys(mut self) -> Self
+    where
+        OrderKey: RelativeOrd,
+    {
+        self.relative_order = Some(OrderKey::cmp_relative);
+        self
+    }
+
+    /// Updates the buffer with a new event.
+    ///
+    /// # Behavior
+    ///
+    /// - **In-Order Event**: The event is applied directly to the current state. Every `INTERVAL`
+    ///   events, the state before the event is stored as a new checkpoint.
+    /// - **Out-of-Order Event**: The event is inserted at its position and the state is replayed from
+    ///   the closest checkpoint before it. Later checkpoints are recomputed along the way.
+    /// - **Retention**: Once more than `SIZE` events are retained, the oldest `INTERVAL` events are
+    ///   folded into the base state by dropping the first checkpoint.
+    pub fn update(&mut self, event: S::Event) -> UpdateOutcome {
+        self.try_update(event)
+            .unwrap_or(UpdateOutcome::RejectedTooLate)
+    }
+
+    /// Updates the buffer with a new event, like [`update`](Self::update).
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`TooLateError`] holding the event if it is too old to be reconciled and the
+    /// buffer uses [`LatePolicy::Error`].
+    pub fn try_update(&mut self, event: S::Event) -> Result<UpdateOutcome, TooLateError<S::Event>> {
+        trace_span!(
+            span,
+            "update",
+            buffer = "checkpointed",
+            len = self.len(),
+            key = tracing::field::Empty,
+            outcome = tracing::field::Empty
+        );
+        let key = event.get_order_key();
+        trace_key!(span, self.key_format, &key);
+        let head_key = self.head_key();
+        let result = self.reconcile(event);
+        trace_record!(
+            span,
+            "outcome",
+            tracing::field::debug(&result.as_ref().ok())
+        );
+        self.stats.record(&result, head_key.as_ref(), &key);
+        result
+    }
+
+    // Places the event and reconciles the state, see `update`
+    fn reconcile(&mut self, event: S::Event) -> Result<UpdateOutcome, TooLateError<S::Event>> {
+        let keys = self.key_order();
+        let key = event.get_order_key();
+
+        let too_late = self
+            .base_key
+            .as_ref()
+            .is_some_and(|base_key| keys.lt(&key, base_key));
+        if too_late {
+            return LatePolicy::reject(self, |buffer| &mut buffer.late_policy, event);
+        }
+
+        // Events go after every retained event that doesn't sort after them
+        let insert_position = self
+            .events
+            .partition_point(|buffered| self.tie_break.is_after(&keys, buffered, &event));
+        let previous_key = match insert_position.checked_sub(1) {
+            Some(previous) => Some(self.events[previous].get_order_key()),
+            None => self.base_key.clone(),
+        };
+        if self.tie_break.is_duplicate(previous_key.as_ref(), &key) {
+            return Ok(UpdateOutcome::Duplicate);
+        }
+
+        let in_order = insert_position == self.events.len();
+
+        let mut outcome = UpdateOutcome::AppliedInOrder;
+
+        if in_order {
+            if !self.events.is_empty() && self.events.len().is_multiple_of(INTERVAL) {
+                self.checkpoints.push_back(self.current_state.clone());
+            }
+            self.current_state.apply(&event);
+            self.events.push_back(event);
+        } else {
+            self.events.insert(insert_position, event);
+
+            outcome = UpdateOutcome::Reordered {
+                replayed: self.replay_from(insert_position),
+            };
+        }
+
+        // Fold the oldest interval into the base state
+        if self.events.len() > SIZE {
+            trace_span!(_span, "swap", retired = INTERVAL);
+            self.checkpoints.pop_front();
+            self.base_key = self
+                .events
+                .drain(..INTERVAL)
+                .last()
+                .map(|folded| folded.get_order_key());
+            self.forget_folded();
+            self.stats.record_swap();
+            outcome = UpdateOutcome::Swapped {
+                replayed: outcome.replayed(),
+            };
+        }
+
+        Ok(outcome)
+    }
+
+    /// Updates the buffer with a batch of events, reconciling all of them with a single replay.
+    ///
+    /// The batch is sorted by key first. Events that are too late are handed to the
+    /// [`LatePolicy`] and events that are not older than the newest retained event are applied
+    /// in order, both exactly like in [`update`](Self::update). All other events are merged into
+    /// the retained events and the state is replayed once from the closest checkpoint before the
+    /// oldest of them. Afterwards, whole intervals are folded until at most `SIZE` events remain.
+    pub fn update_batch(&mut self, events: impl IntoIterator<Item = S::Event>) -> BatchOutcome {
+        batch::update_batch(self, events)
+    }
+
+    // Replays from the closest checkpoint before `position`, recomputing the ones after it.
+    // Returns the number of replayed events.
+    fn replay_from(&mut self, position: usize) -> usize {
+        let first_checkpoint = position / INTERVAL;
+        let replay_start = first_checkpoint * INTERVAL;
+        trace_span!(
+            _span,
+            "reconstruct",
+            position,
+            checkpoint = first_checkpoint,
+            replayed = self.events.len() - replay_start
+        );
+        let mut state = self.checkpoints[first_checkpoint].clone();
+        for (index, buffered) in self.events.iter().enumerate().skip(replay_start) {
+            if index > replay_start && index.is_multiple_of(INTERVAL) {
+                let checkpoint = index / INTERVAL;
+                if checkpoint < self.checkpoints.len() {
+                    self.checkpoints[checkpoint] = state.clone();
+                } else {
+                    self.checkpoints.push_back(state.clone());
+                }
+            }
+            state.apply(buffered);
+        }
+        self.current_state = state;
+        // Fewer events may need fewer checkpoints
+        self.checkpoints
+            .truncate(self.events.len().div_ceil(INTERVAL).max(1));
+        self.events.len() - replay_start
+    }
+
+    /// Removes a retained event and reconstructs the current state as though it never happened.
+    ///
+    /// The state is replayed from the closest checkpoint before the event. Events that were
+    /// already folded into the base state can't be retracted.
+    ///
+    /// # Arguments
+    ///
+    /// - `key`: The order key of the event to remove. If several events share the key, the oldest
+    ///   one is removed.
+    ///
+    /// # Returns
+    ///
+    /// The removed event, or `None` if no retained event has the given key.
+    pub fn retract(&mut self, key: &OrderKey) -> Option<S::Event> {
+        let position = self
+            .events
+            .iter()
+            .position(|buffered| buffered.get_order_key() == *key)?;
+        let event = self.events.remove(position);
+        self.replay_from(position);
+        event
+    }
+
+    /// Replaces the retained event with the same order key and reconstructs the current state.
+    ///
+    /// The state is replayed from the closest checkpoint before the event. If no retained event
+    /// has the key, the event is handled like in [`try_update`](Self::try_update).
+    ///
+    /// # Arguments
+    ///
+    /// - `event`: The corrected event. If several retained events share its key, the oldest one is
+    ///   replaced. With [`TieBreak::SecondaryKey`], the corrected event is moved among them to
+    ///   where the function orders it.
+    ///
+    /// # Errors
+    ///
+    /// Returns the [`TooLateError`] of [`try_update`](Self::try_update) for an event that is
+    /// handled like there.
+    pub fn amend(
+        &mut self,
+        event: S::Event,
+    ) -> Result<AmendOutcome<S::Event>, TooLateError<S::Event>> {
+        let keys = self.key_order();
+        let key = event.get_order_key();
+        let Some(position) = self
+            .events
+            .iter()
+            .position(|buffered| buffered.get_order_key() == key)
+        else {
+            return self.try_update(event).map(AmendOutcome::Updated);
+        };
+        let amended = std::mem::replace(&mut self.events[position], event);
+        let target = self.tie_break.reposition(&keys, &self.events, position);
+        for index in position..target {
+            self.events.swap(index, index + 1);
+        }
+        let replayed = self.replay_from(position);
+        Ok(AmendOutcome::Replaced {
+            event: amended,
+            replayed,
+        })
+    }
+
+    /// Reconstructs the state as of the given order key without touching the current state.
+    ///
+    /// The result contains every retained event whose key is not newer than `key`.
+    ///
+    /// # Returns
+    ///
+    /// `None` if `key` is older than the [`oldest_reconcilable_key`](Self::oldest_reconcilable_key).
+    pub fn state_at(&self, key: &OrderKey) -> Option<S> {
+        let keys = self.key_order();
+        if self
+            .base_key
+            .as_ref()
+            .is_some_and(|base_key| keys.lt(key, base_key))
+        {
+            return None;
+        }
+        let end = self
+            .events
+            .partition_point(|e| keys.le(&e.get_order_key(), key));
+        if end == self.events.len() {
+            return Some(self.current_state.clone());
+        }
+        let checkpoint = end / INTERVAL;
+        let mut state = self.checkpoints[checkpoint].clone();
+        for buffered in self.events.range(checkpoint * INTERVAL..end) {
+            state.apply(buffered);
+        }
+        Some(state)
+    }
+
+    /// Returns the oldest order key that can still be reconciled.
+    ///
+    /// Events with an older key are handed to the [`LatePolicy`]. Returns `None` while no
+    /// events have been folded into the base state, i.e. while every key can be reconciled.
+    pub fn oldest_reconcilable_key(&self) -> Option<OrderKey> {
+        self.base_key.clone()
+    }
+
+    /// Returns the number of retained events.
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Returns `true` if no events are retained.
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Returns the number of events the buffer retains before folding the oldest interval.
+    pub fn capacity(&self) -> usize {
+        SIZE
+    }
+
+    /// Returns the order key of the newest retained event.
+    pub fn head_key(&self) -> Option<OrderKey> {
+        self.events.back().map(S::Event::get_order_key)
+    }
+
+    /// Returns the order key of the newest retained event, like [`head_key`](Self::head_key).
+    pub fn newest_key(&self) -> Option<OrderKey> {
+        self.head_key()
+    }
+
+    /// Returns the order key of the oldest retained event.
+    pub fn oldest_key(&self) -> Option<OrderKey> {
+        self.events().next().map(S::Event::get_order_key)
+    }
+
+    /// Returns an iterator over the retained events in key order.
+    pub fn events(&self) -> impl Iterator<Item = &S::Event> {
+        self.events.iter()
+    }
+
+    /// Returns an iterator over the retained events whose key lies in `range`, in key order.
+    pub fn events_in_range<R: RangeBounds<OrderKey>>(
+        &self,
+        range: R,
+    ) -> impl Iterator<Item = &S::Event> {
+        let keys = self.key_order();
+        self.events()
+            .filter(move |e| keys.contains(&range, &e.get_order_key()))
+    }
+
+    /// Folds all retained events into the base state, keeping the current state.
+    pub fn clear(&mut self) {
+        if let Some(head_key) = self.head_key() {
+            self.base_key = Some(head_key);
+        }
+        self.events.clear();
+        self.checkpoints.clear();
+        self.forget_folded();
+        self.checkpoints.push_back(self.current_state.clone());
+    }
+
+    /// Discards all buffered events and checkpoints and restarts from the given state.
+    pub fn reset(&mut self, state: S) {
+        self.events.clear();
+        self.checkpoints.clear();
+        self.checkpoints.push_back(state.clone());
+        self.current_state = state;
+        self.base_key = None;
+    }
+
+    // Lets the kept states drop what they track for events older than the base key
+    fn forget_folded(&mut self) {
+        let keys = self.key_order();
+        if let Some(base_key) = &self.base_key {
+            let is_folded = |key: &OrderKey| keys.lt(key, base_key);
+            for state in self.checkpoints.iter_mut().chain([&mut self.current_state]) {
+                state.forget(&is_folded);
+            }
+        }
+    }
+
+    // Orders keys for one operation, relative to the oldest key the buffer compares against
+    fn key_order(&self) -> KeyOrder<OrderKey> {
+        KeyOrder::new(self.relative_order, || {
+            self.base_key.clone().or_else(|| self.oldest_key())
+        })
+    }
+
+    /// Returns the reconciliation metrics collected since the buffer was created or the stats
+    /// were last reset.
+    pub fn stats(&self) -> &LagBufferStats {
+        &self.stats.stats
+    }
+
+    /// Resets the reconciliation metrics.
+    pub fn reset_stats(&mut self) {
+        self.stats.stats = LagBufferStats::default();
+    }
+
+    /// Returns a reference to the current state.
+    ///
+    /// # Returns
+    ///
+    /// A reference to the current state after applying all events.
+    pub fn state_ref(&self) -> &S {
+        &self.current_state
+    }
+}
+
+impl<
+        S: State<OrderKey>,
+        const SIZE: usize,
+        const INTERVAL: usize,
+        OrderKey: KeyDistance + Clone,
+    > CheckpointedLagBuffer<S, SIZE, INTERVAL, OrderKey>
+{
+    /// Measures the lateness of out-of-order events in the [`stats`](Self::stats).
+    ///
+    /// Lateness is the [`KeyDistance`] between the newest buffered key and the event's key.
+    pub fn with_lateness_stats(mut self) -> Self {
+        self.stats.lateness = Some(|head_key, key| head_key.distance_units(key));
+        self
+    }
+}
+
+impl<S: State<OrderKey>, const SIZE: usize, const INTERVAL: usize, OrderKey: Ord + Clone>
+    BatchBuffer<S, OrderKey> for CheckpointedLagBuffer<S, SIZE, INTERVAL, OrderKey>
+{
+    const NAME: &'static str = "checkpointed";
+
+    fn tie_break(&self) -> &TieBreak<S, OrderKey> {
+        &self.tie_break
+    }
+
+    fn key_order(&self) -> KeyOrder<OrderKey> {
+        self.key_order()
+    }
+
+    fn stats_collector(&mut self) -> &mut StatsCollector<OrderKey> {
+        &mut self.stats
+    }
+
+    fn folded_key(&self) -> Option<OrderKey> {
+        self.base_key.clone()
+    }
+
+    // Merges sorted out-of-order events into the retained events and replays once, see
+    // `update_batch`
+    fn merge_batch(&mut self, events: Vec<S::Event>) -> UpdateOutcome {
+        let keys = self.key_order();
+        let insert_position = self
+            .events
+            .partition_point(|buffered| self.tie_break.is_after(&keys, buffered, &events[0]));
+        let newer: Vec<_> = self.events.drain(insert_position..).collect();
+        self.events
+            .extend(merge_by_key(newer, events, &self.tie_break, &keys));
+
+        let replayed = self.replay_from(insert_position);
+
+        // Fold the oldest intervals into the base state
+        if self.events.len() <= SIZE {
+            return UpdateOutcome::Reordered { replayed };
+        }
+        while self.events.len() > SIZE {
+            self.checkpoints.pop_front();
+            self.base_key = self
+                .events
+                .drain(..INTERVAL)
+                .last()
+                .map(|folded| folded.get_order_key());
+        }
+        self.forget_folded();
+        self.stats.record_swap();
+        UpdateOutcome::Swapped { replayed }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{insert, MyState};
+
+    #[test]
+    fn test_event_application_out_of_order() {
+        let mut buffer = CheckpointedLagBuffer::<MyState, 8, 2>::new(MyState::new());
+
+        buffer.update(insert(1));
+        buffer.update(insert(3));
+        assert_eq!(
+            buffer.update(insert(2)),
+            UpdateOutcome::Reordered { replayed: 3 }
+        );
+        assert_eq!(buffer.state_ref().data, vec![10, 20, 30]);
+    }
+
+    #[test]
+    fn test_replay_from_closest_checkpoint() {
+        let mut buffer = CheckpointedLagBuffer::<MyState, 8, 2>::new(MyState::new());
+
+        for id in [2, 4, 6, 8, 10] {
+            buffer.update(insert(id));
+        }
+
+        // Lands after the last checkpoint, so only the last interval is replayed.
+        assert_eq!(
+            buffer.update(insert(9)),
+            UpdateOutcome::Reordered { replayed: 2 }
+        );
+        // Shifts every later event and checkpoint by one.
+        assert_eq!(
+            buffer.update(insert(3)),
+            UpdateOutcome::Reordered { replayed: 7 }
+        );
+        assert_eq!(
+            buffer.update(insert(7)),
+            UpdateOutcome::Reordered { replayed: 4 }
+        );
+        assert_eq!(
+            buffer.state_ref().data,
+            vec![20, 30, 40, 60, 70, 80, 90, 100]
+        );
+    }
+
+    #[test]
+    fn test_fold_oldest_interval() {
+        let mut buffer = CheckpointedLagBuffer::<MyState, 4, 2>::new(MyState::new());
+
+        for id in 1..=4 {
+            assert_eq!(buffer.update(insert(id)), UpdateOutcome::AppliedInOrder);
+        }
+        assert_eq!(
+            buffer.update(insert(5)),
+            UpdateOutcome::Swapped { replayed: 0 }
+        );
+        assert_eq!(buffer.oldest_reconcilable_key(), Some(2));
+
+        assert_eq!(buffer.update(insert(1)), UpdateOutcome::RejectedTooLate);
+        assert_eq!(buffer.state_ref().data, vec![10, 20, 30, 40, 50]);
+    }
+}
@@ -1,4 +1,14 @@
-use crate::{Event, State};
+use std::fmt;
+use std::ops::RangeBounds;
+
+use crate::batch::{self, merge_by_key, BatchBuffer};
+use crate::key::{KeyOrder, RelativeCmp};
+use crate::stats::StatsCollector;
+use crate::trace::KeyFormat;
+use crate::{
+    AmendOutcome, BatchOutcome, CommitCallback, Event, KeyDistance, LagBufferObserver,
+    LagBufferStats, LatePolicy, RelativeOrd, State, TieBreak, TooLateError, UpdateOutcome,
+};
 
 /// A buffer system designed to handle out-of-order events and reconcile the state.
 ///
@@ -18,11 +28,18 @@ use crate::{Event, State};
 /// - **State Reconstruction**: Reconstructs the state efficiently when out-of-order events are received.
 /// - **Buffer Swapping**: Manages memory usage by swapping buffers when they reach a certain capacity.
 ///
+/// Retention is by event count only. For time-horizon retention, where events stay reconcilable
+/// until their key falls a given distance behind the head, use
+/// [`DoubleEndedLagBuffer::with_horizon`](crate::DoubleEndedLagBuffer::with_horizon).
+///
 /// # Type Parameters
 ///
 /// - `S`: The type of the state, which must implement the [`State`](trait.State.html) trait.
-/// - `SIZE`: The maximum number of events each buffer can hold before triggering a swap.
+/// - `SIZE`: The default maximum number of events each buffer can hold before triggering a swap.
+///   Use [`with_capacity`](Self::with_capacity) to choose the capacity at runtime instead.
 /// - `OrderKey`: The type of the event's order key, which must implement [`Ord`](https://doc.rust-lang.org/std/cmp/trait.Ord.html). Defaults to `usize`.
+/// - `Observer`: The [`LagBufferObserver`] notified by [`update`](Self::update). Defaults to `()`,
+///   which observes nothing.
 ///
 /// # Fields
 ///
@@ -30,6 +47,8 @@ use crate::{Event, State};
 /// - `active_buffer`: Index indicating which buffer is currently active (0 or 1).
 /// - `buffer_bases`: An array holding the base states corresponding to each buffer.
 /// - `buffers`: An array of two event buffers (`Vec<S::Event>`) used to store events.
+/// - `capacity`: The maximum number of events each buffer can hold before triggering a swap.
+/// - `on_commit`: Receives events once they are folded into the base of the active buffer.
 ///
 /// # Examples
 ///
@@ -114,14 +133,35 @@ use crate::{Event, State};
 /// let state = lag_buffer.state_ref();
 /// assert_eq!(state.data, vec![10, 20, 30]); // Should print [10, 20, 30]
 /// ```
-pub struct DoubleBufferedLagBuffer<S: State<OrderKey>, const SIZE: usize, OrderKey: Ord = usize> {
+pub struct DoubleBufferedLagBuffer<
+    S: State<OrderKey>,
+    const SIZE: usize,
+    OrderKey: Ord = usize,
+    Observer = (),
+> {
     pub(crate) current_state: S,
     pub(crate) active_buffer: usize,
     pub(crate) buffer_bases: [S; 2],
+    pub(crate) buffer_base_keys: [Option<OrderKey>; 2],
+    pub(crate) watermark: Option<OrderKey>,
     pub(crate) buffers: [Vec<S::Event>; 2],
+    pub(crate) capacity: usize,
+    pub(crate) late_policy: LatePolicy<S, OrderKey>,
+    pub(crate) tie_break: TieBreak<S, OrderKey>,
+    pub(crate) stats: StatsCollector<OrderKey>,
+    pub(crate) key_format: Option<KeyFormat<OrderKey>>,
+    pub(crate) relative_order: Option<RelativeCmp<OrderKey>>,
+    pub(crate) on_commit: Option<CommitCallback<S::Event>>,
+    pub(crate) observer: Observer,
 }
 
-impl<S: State<OrderKey>, const SIZE: usize, OrderKey: Ord>
+/// A [`DoubleBufferedLagBuffer`] whose capacity is only chosen at runtime.
+///
+/// All capacities share one type. Create it with [`with_capacity`](DoubleBufferedLagBuffer::with_capacity).
+pub type DynDoubleBufferedLagBuffer<S, OrderKey = usize, Observer = ()> =
+    DoubleBufferedLagBuffer<S, 0, OrderKey, Observer>;
+
+impl<S: State<OrderKey>, const SIZE: usize, OrderKey: Ord + Clone>
     DoubleBufferedLagBuffer<S, SIZE, OrderKey>
 {
     /// Creates a new `DoubleBufferedLagBuffer` with the given initial state.
@@ -133,15 +173,152 @@ impl<S: State<OrderKey>, const SIZE: usize, OrderKey: Ord>
     /// # Returns
     ///
     /// A new `DoubleBufferedLagBuffer` instance initialized with the provided state.
+    ///
+    /// Fails to compile if `SIZE` is 0, as for a [`DynDoubleBufferedLagBuffer`]. Use
+    /// [`with_capacity`](Self::with_capacity) for those.
     pub fn new(initial_state: S) -> Self {
+        const { assert!(SIZE > 0, "SIZE must be greater than 0, use with_capacity") };
+        Self::with_capacity(initial_state, SIZE)
+    }
+
+    /// Creates a new `DoubleBufferedLagBuffer` with a capacity chosen at runtime.
+    ///
+    /// # Arguments
+    ///
+    /// - `initial_state`: The initial state from which the buffer will start.
+    /// - `capacity`: The maximum number of events each buffer can hold before triggering a swap.
+    ///   Overrides `SIZE`.
+    ///
+    /// # Returns
+    ///
+    /// A new `DoubleBufferedLagBuffer` instance initialized with the provided state.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is 0.
+    pub fn with_capacity(initial_state: S, capacity: usize) -> Self {
+        assert!(capacity > 0, "Capacity must be greater than 0");
         Self {
-            buffers: [Vec::with_capacity(SIZE), Vec::with_capacity(SIZE)],
+            buffers: [Vec::with_capacity(capacity), Vec::with_capacity(capacity)],
+            capacity,
             active_buffer: 0,
             buffer_bases: [initial_state.clone(), initial_state.clone()],
+            buffer_base_keys: [None, None],
+            watermark: None,
             current_state: initial_state,
+            late_policy: LatePolicy::default(),
+            tie_break: TieBreak::default(),
+            stats: StatsCollector::default(),
+            key_format: None,
+            relative_order: None,
+            on_commit: None,
+            observer: (),
+        }
+    }
+}
+
+impl<
+        S: State<OrderKey>,
+        const SIZE: usize,
+        OrderKey: Ord + Clone,
+        Observer: LagBufferObserver<S, OrderKey>,
+    > DoubleBufferedLagBuffer<S, SIZE, OrderKey, Observer>
+{
+    /// Sets the observer that is notified about what [`update`](Self::update) does.
+    ///
+    /// # Arguments
+    ///
+    /// - `observer`: The [`LagBufferObserver`] replacing the current one.
+    pub fn with_observer<O: LagBufferObserver<S, OrderKey>>(
+        self,
+        observer: O,
+    ) -> DoubleBufferedLagBuffer<S, SIZE, OrderKey, O> {
+        DoubleBufferedLagBuffer {
+            current_state: self.current_state,
+            active_buffer: self.active_buffer,
+            buffer_bases: self.buffer_bases,
+            buffer_base_keys: self.buffer_base_keys,
+            watermark: self.watermark,
+            buffers: self.buffers,
+            capacity: self.capacity,
+            late_policy: self.late_policy,
+            tie_break: self.tie_break,
+            stats: self.stats,
+            key_format: self.key_format,
+            relative_order: self.relative_order,
+            on_commit: self.on_commit,
+            observer,
         }
     }
 
+    /// Returns a reference to the observer.
+    pub fn observer(&self) -> &Observer {
+        &self.observer
+    }
+
+    /// Returns a mutable reference to the observer.
+    pub fn observer_mut(&mut self) -> &mut Observer {
+        &mut self.observer
+    }
+
+    /// Sets the policy for events that are too old to be reconciled.
+    ///
+    /// # Arguments
+    ///
+    /// - `late_policy`: The [`LatePolicy`] to apply to late events.
+    pub fn with_late_policy(mut self, late_policy: LatePolicy<S, OrderKey>) -> Self {
+        self.late_policy = late_policy;
+        self
+    }
+
+    /// Sets how events with equal order keys are ordered.
+    ///
+    /// # Arguments
+    ///
+    /// - `tie_break`: The [`TieBreak`] to apply to events with equal keys.
+    pub fn with_tie_break(mut self, tie_break: TieBreak<S, OrderKey>) -> Self {
+        self.tie_break = tie_break;
+        self
+    }
+
+    /// Records the order key of every update on its trace span.
+    ///
+    /// Only has an effect with the `tracing` feature.
+    pub fn with_traced_keys(mut self) -> Self
+    where
+        OrderKey: fmt::Debug,
+    {
+        self.key_format = Some(|key| format!("{key:?}"));
+        self
+    }
+
+    /// Compares order keys with [`RelativeOrd::cmp_relative`], anchored at the last folded key.
+    ///
+    /// Use this for keys like [`WrappingSeq`](crate::WrappingSeq), whose `Ord` doesn't follow
+    /// the order in which the events were produced.
+    pub fn with_relative_keys(mut self) -> Self
+    where
+        OrderKey: RelativeOrd,
+    {
+        self.relative_order = Some(OrderKey::cmp_relative);
+        self
+    }
+
+    /// Sets a callback that receives every event once it is committed.
+    ///
+    /// An event is committed when it is folded into the base of the active buffer, either by a
+    /// buffer swap, by [`advance_watermark`](Self::advance_watermark), [`resize`](Self::resize)
+    /// or [`clear`](Self::clear). Committed events can no longer be reconciled, so the callback
+    /// sees them exactly once and in key order.
+    ///
+    /// # Arguments
+    ///
+    /// - `on_commit`: The callback receiving the committed events.
+    pub fn with_on_commit(mut self, on_commit: impl FnMut(&S::Event) + Send + 'static) -> Self {
+        self.on_commit = Some(Box::new(on_commit));
+        self
+    }
+
     /// Updates the buffer with a new event.
     ///
     /// This method handles the incoming event by determining whether it is in order or out of order
@@ -150,23 +327,34 @@ impl<S: State<OrderKey>, const SIZE: usize, OrderKey: Ord>
     ///
     /// # Behavior
     ///
+    /// - **Late Event**:
+    ///   - The event's `OrderKey` is less than the [`oldest_reconcilable_key`](Self::oldest_reconcilable_key).
+    ///   - The event is handed to the buffer's [`LatePolicy`] and is never applied.
+    ///
+    /// - **Duplicate Event**:
+    ///   - The buffer uses [`TieBreak::RejectDuplicates`] and already holds an event with the same `OrderKey`.
+    ///   - The event is discarded and [`UpdateOutcome::Duplicate`] is returned.
+    ///
     /// - **In-Order Event**:
-    ///   - The event's `OrderKey` is greater than or equal to the last event's key in the active buffer.
+    ///   - The event sorts after the last event in the active buffer. Events with an equal `OrderKey` are
+    ///     ordered by the buffer's [`TieBreak`].
     ///   - The event is applied directly to the `current_state`.
     ///   - The event is added to the active buffer.
-    ///   - If the active buffer's length exceeds half of `SIZE`, the event is also added to the secondary buffer.
+    ///   - If the active buffer's length exceeds half of the capacity, the event is also added to the secondary buffer.
     ///
     /// - **Out-of-Order Event**:
-    ///   - The event's `OrderKey` is less than the last event's key in the active buffer.
+    ///   - The event sorts before the last event in the active buffer.
     ///   - The event is inserted into the active buffer at the correct position to maintain order.
     ///   - The `current_state` is reconstructed by cloning the base state of the active buffer and
     ///     reapplying all events from the active buffer.
-    ///   - If the active buffer's length exceeds half of `SIZE` and the secondary buffer is not empty,
-    ///     the event is also inserted into the secondary buffer at the correct position.
+    ///   - If the secondary buffer is not empty, the event is either inserted into it or, if it is
+    ///     older than the secondary buffer's first event, folded into the secondary base state.
+    ///   - If the secondary buffer is empty and the active buffer is now more than half full, the
+    ///     secondary buffer is started with the second half of the active buffer.
     ///
     /// - **Buffer Swap**:
     ///   - Occurs after the event is processed.
-    ///   - If the active buffer's length exceeds `SIZE`, a buffer swap is triggered:
+    ///   - If the active buffer's length exceeds the capacity, a buffer swap is triggered:
     ///     - The `current_state` is saved as the new base state for the active buffer.
     ///     - The active buffer is cleared.
     ///     - The active and secondary buffers swap roles.
@@ -174,64 +362,556 @@ impl<S: State<OrderKey>, const SIZE: usize, OrderKey: Ord>
     /// # Arguments
     ///
     /// - `event`: The event to be applied or buffered.
-    pub fn update(&mut self, event: S::Event) {
+    ///
+    /// # Returns
+    ///
+    /// An [`UpdateOutcome`] describing how the event was handled.
+    pub fn update(&mut self, event: S::Event) -> UpdateOutcome {
+        self.try_update(event)
+            .unwrap_or(UpdateOutcome::RejectedTooLate)
+    }
+
+    /// Updates the buffer with a new event, like [`update`](Self::update).
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`TooLateError`] holding the event if it is too old to be reconciled and the
+    /// buffer uses [`LatePolicy::Error`].
+    pub fn try_update(&mut self, event: S::Event) -> Result<UpdateOutcome, TooLateError<S::Event>> {
+        trace_span!(
+            span,
+            "update",
+            buffer = "double_buffered",
+            len = self.len(),
+            key = tracing::field::Empty,
+            outcome = tracing::field::Empty
+        );
+        let key = event.get_order_key();
+        trace_key!(span, self.key_format, &key);
+        let head_key = self.head_key();
+        let result = self.reconcile(event);
+        trace_record!(
+            span,
+            "outcome",
+            tracing::field::debug(&result.as_ref().ok())
+        );
+        self.stats.record(&result, head_key.as_ref(), &key);
+        result
+    }
+
+    // Places the event and reconciles the state, see `update`
+    fn reconcile(&mut self, event: S::Event) -> Result<UpdateOutcome, TooLateError<S::Event>> {
+        let keys = self.key_order();
         let active_buffer = self.active_buffer;
         let secondary_buffer = 1 - active_buffer;
 
-        // Determine if the event is in order
-        let in_order = match self.buffers[active_buffer].last() {
-            Some(last_event) => last_event.get_order_key() <= event.get_order_key(),
-            None => true,
+        // Events older than the active base can't be placed correctly anymore
+        if self.is_too_late(&event.get_order_key()) {
+            self.observer.on_drop(&event);
+            return LatePolicy::reject(self, |buffer| &mut buffer.late_policy, event);
+        }
+
+        // Events go after every buffered event that doesn't sort after them
+        let insert_position = self.buffers[active_buffer]
+            .partition_point(|buffered| self.tie_break.is_after(&keys, buffered, &event));
+        let previous_key = match insert_position.checked_sub(1) {
+            Some(previous) => Some(self.buffers[active_buffer][previous].get_order_key()),
+            None => self.buffer_base_keys[active_buffer].clone(),
         };
+        if self
+            .tie_break
+            .is_duplicate(previous_key.as_ref(), &event.get_order_key())
+        {
+            return Ok(UpdateOutcome::Duplicate);
+        }
+
+        // Determine if the event is in order
+        let in_order = insert_position == self.buffers[active_buffer].len();
+
+        let mut outcome = UpdateOutcome::AppliedInOrder;
 
         if in_order {
             // In-order event: apply directly and add to active buffer
             self.buffers[active_buffer].push(event.clone());
 
             // If buffer is more than half full, start populating the secondary buffer
-            if self.buffers[active_buffer].len() > (SIZE / 2) {
+            let active_len = self.buffers[active_buffer].len();
+            if active_len > (self.capacity / 2) {
                 if self.buffers[secondary_buffer].is_empty() {
                     self.buffer_bases[secondary_buffer] = self.current_state.clone();
+                    self.buffer_base_keys[secondary_buffer] = match active_len {
+                        1 => self.buffer_base_keys[active_buffer].clone(),
+                        _ => Some(self.buffers[active_buffer][active_len - 2].get_order_key()),
+                    };
                 }
                 self.buffers[secondary_buffer].push(event.clone());
             }
 
             self.current_state.apply(&event);
+            self.observer.on_apply(&event);
         } else {
-            // Out-of-order event: insert into active buffer and reconstruct state
-            let insert_position = self.buffers[active_buffer]
-                .binary_search_by_key(&event.get_order_key(), S::Event::get_order_key)
-                .unwrap_or_else(|e| e);
+            // Out-of-order event: insert into active buffer and reconstruct state.
+            // The secondary buffer always mirrors the tail of the active buffer, starting at `split`.
+            let split = self.buffers[active_buffer].len() - self.buffers[secondary_buffer].len();
+            self.buffers[active_buffer].insert(insert_position, event.clone());
+            trace_span!(
+                _span,
+                "reconstruct",
+                insert_position,
+                replayed = self.buffers[active_buffer].len()
+            );
 
-            self.buffers[active_buffer].insert(dbg!(insert_position), event.clone());
+            // If the event lands before the secondary buffer, its base has to include it. Once
+            // the active buffer is more than half full, the secondary buffer has to be started.
+            let start_secondary = self.buffers[secondary_buffer].is_empty()
+                && self.buffers[active_buffer].len() > self.capacity / 2;
+            let rebase_secondary =
+                !self.buffers[secondary_buffer].is_empty() && insert_position < split;
 
             // Reconstruct current state from buffer base and events
-            self.current_state = self.buffer_bases[active_buffer].clone();
-            for buffered_event in &self.buffers[active_buffer] {
-                self.current_state.apply(buffered_event);
+            let secondary_start = if start_secondary {
+                Some(self.capacity / 2)
+            } else {
+                rebase_secondary.then_some(split + 1)
+            };
+            self.replay_active(secondary_start);
+            if start_secondary {
+                let secondary_events = self.buffers[active_buffer][self.capacity / 2..].to_vec();
+                self.buffers[secondary_buffer] = secondary_events;
             }
+            outcome = UpdateOutcome::Reordered {
+                replayed: self.buffers[active_buffer].len(),
+            };
+            self.observer
+                .on_rollback(&event.get_order_key(), outcome.replayed());
+            self.observer.on_apply(&event);
 
-            // Update secondary buffer if necessary
-            if self.buffers[active_buffer].len() > (SIZE / 2)
-                && !self.buffers[secondary_buffer].is_empty()
-            {
-                let insert_position = self.buffers[secondary_buffer]
-                    .binary_search_by_key(&event.get_order_key(), S::Event::get_order_key)
-                    .unwrap_or_else(|e| e);
-
-                self.buffers[secondary_buffer].insert(insert_position, event);
+            // Otherwise it belongs into the secondary buffer as well
+            if !self.buffers[secondary_buffer].is_empty() && !start_secondary && !rebase_secondary {
+                self.buffers[secondary_buffer].insert(insert_position - split, event);
             }
         }
 
         // Check if buffer swap is needed
-        if self.buffers[active_buffer].len() > SIZE {
+        if self.buffers[active_buffer].len() > self.capacity {
+            // Everything before the secondary buffer becomes final
+            let split = self.buffers[active_buffer].len() - self.buffers[secondary_buffer].len();
+            trace_span!(_span, "swap", retired = split);
+            if let Some(on_commit) = &mut self.on_commit {
+                self.buffers[active_buffer][..split]
+                    .iter()
+                    .for_each(on_commit);
+            }
             // Save current state as new buffer base
             self.buffer_bases[active_buffer] = self.current_state.clone();
+            self.buffer_base_keys[active_buffer] = self.buffers[active_buffer]
+                .last()
+                .map(S::Event::get_order_key);
             // Clear the active buffer
             self.buffers[active_buffer].clear();
             // Swap active and secondary buffers
             self.active_buffer = secondary_buffer;
+            self.forget_folded();
+            self.observer
+                .on_swap(&self.buffer_bases[self.active_buffer]);
+            self.stats.record_swap();
+            outcome = UpdateOutcome::Swapped {
+                replayed: outcome.replayed(),
+            };
+        }
+
+        Ok(outcome)
+    }
+
+    /// Updates the buffer with a batch of events, reconciling all of them with a single replay.
+    ///
+    /// The batch is sorted by key first. Events that are too late are handed to the
+    /// [`LatePolicy`] and events that are not older than the newest buffered event are applied
+    /// in order, both exactly like in [`update`](Self::update). All other events are merged into
+    /// the active buffer, which is then replayed once. If they don't fit, the oldest events are
+    /// folded into the base state before the replay.
+    ///
+    /// # Arguments
+    ///
+    /// - `events`: The events to be applied or buffered, in any order.
+    ///
+    /// # Returns
+    ///
+    /// A [`BatchOutcome`] summarizing how the events were handled.
+    pub fn update_batch(&mut self, events: impl IntoIterator<Item = S::Event>) -> BatchOutcome {
+        batch::update_batch(self, events)
+    }
+
+    /// Removes a buffered event and reconstructs the current state as though it never happened.
+    ///
+    /// The event is removed from both buffers. Events that were already folded into the base
+    /// state can't be retracted.
+    ///
+    /// # Arguments
+    ///
+    /// - `key`: The order key of the event to remove. If several events share the key, the oldest
+    ///   one is removed.
+    ///
+    /// # Returns
+    ///
+    /// The removed event, or `None` if no buffered event has the given key.
+    pub fn retract(&mut self, key: &OrderKey) -> Option<S::Event> {
+        let active_buffer = self.active_buffer;
+        let secondary_buffer = 1 - active_buffer;
+
+        let split = self.buffers[active_buffer].len() - self.buffers[secondary_buffer].len();
+        let position = self.buffers[active_buffer]
+            .iter()
+            .position(|e| e.get_order_key() == *key)?;
+        let event = self.buffers[active_buffer].remove(position);
+        trace_span!(
+            _span,
+            "reconstruct",
+            position,
+            replayed = self.buffers[active_buffer].len()
+        );
+
+        // If the event lies before the secondary buffer, its base has to be rebuilt without it
+        let rebase_secondary = !self.buffers[secondary_buffer].is_empty() && position < split;
+        if !self.buffers[secondary_buffer].is_empty() && !rebase_secondary {
+            self.buffers[secondary_buffer].remove(position - split);
+        }
+
+        self.replay_active(rebase_secondary.then(|| split - 1));
+        self.observer
+            .on_rollback(key, self.buffers[active_buffer].len());
+
+        Some(event)
+    }
+
+    /// Replaces the buffered event with the same order key and reconstructs the current state.
+    ///
+    /// The event is replaced in both buffers. If no buffered event has the key, the event is
+    /// handled like in [`try_update`](Self::try_update).
+    ///
+    /// # Arguments
+    ///
+    /// - `event`: The corrected event. If several buffered events share its key, the oldest one is
+    ///   replaced. With [`TieBreak::SecondaryKey`], the corrected event is moved among them to
+    ///   where the function orders it.
+    ///
+    /// # Errors
+    ///
+    /// Returns the [`TooLateError`] of [`try_update`](Self::try_update) for an event that is
+    /// handled like there.
+    pub fn amend(
+        &mut self,
+        event: S::Event,
+    ) -> Result<AmendOutcome<S::Event>, TooLateError<S::Event>> {
+        let keys = self.key_order();
+        let active_buffer = self.active_buffer;
+        let secondary_buffer = 1 - active_buffer;
+
+        let key = event.get_order_key();
+        let Some(position) = self.buffers[active_buffer]
+            .iter()
+            .position(|e| e.get_order_key() == key)
+        else {
+            return self.try_update(event).map(AmendOutcome::Updated);
+        };
+        let amended = std::mem::replace(&mut self.buffers[active_buffer][position], event);
+        let target = self
+            .tie_break
+            .reposition(&keys, &self.buffers[active_buffer], position);
+        self.buffers[active_buffer][position..=target].rotate_left(1);
+
+        // The secondary buffer holds the newest events of the active one
+        let replayed = self.buffers[active_buffer].len();
+        trace_span!(_span, "reconstruct", position, replayed);
+        let split = replayed - self.buffers[secondary_buffer].len();
+        if split < replayed {
+            let secondary_events = self.buffers[active_buffer][split..].to_vec();
+            self.buffers[secondary_buffer] = secondary_events;
+        }
+        self.replay_active((split < replayed).then_some(split));
+        self.observer.on_rollback(&key, replayed);
+
+        Ok(AmendOutcome::Replaced {
+            event: amended,
+            replayed,
+        })
+    }
+
+    /// Reconstructs the state as of the given order key without touching the current state.
+    ///
+    /// The result contains every retained event whose key is not newer than `key`.
+    ///
+    /// # Returns
+    ///
+    /// `None` if `key` is older than the [`oldest_reconcilable_key`](Self::oldest_reconcilable_key).
+    pub fn state_at(&self, key: &OrderKey) -> Option<S> {
+        if self.is_too_late(key) {
+            return None;
         }
+        let keys = self.key_order();
+        let active_events = &self.buffers[self.active_buffer];
+        let end = active_events.partition_point(|e| keys.le(&e.get_order_key(), key));
+        if end == active_events.len() {
+            return Some(self.current_state.clone());
+        }
+        let mut state = self.buffer_bases[self.active_buffer].clone();
+        for buffered_event in &active_events[..end] {
+            state.apply(buffered_event);
+        }
+        Some(state)
+    }
+
+    /// Returns the oldest order key that can still be reconciled.
+    ///
+    /// Events with an older key are handed to the [`LatePolicy`]. Returns `None` while no
+    /// events have been folded into the base state, i.e. while every key can be reconciled.
+    pub fn oldest_reconcilable_key(&self) -> Option<OrderKey> {
+        self.key_order().max(
+            self.buffer_base_keys[self.active_buffer].clone(),
+            self.watermark.clone(),
+        )
+    }
+
+    /// Returns the number of events in the active buffer.
+    pub fn len(&self) -> usize {
+        self.buffers[self.active_buffer].len()
+    }
+
+    /// Returns `true` if the active buffer holds no events.
+    pub fn is_empty(&self) -> bool {
+        self.buffers[self.active_buffer].is_empty()
+    }
+
+    /// Returns the number of events the active buffer holds before a swap is triggered.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Changes the capacity while keeping the current state.
+    ///
+    /// When shrinking below the number of buffered events, the oldest events are folded into the
+    /// base state and can no longer be reconciled. The secondary buffer is rebuilt to mirror the
+    /// tail of the active buffer, as if the events had arrived with the new capacity.
+    ///
+    /// # Arguments
+    ///
+    /// - `new_capacity`: The maximum number of events each buffer can hold before triggering a swap.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `new_capacity` is 0.
+    pub fn resize(&mut self, new_capacity: usize) {
+        assert!(new_capacity > 0, "Capacity must be greater than 0");
+        let active_buffer = self.active_buffer;
+
+        // Fold events that no longer fit into the active base
+        let excess = self.buffers[active_buffer]
+            .len()
+            .saturating_sub(new_capacity);
+        for folded in self.buffers[active_buffer].drain(..excess) {
+            self.buffer_bases[active_buffer].apply(&folded);
+            self.buffer_base_keys[active_buffer] = Some(folded.get_order_key());
+            if let Some(on_commit) = &mut self.on_commit {
+                on_commit(&folded);
+            }
+        }
+        if excess > 0 {
+            self.forget_folded();
+            self.observer.on_swap(&self.buffer_bases[active_buffer]);
+        }
+
+        self.capacity = new_capacity;
+        self.rebuild_secondary();
+    }
+
+    /// Commits every buffered event up to and including `key`.
+    ///
+    /// The committed events are folded into the base states and passed to the
+    /// [`on_commit`](Self::with_on_commit) callback. Afterwards, events older than `key` are
+    /// handed to the [`LatePolicy`]. A watermark older than the current one has no effect.
+    ///
+    /// # Arguments
+    ///
+    /// - `key`: The order key up to which no more events are expected.
+    pub fn advance_watermark(&mut self, key: OrderKey) {
+        if self.is_too_late(&key) {
+            return;
+        }
+        let keys = self.key_order();
+        let active_buffer = self.active_buffer;
+        let committed =
+            self.buffers[active_buffer].partition_point(|e| keys.le(&e.get_order_key(), &key));
+        for folded in self.buffers[active_buffer].drain(..committed) {
+            self.buffer_bases[active_buffer].apply(&folded);
+            self.buffer_base_keys[active_buffer] = Some(folded.get_order_key());
+            if let Some(on_commit) = &mut self.on_commit {
+                on_commit(&folded);
+            }
+        }
+        // Only folded events set the base key, `RejectDuplicates` compares ties against it
+        let watermark = Some(key);
+        if committed > 0 || self.watermark != watermark {
+            self.watermark = watermark;
+            self.forget_folded();
+            self.observer.on_swap(&self.buffer_bases[active_buffer]);
+        }
+        self.rebuild_secondary();
+    }
+
+    /// Returns the state that contains every committed event and nothing else.
+    ///
+    /// Unlike [`state_ref`](Self::state_ref), this state is final and no late event can change it.
+    pub fn committed_state(&self) -> &S {
+        &self.buffer_bases[self.active_buffer]
+    }
+
+    /// Returns the order key of the newest buffered event.
+    pub fn head_key(&self) -> Option<OrderKey> {
+        self.buffers[self.active_buffer]
+            .last()
+            .map(S::Event::get_order_key)
+    }
+
+    /// Returns the order key of the newest buffered event, like [`head_key`](Self::head_key).
+    pub fn newest_key(&self) -> Option<OrderKey> {
+        self.head_key()
+    }
+
+    /// Returns the order key of the oldest buffered event.
+    pub fn oldest_key(&self) -> Option<OrderKey> {
+        self.events().next().map(S::Event::get_order_key)
+    }
+
+    /// Returns an iterator over the buffered events in key order.
+    pub fn events(&self) -> impl Iterator<Item = &S::Event> {
+        self.buffers[self.active_buffer].iter()
+    }
+
+    /// Returns an iterator over the buffered events whose key lies in `range`, in key order.
+    pub fn events_in_range<R: RangeBounds<OrderKey>>(
+        &self,
+        range: R,
+    ) -> impl Iterator<Item = &S::Event> {
+        let keys = self.key_order();
+        self.events()
+            .filter(move |e| keys.contains(&range, &e.get_order_key()))
+    }
+
+    /// Folds all buffered events into the base states, keeping the current state.
+    pub fn clear(&mut self) {
+        let head_key = self
+            .head_key()
+            .or_else(|| self.buffer_base_keys[self.active_buffer].clone());
+        let folded = !self.is_empty();
+        if let Some(on_commit) = &mut self.on_commit {
+            self.buffers[self.active_buffer].iter().for_each(on_commit);
+        }
+        for buffer in &mut self.buffers {
+            buffer.clear();
+        }
+        self.buffer_bases = [self.current_state.clone(), self.current_state.clone()];
+        self.buffer_base_keys = [head_key.clone(), head_key];
+        if folded {
+            self.forget_folded();
+            self.observer.on_swap(&self.current_state);
+        }
+    }
+
+    /// Discards all buffered events and restarts from the given state.
+    ///
+    /// # Arguments
+    ///
+    /// - `state`: The state the buffer continues from.
+    pub fn reset(&mut self, state: S) {
+        for buffer in &mut self.buffers {
+            buffer.clear();
+        }
+        self.active_buffer = 0;
+        self.buffer_bases = [state.clone(), state.clone()];
+        self.buffer_base_keys = [None, None];
+        self.watermark = None;
+        self.current_state = state;
+    }
+
+    fn is_too_late(&self, key: &OrderKey) -> bool {
+        self.oldest_reconcilable_key()
+            .is_some_and(|base_key| self.key_order().lt(key, &base_key))
+    }
+
+    // Lets the kept states drop what they track for events older than the base key
+    fn forget_folded(&mut self) {
+        let keys = self.key_order();
+        if let Some(base_key) = &self.buffer_base_keys[self.active_buffer] {
+            let is_folded = |key: &OrderKey| keys.lt(key, base_key);
+            for state in self
+                .buffer_bases
+                .iter_mut()
+                .chain([&mut self.current_state])
+            {
+                state.forget(&is_folded);
+            }
+        }
+    }
+
+    // Rebuilds the secondary buffer from the second half of the active buffer
+    fn rebuild_secondary(&mut self) {
+        let active_buffer = self.active_buffer;
+        let secondary_buffer = 1 - active_buffer;
+
+        self.buffers[secondary_buffer].clear();
+        let split = self.capacity / 2;
+        if self.buffers[active_buffer].len() > split {
+            let mut secondary_base = self.buffer_bases[active_buffer].clone();
+            for buffered_event in &self.buffers[active_buffer][..split] {
+                secondary_base.apply(buffered_event);
+            }
+            self.buffer_bases[secondary_buffer] = secondary_base;
+            self.buffer_base_keys[secondary_buffer] = match split {
+                0 => self.buffer_base_keys[active_buffer].clone(),
+                _ => Some(self.buffers[active_buffer][split - 1].get_order_key()),
+            };
+            let secondary_events = self.buffers[active_buffer][split..].to_vec();
+            self.buffers[secondary_buffer].extend(secondary_events);
+        }
+    }
+
+    // Replays the active buffer from its base. With `secondary_start`, the base of the secondary
+    // buffer is rebuilt from the events before that position along the way.
+    fn replay_active(&mut self, secondary_start: Option<usize>) {
+        let active_buffer = self.active_buffer;
+        let secondary_buffer = 1 - active_buffer;
+
+        self.current_state = self.buffer_bases[active_buffer].clone();
+        for (index, buffered_event) in self.buffers[active_buffer].iter().enumerate() {
+            if secondary_start == Some(index) {
+                self.buffer_bases[secondary_buffer] = self.current_state.clone();
+                self.buffer_base_keys[secondary_buffer] = match index {
+                    0 => self.buffer_base_keys[active_buffer].clone(),
+                    _ => Some(self.buffers[active_buffer][index - 1].get_order_key()),
+                };
+            }
+            self.current_state.apply(buffered_event);
+        }
+    }
+
+    // Orders keys for one operation, relative to the oldest key the buffer compares against
+    fn key_order(&self) -> KeyOrder<OrderKey> {
+        KeyOrder::new(self.relative_order, || {
+            self.buffer_base_keys[self.active_buffer]
+                .clone()
+                .or_else(|| self.oldest_key())
+        })
+    }
+
+    /// Returns the reconciliation metrics collected since the buffer was created or the stats
+    /// were last reset.
+    pub fn stats(&self) -> &LagBufferStats {
+        &self.stats.stats
+    }
+
+    /// Resets the reconciliation metrics.
+    pub fn reset_stats(&mut self) {
+        self.stats.stats = LagBufferStats::default();
     }
 
     /// Returns a reference to the current state.
@@ -255,92 +935,111 @@ impl<S: State<OrderKey>, const SIZE: usize, OrderKey: Ord>
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    // Example State and Event implementation for testing.
-
-    #[derive(Clone, Debug, PartialEq)]
-    struct MyState {
-        pub data: Vec<i32>,
+impl<
+        S: State<OrderKey>,
+        const SIZE: usize,
+        OrderKey: KeyDistance + Clone,
+        Observer: LagBufferObserver<S, OrderKey>,
+    > DoubleBufferedLagBuffer<S, SIZE, OrderKey, Observer>
+{
+    /// Measures the lateness of out-of-order events in the [`stats`](Self::stats).
+    ///
+    /// Lateness is the [`KeyDistance`] between the newest buffered key and the event's key.
+    pub fn with_lateness_stats(mut self) -> Self {
+        self.stats.lateness = Some(|head_key, key| head_key.distance_units(key));
+        self
     }
+}
 
-    impl MyState {
-        pub fn new() -> Self {
-            Self { data: Vec::new() }
-        }
-    }
+impl<
+        S: State<OrderKey>,
+        const SIZE: usize,
+        OrderKey: Ord + Clone,
+        Observer: LagBufferObserver<S, OrderKey>,
+    > BatchBuffer<S, OrderKey> for DoubleBufferedLagBuffer<S, SIZE, OrderKey, Observer>
+{
+    const NAME: &'static str = "double_buffered";
 
-    impl State<usize> for MyState {
-        type Event = MyEvent;
+    fn tie_break(&self) -> &TieBreak<S, OrderKey> {
+        &self.tie_break
+    }
 
-        fn apply(&mut self, event: &Self::Event) {
-            match event.action {
-                Action::Insert => self.data.push(event.value),
-                Action::Replace => {
-                    if let Some(pos) = self.data.iter().position(|&x| x == event.target) {
-                        self.data[pos] = event.value;
-                    }
-                }
-            }
-        }
+    fn key_order(&self) -> KeyOrder<OrderKey> {
+        self.key_order()
     }
 
-    #[derive(Clone, Debug)]
-    enum Action {
-        Insert,
-        Replace,
+    fn stats_collector(&mut self) -> &mut StatsCollector<OrderKey> {
+        &mut self.stats
     }
 
-    #[derive(Clone, Debug)]
-    struct MyEvent {
-        id: usize,
-        value: i32,
-        target: i32, // Used for replacing a specific element
-        action: Action,
+    fn folded_key(&self) -> Option<OrderKey> {
+        self.buffer_base_keys[self.active_buffer].clone()
     }
 
-    impl Event<usize> for MyEvent {
-        fn get_order_key(&self) -> usize {
-            self.id
+    // Merges sorted out-of-order events into the active buffer and replays it once, see
+    // `update_batch`
+    fn merge_batch(&mut self, events: Vec<S::Event>) -> UpdateOutcome {
+        let keys = self.key_order();
+        let active_buffer = self.active_buffer;
+        let secondary_buffer = 1 - active_buffer;
+        let from_key = events[0].get_order_key();
+        for event in &events {
+            self.observer.on_apply(event);
+        }
+
+        let buffered = std::mem::take(&mut self.buffers[active_buffer]);
+        self.buffers[active_buffer] = merge_by_key(buffered, events, &self.tie_break, &keys);
+
+        // Retire the oldest events if the batch doesn't fit
+        let excess = self.buffers[active_buffer]
+            .len()
+            .saturating_sub(self.capacity);
+        for folded in self.buffers[active_buffer].drain(..excess) {
+            self.buffer_bases[active_buffer].apply(&folded);
+            self.buffer_base_keys[active_buffer] = Some(folded.get_order_key());
+            if let Some(on_commit) = &mut self.on_commit {
+                on_commit(&folded);
+            }
+        }
+
+        // Replay once, rebuilding the secondary buffer from the second half along the way
+        let len = self.buffers[active_buffer].len();
+        let split = self.capacity / 2;
+        trace_span!(_span, "reconstruct", replayed = len);
+        self.buffers[secondary_buffer].clear();
+        self.replay_active((len > split).then_some(split));
+        if len > split {
+            let secondary_events = self.buffers[active_buffer][split..].to_vec();
+            self.buffers[secondary_buffer].extend(secondary_events);
+        }
+        self.observer.on_rollback(&from_key, len);
+
+        // Folding the excess retires the oldest events like a swap
+        if excess > 0 {
+            self.forget_folded();
+            self.observer.on_swap(&self.buffer_bases[active_buffer]);
+            self.stats.record_swap();
+            return UpdateOutcome::Swapped { replayed: len };
         }
+        UpdateOutcome::Reordered { replayed: len }
     }
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{insert, insert_value, replace, MyEvent, MyState, Recorder};
+    use std::sync::{Arc, Mutex};
     #[test]
     fn test_event_application_in_order() {
         let mut buffer = DoubleBufferedLagBuffer::<MyState, 4>::new(MyState::new());
 
         // Apply 4 insert events in order.
-        buffer.update(MyEvent {
-            id: 1,
-            value: 10,
-            target: 0,
-            action: Action::Insert,
-        });
-        buffer.update(MyEvent {
-            id: 2,
-            value: 20,
-            target: 0,
-            action: Action::Insert,
-        });
-        buffer.update(MyEvent {
-            id: 3,
-            value: 30,
-            target: 0,
-            action: Action::Insert,
-        });
-        buffer.update(MyEvent {
-            id: 4,
-            value: 40,
-            target: 0,
-            action: Action::Insert,
-        });
-        buffer.update(MyEvent {
-            id: 5,
-            value: 50,
-            target: 0,
-            action: Action::Insert,
-        });
+        buffer.update(insert(1));
+        buffer.update(insert(2));
+        buffer.update(insert(3));
+        buffer.update(insert(4));
+        buffer.update(insert(5));
 
         // Verify that the current state is as expected (order matters here).
         assert_eq!(buffer.state_ref().data, vec![10, 20, 30, 40, 50]);
@@ -354,43 +1053,18 @@ mod tests {
         let mut buffer = DoubleBufferedLagBuffer::<MyState, 4>::new(MyState::new());
 
         // Apply 4 insert events in order.
-        buffer.update(MyEvent {
-            id: 1,
-            value: 10,
-            target: 0,
-            action: Action::Insert,
-        });
-        buffer.update(MyEvent {
-            id: 2,
-            value: 20,
-            target: 0,
-            action: Action::Insert,
-        });
-        buffer.update(MyEvent {
-            id: 3,
-            value: 30,
-            target: 0,
-            action: Action::Insert,
-        });
-        buffer.update(MyEvent {
-            id: 4,
-            value: 40,
-            target: 0,
-            action: Action::Insert,
-        });
-        buffer.update(MyEvent {
-            id: 5,
-            value: 50,
-            target: 0,
-            action: Action::Insert,
-        });
-
-        let mut first = dbg!(buffer.buffer_bases[0].clone());
+        buffer.update(insert(1));
+        buffer.update(insert(2));
+        buffer.update(insert(3));
+        buffer.update(insert(4));
+        buffer.update(insert(5));
+
+        let mut first = buffer.buffer_bases[0].clone();
         for event in &buffer.buffers[0] {
             first.apply(event);
         }
 
-        let mut second = dbg!(buffer.buffer_bases[1].clone());
+        let mut second = buffer.buffer_bases[1].clone();
         for event in &buffer.buffers[1] {
             second.apply(event);
         }
@@ -402,24 +1076,9 @@ mod tests {
         let mut buffer = DoubleBufferedLagBuffer::<MyState, 4>::new(MyState::new());
 
         // Apply some insert events.
-        buffer.update(MyEvent {
-            id: 1,
-            value: 10,
-            target: 0,
-            action: Action::Insert,
-        });
-        buffer.update(MyEvent {
-            id: 3,
-            value: 30,
-            target: 0,
-            action: Action::Insert,
-        });
-        buffer.update(MyEvent {
-            id: 2,
-            value: 20,
-            target: 0,
-            action: Action::Insert,
-        }); // Out-of-order event.
+        buffer.update(insert(1));
+        buffer.update(insert(3));
+        buffer.update(insert(2)); // Out-of-order event.
 
         // The state should reflect that the event with id=2 was applied in the correct order.
         assert_eq!(buffer.state_ref().data, vec![10, 20, 30]);
@@ -430,36 +1089,11 @@ mod tests {
         let mut buffer = DoubleBufferedLagBuffer::<MyState, 4>::new(MyState::new());
 
         // Apply 5 events to trigger buffer swap.
-        buffer.update(MyEvent {
-            id: 1,
-            value: 10,
-            target: 0,
-            action: Action::Insert,
-        });
-        buffer.update(MyEvent {
-            id: 2,
-            value: 20,
-            target: 0,
-            action: Action::Insert,
-        });
-        buffer.update(MyEvent {
-            id: 3,
-            value: 30,
-            target: 0,
-            action: Action::Insert,
-        });
-        buffer.update(MyEvent {
-            id: 4,
-            value: 40,
-            target: 0,
-            action: Action::Insert,
-        });
-        buffer.update(MyEvent {
-            id: 5,
-            value: 50,
-            target: 0,
-            action: Action::Insert,
-        });
+        buffer.update(insert(1));
+        buffer.update(insert(2));
+        buffer.update(insert(3));
+        buffer.update(insert(4));
+        buffer.update(insert(5));
 
         // After buffer swap, one more than half of the events should be in the active buffer.
         assert_eq!(buffer.get_active_buffer_len(), 3);
@@ -470,34 +1104,367 @@ mod tests {
         let mut buffer = DoubleBufferedLagBuffer::<MyState, 4>::new(MyState::new());
 
         // Apply insert events.
-        buffer.update(MyEvent {
-            id: 1,
-            value: 10,
-            target: 0,
-            action: Action::Insert,
-        });
-        buffer.update(MyEvent {
-            id: 2,
-            value: 20,
-            target: 0,
-            action: Action::Insert,
-        });
-        buffer.update(MyEvent {
-            id: 3,
-            value: 30,
-            target: 0,
-            action: Action::Insert,
-        });
+        buffer.update(insert(1));
+        buffer.update(insert(2));
+        buffer.update(insert(3));
 
         // Apply a replace event.
-        buffer.update(MyEvent {
-            id: 4,
-            value: 99,
-            target: 20,
-            action: Action::Replace,
-        });
+        buffer.update(replace(4, 20, 99));
 
         // Verify that the replace action was correctly applied.
         assert_eq!(buffer.state_ref().data, vec![10, 99, 30]);
     }
+
+    #[test]
+    fn test_update_outcome() {
+        let mut buffer = DoubleBufferedLagBuffer::<MyState, 4>::new(MyState::new());
+
+        assert_eq!(buffer.update(insert(1)), UpdateOutcome::AppliedInOrder);
+        assert_eq!(buffer.update(insert(3)), UpdateOutcome::AppliedInOrder);
+        assert_eq!(
+            buffer.update(insert(2)),
+            UpdateOutcome::Reordered { replayed: 3 }
+        );
+        assert_eq!(buffer.update(insert(4)), UpdateOutcome::AppliedInOrder);
+
+        // The fifth event overflows the active buffer and triggers a swap.
+        assert_eq!(
+            buffer.update(insert(5)),
+            UpdateOutcome::Swapped { replayed: 0 }
+        );
+        assert_eq!(buffer.state_ref().data, vec![10, 20, 30, 40, 50]);
+    }
+
+    #[test]
+    fn test_late_event_before_secondary_buffer() {
+        let mut buffer = DoubleBufferedLagBuffer::<MyState, 4>::new(MyState::new());
+
+        buffer.update(insert(2));
+        buffer.update(insert(4));
+        buffer.update(insert(6));
+
+        // Lands before the secondary buffer, so the secondary base has to include it.
+        buffer.update(insert(1));
+
+        // Swap, then reconcile from the former secondary buffer.
+        buffer.update(insert(8));
+        assert_eq!(buffer.oldest_reconcilable_key(), Some(4));
+        assert_eq!(
+            buffer.update(insert(5)),
+            UpdateOutcome::Reordered { replayed: 3 }
+        );
+        assert_eq!(buffer.state_ref().data, vec![10, 20, 40, 50, 60, 80]);
+    }
+
+    #[test]
+    fn test_late_events_start_secondary_buffer() {
+        let mut buffer = DoubleBufferedLagBuffer::<MyState, 4>::new(MyState::new());
+
+        // Only late events fill the active buffer, the swap still has a secondary base.
+        for id in [10, 1, 2, 3, 4] {
+            buffer.update(insert(id));
+        }
+        assert_eq!(buffer.committed_state().data, vec![10, 20]);
+        assert_eq!(buffer.oldest_reconcilable_key(), Some(2));
+
+        buffer.update(insert(11));
+        buffer.update(insert(12));
+        assert_eq!(buffer.update(insert(5)), UpdateOutcome::RejectedTooLate);
+        assert_eq!(buffer.state_ref().data, vec![10, 20, 30, 40, 100, 110, 120]);
+    }
+
+    #[test]
+    fn test_late_policy() {
+        let mut buffer = DoubleBufferedLagBuffer::<MyState, 2>::new(MyState::new());
+        assert_eq!(buffer.oldest_reconcilable_key(), None);
+        for id in 2..=4 {
+            buffer.update(insert(id));
+        }
+        assert_eq!(buffer.oldest_reconcilable_key(), Some(2));
+
+        // The default policy drops the event without touching the state.
+        assert_eq!(buffer.update(insert(1)), UpdateOutcome::RejectedTooLate);
+        assert_eq!(buffer.state_ref().data, vec![20, 30, 40]);
+
+        let mut buffer = DoubleBufferedLagBuffer::<MyState, 2>::new(MyState::new())
+            .with_late_policy(LatePolicy::Error);
+        for id in 2..=4 {
+            buffer.update(insert(id));
+        }
+        let error = buffer.try_update(insert(1)).unwrap_err();
+        assert_eq!(error.event.id, 1);
+
+        let mut buffer = DoubleBufferedLagBuffer::<MyState, 2>::new(MyState::new())
+            .with_late_policy(LatePolicy::Reset(Box::new(|_| MyState {
+                data: vec![10, 20, 30, 40],
+                ..MyState::new()
+            })));
+        for id in 2..=4 {
+            buffer.update(insert(id));
+        }
+        assert_eq!(buffer.update(insert(1)), UpdateOutcome::Reset);
+        assert_eq!(buffer.state_ref().data, vec![10, 20, 30, 40]);
+        assert_eq!(buffer.oldest_reconcilable_key(), None);
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut buffer = DynDoubleBufferedLagBuffer::<MyState>::with_capacity(MyState::new(), 8);
+        assert_eq!(buffer.capacity(), 8);
+
+        for id in [1, 2, 3, 5, 6, 7] {
+            buffer.update(insert(id));
+        }
+
+        // Shrinking folds the two oldest events into the base.
+        buffer.resize(4);
+        assert_eq!(buffer.capacity(), 4);
+        assert_eq!(buffer.get_active_buffer_len(), 4);
+        assert_eq!(buffer.get_secondary_buffer_len(), 2);
+        assert_eq!(buffer.oldest_reconcilable_key(), Some(2));
+        assert_eq!(buffer.state_ref().data, vec![10, 20, 30, 50, 60, 70]);
+
+        // Swap into the rebuilt secondary buffer and reconcile from it.
+        buffer.update(insert(8));
+        assert_eq!(buffer.oldest_reconcilable_key(), Some(5));
+        assert_eq!(buffer.update(insert(4)), UpdateOutcome::RejectedTooLate);
+        assert_eq!(buffer.state_ref().data, vec![10, 20, 30, 50, 60, 70, 80]);
+
+        buffer.update(insert(9));
+        assert_eq!(
+            buffer.update(insert(6)),
+            UpdateOutcome::Swapped { replayed: 5 }
+        );
+        assert_eq!(
+            buffer.state_ref().data,
+            vec![10, 20, 30, 50, 60, 60, 70, 80, 90]
+        );
+    }
+
+    #[test]
+    fn test_events_in_range() {
+        let mut buffer = DoubleBufferedLagBuffer::<MyState, 8>::new(MyState::new());
+
+        for id in [1, 2, 4, 5, 7] {
+            buffer.update(insert(id));
+        }
+
+        let ids = |events: Vec<&MyEvent>| events.iter().map(|e| e.id).collect::<Vec<_>>();
+        assert_eq!(ids(buffer.events_in_range(2..5).collect()), vec![2, 4]);
+        assert_eq!(ids(buffer.events_in_range(..=2).collect()), vec![1, 2]);
+        assert_eq!(ids(buffer.events_in_range(5..).collect()), vec![5, 7]);
+        assert_eq!(buffer.oldest_key(), Some(1));
+        assert_eq!(buffer.newest_key(), Some(7));
+        assert_eq!(buffer.len(), 5);
+        assert!(!buffer.is_empty());
+    }
+
+    #[test]
+    fn test_advance_watermark() {
+        let committed = Arc::new(Mutex::new(Vec::new()));
+        let mut buffer =
+            DoubleBufferedLagBuffer::<MyState, 4>::new(MyState::new()).with_on_commit({
+                let committed = Arc::clone(&committed);
+                move |e: &MyEvent| committed.lock().unwrap().push(e.id)
+            });
+
+        for id in 1..=3 {
+            buffer.update(insert(id));
+        }
+        buffer.advance_watermark(2);
+        assert_eq!(*committed.lock().unwrap(), vec![1, 2]);
+        assert_eq!(buffer.committed_state().data, vec![10, 20]);
+        assert_eq!(buffer.update(insert(1)), UpdateOutcome::RejectedTooLate);
+
+        // A swap commits everything before the secondary buffer.
+        for id in 4..=7 {
+            buffer.update(insert(id));
+        }
+        assert_eq!(*committed.lock().unwrap(), vec![1, 2, 3, 4]);
+        assert_eq!(buffer.committed_state().data, vec![10, 20, 30, 40]);
+
+        // Moving the watermark backwards has no effect.
+        buffer.advance_watermark(3);
+        assert_eq!(buffer.oldest_reconcilable_key(), Some(4));
+
+        buffer.clear();
+        assert_eq!(*committed.lock().unwrap(), vec![1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(buffer.committed_state(), buffer.state_ref());
+    }
+
+    #[test]
+    fn test_advance_watermark_reject_duplicates() {
+        let mut buffer = DoubleBufferedLagBuffer::<MyState, 4>::new(MyState::new())
+            .with_tie_break(TieBreak::RejectDuplicates);
+
+        for id in [1, 2, 4] {
+            buffer.update(insert(id));
+        }
+
+        // No event with the watermark's key was folded, so it is not a duplicate
+        buffer.advance_watermark(3);
+        assert!(buffer.update(insert(3)).is_accepted());
+        assert_eq!(buffer.state_ref().data, vec![10, 20, 30, 40]);
+
+        buffer.advance_watermark(4);
+        assert_eq!(buffer.update(insert(4)), UpdateOutcome::Duplicate);
+    }
+
+    #[test]
+    fn test_update_batch() {
+        let mut buffer = DoubleBufferedLagBuffer::<MyState, 4>::new(MyState::new());
+
+        for id in [2, 4, 6] {
+            buffer.update(insert(id));
+        }
+
+        // 1, 3 and 5 are merged with a single replay, 1 and 2 no longer fit and are retired.
+        let batch = buffer.update_batch([5, 1, 3, 7].map(insert));
+        assert_eq!(
+            batch,
+            BatchOutcome {
+                accepted: 4,
+                rejected: 0,
+                replayed: 4,
+                swapped: true,
+            }
+        );
+        assert_eq!(buffer.state_ref().data, vec![10, 20, 30, 40, 50, 60, 70]);
+        assert_eq!(buffer.stats().replayed, 4);
+
+        let batch = buffer.update_batch([2, 9, 8].map(insert));
+        assert_eq!(batch.rejected, 1);
+        assert_eq!(
+            buffer.state_ref().data,
+            vec![10, 20, 30, 40, 50, 60, 70, 80, 90]
+        );
+    }
+
+    #[test]
+    fn test_update_batch_fold_only() {
+        let mut buffer = DoubleBufferedLagBuffer::<MyState, 4>::new(MyState::new())
+            .with_observer(Recorder::default());
+
+        for id in [2, 4, 6] {
+            buffer.update(insert(id));
+        }
+
+        // 1 no longer fits and is folded, which retires it like a swap
+        let batch = buffer.update_batch([3, 1].map(insert));
+        assert!(batch.swapped);
+        assert_eq!(batch.replayed, 4);
+        assert_eq!(buffer.committed_state().data, vec![10]);
+        assert_eq!(buffer.observer().swaps, 1);
+        assert_eq!(buffer.stats().swaps, 1);
+        assert_eq!(buffer.state_ref().data, vec![10, 20, 30, 40, 60]);
+    }
+
+    #[test]
+    fn test_retract() {
+        let mut buffer = DoubleBufferedLagBuffer::<MyState, 4>::new(MyState::new());
+
+        for id in 1..=3 {
+            buffer.update(insert(id));
+        }
+
+        // 1 lies before the secondary buffer, so its base is rebuilt without it.
+        assert_eq!(buffer.retract(&1).map(|e| e.id), Some(1));
+        assert_eq!(buffer.state_ref().data, vec![20, 30]);
+        assert_eq!(buffer.get_secondary_buffer_len(), 1);
+
+        for id in 4..=6 {
+            buffer.update(insert(id));
+        }
+        assert_eq!(buffer.committed_state().data, vec![20]);
+
+        assert!(buffer.retract(&5).is_some());
+        assert_eq!(buffer.state_ref().data, vec![20, 30, 40, 60]);
+        assert!(buffer.retract(&2).is_none());
+    }
+
+    #[test]
+    fn test_tie_break_batch() {
+        let mut buffer = DoubleBufferedLagBuffer::<MyState, 8>::new(MyState::new())
+            .with_tie_break(TieBreak::RejectDuplicates);
+        for id in [1, 2, 5] {
+            buffer.update(insert(id));
+        }
+
+        // 2 is already buffered and the second 3 duplicates the first one of the batch.
+        let batch = buffer.update_batch([
+            insert_value(3, 30),
+            insert_value(2, 21),
+            insert_value(3, 31),
+            insert_value(4, 40),
+        ]);
+        assert_eq!((batch.accepted, batch.rejected), (2, 2));
+        assert_eq!(buffer.state_ref().data, vec![10, 20, 30, 40, 50]);
+        assert_eq!(buffer.update(insert_value(5, 51)), UpdateOutcome::Duplicate);
+
+        // Higher values first among equal keys.
+        let mut buffer = DoubleBufferedLagBuffer::<MyState, 8>::new(MyState::new()).with_tie_break(
+            TieBreak::SecondaryKey(Box::new(|a: &MyEvent, b: &MyEvent| b.value.cmp(&a.value))),
+        );
+        for id in [1, 2, 3] {
+            buffer.update(insert(id));
+        }
+        buffer.update_batch([
+            insert_value(2, 21),
+            insert_value(3, 33),
+            insert_value(2, 22),
+        ]);
+        assert_eq!(buffer.state_ref().data, vec![10, 22, 21, 20, 33, 30]);
+    }
+
+    #[test]
+    fn test_amend() {
+        let mut buffer = DoubleBufferedLagBuffer::<MyState, 4>::new(MyState::new());
+
+        for id in 1..=3 {
+            buffer.update(insert(id));
+        }
+
+        // 2 lies before the secondary buffer, 3 in both buffers.
+        let amended = buffer.amend(insert_value(2, 25)).unwrap().replaced();
+        assert_eq!(amended.map(|e| e.value), Some(20));
+        let amended = buffer.amend(insert_value(3, 35)).unwrap().replaced();
+        assert_eq!(amended.map(|e| e.value), Some(30));
+        assert_eq!(buffer.state_ref().data, vec![10, 25, 35]);
+
+        // After the swap, the secondary buffer holds the amended events.
+        for id in 4..=6 {
+            buffer.update(insert(id));
+        }
+        assert_eq!(buffer.committed_state().data, vec![10, 25]);
+        assert_eq!(buffer.state_ref().data, vec![10, 25, 35, 40, 50, 60]);
+
+        buffer.amend(insert_value(5, 55)).unwrap();
+        assert_eq!(buffer.state_ref().data, vec![10, 25, 35, 40, 55, 60]);
+    }
+
+    #[test]
+    fn test_observer() {
+        let mut buffer = DoubleBufferedLagBuffer::<MyState, 4>::new(MyState::new())
+            .with_observer(Recorder::default());
+
+        for id in [1, 2, 3, 5, 4, 1] {
+            buffer.update(insert(id));
+        }
+
+        let observer = buffer.observer();
+        assert_eq!(observer.applied, vec![1, 2, 3, 5, 4]);
+        assert_eq!(observer.rollbacks, vec![(4, 5)]);
+        assert_eq!(observer.swaps, 1);
+        assert_eq!(observer.dropped, vec![1]);
+
+        // Every other path that moves the base is a swap as well.
+        buffer.advance_watermark(3);
+        buffer.advance_watermark(3);
+        assert_eq!(buffer.observer().swaps, 2);
+        buffer.resize(1);
+        assert_eq!(buffer.observer().swaps, 3);
+        buffer.clear();
+        buffer.clear();
+        assert_eq!(buffer.observer().swaps, 4);
+    }
 }
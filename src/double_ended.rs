@@ -1,26 +1,51 @@
-use crate::{Event, State};
-
-pub struct CircularBuffer<T, const SIZE: usize> {
-    buffer: [Option<T>; SIZE],
+use std::fmt;
+use std::ops::RangeBounds;
+
+use crate::batch::{self, merge_by_key, BatchBuffer};
+use crate::key::{KeyOrder, RelativeCmp};
+use crate::stats::StatsCollector;
+use crate::trace::KeyFormat;
+use crate::{
+    AmendOutcome, BatchOutcome, CommitCallback, Event, KeyDistance, LagBufferObserver,
+    LagBufferStats, LatePolicy, RelativeOrd, State, TieBreak, TooLateError, UpdateOutcome,
+};
+
+// Decides whether a buffered key has fallen too far behind the head key.
+type ExpiryFn<OrderKey> = Box<dyn Fn(&OrderKey, &OrderKey) -> bool + Send>;
+
+pub struct CircularBuffer<T> {
+    buffer: Vec<Option<T>>,
     capacity: usize,
     start: usize,
     end: usize,
     full: bool,
 }
 
-impl<T, const SIZE: usize> CircularBuffer<T, SIZE> {
+impl<T> CircularBuffer<T> {
     // Create a new circular buffer with a given capacity
-    pub fn new() -> Self {
-        assert!(SIZE > 0, "Capacity must be greater than 0");
+    pub fn with_capacity(capacity: usize) -> Self {
+        assert!(capacity > 0, "Capacity must be greater than 0");
         CircularBuffer {
-            buffer: [const { None }; SIZE],
-            capacity: SIZE,
+            buffer: (0..capacity).map(|_| None).collect(),
+            capacity,
             start: 0,
             end: 0,
             full: false,
         }
     }
 
+    // Change the capacity, keeping the newest elements
+    // Returns the dropped elements, oldest first
+    pub fn resize(&mut self, new_capacity: usize) -> Vec<T> {
+        let mut resized = Self::with_capacity(new_capacity);
+        let mut dropped = Vec::new();
+        while let Some(item) = self.pop() {
+            dropped.extend(resized.push(item));
+        }
+        *self = resized;
+        dropped
+    }
+
     // Push an element into the circular buffer
     // Returns an Option containing the dropped element, if any
     pub fn push(&mut self, item: T) -> Option<T> {
@@ -44,7 +69,6 @@ impl<T, const SIZE: usize> CircularBuffer<T, SIZE> {
     }
 
     // Get the current size of the buffer
-    #[cfg(test)]
     pub fn size(&self) -> usize {
         if self.full {
             self.capacity
@@ -56,7 +80,6 @@ impl<T, const SIZE: usize> CircularBuffer<T, SIZE> {
     }
 
     // Get the capacity of the buffer
-    #[cfg(test)]
     pub fn capacity(&self) -> usize {
         self.capacity
     }
@@ -85,15 +108,15 @@ impl<T, const SIZE: usize> CircularBuffer<T, SIZE> {
         item
     }
 
-    // // Peek at the next element to be popped, without removing it
-    // pub fn peek(&self) -> Option<&T> {
-    //     if self.is_empty() {
-    //         None
-    //     } else {
-    //         // Safely access the element at the start index
-    //         self.buffer[self.start].as_ref()
-    //     }
-    // }
+    // Peek at the next element to be popped, without removing it
+    pub fn peek(&self) -> Option<&T> {
+        if self.is_empty() {
+            None
+        } else {
+            // Safely access the element at the start index
+            self.buffer[self.start].as_ref()
+        }
+    }
 
     // Peek at the next element to be popped, without removing it
     pub fn peek_end(&self) -> Option<&T> {
@@ -111,50 +134,613 @@ impl<T, const SIZE: usize> CircularBuffer<T, SIZE> {
             self.buffer[end_index].as_ref()
         }
     }
+
+    // Get a mutable reference to the element at `index`, counted from the oldest
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        if index >= self.size() {
+            return None;
+        }
+        self.buffer[(self.start + index) % self.capacity].as_mut()
+    }
+
+    // Swap the elements at `a` and `b`, counted from the oldest
+    pub fn swap(&mut self, a: usize, b: usize) {
+        assert!(a < self.size() && b < self.size(), "index out of bounds");
+        self.buffer.swap(
+            (self.start + a) % self.capacity,
+            (self.start + b) % self.capacity,
+        );
+    }
+
+    // Iterate over the elements from the oldest to the newest
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        (0..self.size()).filter_map(move |i| self.buffer[(self.start + i) % self.capacity].as_ref())
+    }
 }
 
-pub struct DoubleEndedLagBuffer<S: State<OrderKey>, const SIZE: usize, OrderKey: Ord = usize> {
-    buffer: CircularBuffer<S::Event, SIZE>,
+pub struct DoubleEndedLagBuffer<
+    S: State<OrderKey>,
+    const SIZE: usize,
+    OrderKey: Ord = usize,
+    Observer = (),
+> {
+    buffer: CircularBuffer<S::Event>,
     head: S,
     tail: S,
+    tail_key: Option<OrderKey>,
+    watermark: Option<OrderKey>,
+    horizon: Option<ExpiryFn<OrderKey>>,
+    late_policy: LatePolicy<S, OrderKey>,
+    tie_break: TieBreak<S, OrderKey>,
+    stats: StatsCollector<OrderKey>,
+    key_format: Option<KeyFormat<OrderKey>>,
+    relative_order: Option<RelativeCmp<OrderKey>>,
+    on_commit: Option<CommitCallback<S::Event>>,
+    observer: Observer,
 }
 
-impl<S: State<OrderKey>, const SIZE: usize, OrderKey: Ord> DoubleEndedLagBuffer<S, SIZE, OrderKey> {
+/// A [`DoubleEndedLagBuffer`] whose capacity is only chosen at runtime.
+///
+/// All capacities share one type. Create it with [`with_capacity`](DoubleEndedLagBuffer::with_capacity).
+pub type DynDoubleEndedLagBuffer<S, OrderKey = usize, Observer = ()> =
+    DoubleEndedLagBuffer<S, 0, OrderKey, Observer>;
+
+impl<S: State<OrderKey>, const SIZE: usize, OrderKey: Ord + Clone>
+    DoubleEndedLagBuffer<S, SIZE, OrderKey>
+{
+    /// Creates a new `DoubleEndedLagBuffer` that holds `SIZE` events.
+    ///
+    /// Fails to compile if `SIZE` is 0, as for a [`DynDoubleEndedLagBuffer`]. Use
+    /// [`with_capacity`](Self::with_capacity) for those.
     pub fn new(initial_state: S) -> Self {
+        const { assert!(SIZE > 0, "SIZE must be greater than 0, use with_capacity") };
+        Self::with_capacity(initial_state, SIZE)
+    }
+
+    /// Creates a new `DoubleEndedLagBuffer` that holds `capacity` events, overriding `SIZE`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is 0.
+    pub fn with_capacity(initial_state: S, capacity: usize) -> Self {
         Self {
-            buffer: CircularBuffer::new(),
+            buffer: CircularBuffer::with_capacity(capacity),
             head: initial_state.clone(),
             tail: initial_state,
+            tail_key: None,
+            watermark: None,
+            horizon: None,
+            late_policy: LatePolicy::default(),
+            tie_break: TieBreak::default(),
+            stats: StatsCollector::default(),
+            key_format: None,
+            relative_order: None,
+            on_commit: None,
+            observer: (),
+        }
+    }
+}
+
+impl<
+        S: State<OrderKey>,
+        const SIZE: usize,
+        OrderKey: Ord + Clone,
+        Observer: LagBufferObserver<S, OrderKey>,
+    > DoubleEndedLagBuffer<S, SIZE, OrderKey, Observer>
+{
+    /// Sets the observer that is notified about what [`update`](Self::update) does.
+    pub fn with_observer<O: LagBufferObserver<S, OrderKey>>(
+        self,
+        observer: O,
+    ) -> DoubleEndedLagBuffer<S, SIZE, OrderKey, O> {
+        DoubleEndedLagBuffer {
+            buffer: self.buffer,
+            head: self.head,
+            tail: self.tail,
+            tail_key: self.tail_key,
+            watermark: self.watermark,
+            horizon: self.horizon,
+            late_policy: self.late_policy,
+            tie_break: self.tie_break,
+            stats: self.stats,
+            key_format: self.key_format,
+            relative_order: self.relative_order,
+            on_commit: self.on_commit,
+            observer,
         }
     }
 
-    pub fn update(&mut self, event: S::Event) {
-        let in_order = match self.buffer.peek_end() {
-            Some(last_event) => last_event.get_order_key() <= event.get_order_key(),
-            None => true,
+    /// Returns a reference to the observer.
+    pub fn observer(&self) -> &Observer {
+        &self.observer
+    }
+
+    /// Returns a mutable reference to the observer.
+    pub fn observer_mut(&mut self) -> &mut Observer {
+        &mut self.observer
+    }
+
+    /// Sets the policy for events that are too old to be reconciled.
+    pub fn with_late_policy(mut self, late_policy: LatePolicy<S, OrderKey>) -> Self {
+        self.late_policy = late_policy;
+        self
+    }
+
+    /// Sets how events with equal order keys are ordered.
+    pub fn with_tie_break(mut self, tie_break: TieBreak<S, OrderKey>) -> Self {
+        self.tie_break = tie_break;
+        self
+    }
+
+    /// Records the order key of every update on its trace span.
+    ///
+    /// Only has an effect with the `tracing` feature.
+    pub fn with_traced_keys(mut self) -> Self
+    where
+        OrderKey: fmt::Debug,
+    {
+        self.key_format = Some(|key| format!("{key:?}"));
+        self
+    }
+
+    /// Compares order keys with [`RelativeOrd::cmp_relative`], anchored at the last folded key.
+    ///
+    /// Use this for keys like [`WrappingSeq`](crate::WrappingSeq), whose `Ord` doesn't follow
+    /// the order in which the events were produced.
+    pub fn with_relative_keys(mut self) -> Self
+    where
+        OrderKey: RelativeOrd,
+    {
+        self.relative_order = Some(OrderKey::cmp_relative);
+        self
+    }
+
+    /// Sets a callback that receives every event once it is committed.
+    ///
+    /// An event is committed when it is folded into the tail state, because it was pushed out of
+    /// the buffer, expired, or was committed by [`advance_watermark`](Self::advance_watermark) or
+    /// [`clear`](Self::clear). Committed events can no longer be reconciled, so the callback sees
+    /// them exactly once and in key order.
+    pub fn with_on_commit(mut self, on_commit: impl FnMut(&S::Event) + Send + 'static) -> Self {
+        self.on_commit = Some(Box::new(on_commit));
+        self
+    }
+
+    pub fn update(&mut self, event: S::Event) -> UpdateOutcome {
+        self.try_update(event)
+            .unwrap_or(UpdateOutcome::RejectedTooLate)
+    }
+
+    /// Updates the buffer with a new event, like [`update`](Self::update).
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`TooLateError`] holding the event if it is too old to be reconciled and the
+    /// buffer uses [`LatePolicy::Error`].
+    pub fn try_update(&mut self, event: S::Event) -> Result<UpdateOutcome, TooLateError<S::Event>> {
+        trace_span!(
+            span,
+            "update",
+            buffer = "double_ended",
+            len = self.len(),
+            key = tracing::field::Empty,
+            outcome = tracing::field::Empty
+        );
+        let key = event.get_order_key();
+        trace_key!(span, self.key_format, &key);
+        let head_key = self.head_key();
+        let result = self.reconcile(event);
+        trace_record!(
+            span,
+            "outcome",
+            tracing::field::debug(&result.as_ref().ok())
+        );
+        self.stats.record(&result, head_key.as_ref(), &key);
+        result
+    }
+
+    // Places the event and reconciles the state, see `update`
+    fn reconcile(&mut self, event: S::Event) -> Result<UpdateOutcome, TooLateError<S::Event>> {
+        let keys = self.key_order();
+        if self.is_too_late(&event.get_order_key()) {
+            self.observer.on_drop(&event);
+            return LatePolicy::reject(self, |buffer| &mut buffer.late_policy, event);
+        }
+
+        // Events go after every buffered event that doesn't sort after them
+        let in_order = self
+            .buffer
+            .peek_end()
+            .is_none_or(|last_event| self.tie_break.is_after(&keys, last_event, &event));
+        let previous = if in_order {
+            self.buffer.peek_end()
+        } else {
+            self.buffer
+                .iter()
+                .take_while(|buffered| self.tie_break.is_after(&keys, buffered, &event))
+                .last()
         };
-        if in_order {
+        let previous_key = previous
+            .map(S::Event::get_order_key)
+            .or_else(|| self.tail_key.clone());
+        if self
+            .tie_break
+            .is_duplicate(previous_key.as_ref(), &event.get_order_key())
+        {
+            return Ok(UpdateOutcome::Duplicate);
+        }
+
+        let tail_key = self.tail_key.clone();
+        let mut outcome = if in_order {
             self.head.apply(&event);
+            self.observer.on_apply(&event);
             if let Some(ev) = self.buffer.push(event) {
-                self.tail.apply(&ev);
+                self.fold_into_tail(ev);
             }
+            UpdateOutcome::AppliedInOrder
         } else {
-            let mut ev = Some(event);
-            let mut cb = CircularBuffer::<S::Event, SIZE>::new();
-            self.head = self.tail.clone();
-            while let Some(event) = self.buffer.pop() {
-                if let Some(e) = &ev {
-                    if event.get_order_key() > e.get_order_key() {
-                        self.head.apply(&e);
-                        if let Some(e) = ev.take() {
-                            cb.push(e);
-                        }
+            let key = event.get_order_key();
+            self.observer.on_apply(&event);
+            trace_span!(span, "reconstruct", replayed = tracing::field::Empty);
+            let mut late = Some(event);
+            let mut reordered = CircularBuffer::<S::Event>::with_capacity(self.capacity());
+            while let Some(buffered) = self.buffer.pop() {
+                if let Some(e) = late.take_if(|e| !self.tie_break.is_after(&keys, &buffered, e)) {
+                    if let Some(dropped) = reordered.push(e) {
+                        self.fold_into_tail(dropped);
                     }
-                    self.head.apply(&event);
-                    cb.push(event);
                 }
+                if let Some(dropped) = reordered.push(buffered) {
+                    self.fold_into_tail(dropped);
+                }
+            }
+            self.buffer = reordered;
+
+            self.head = self.tail.clone();
+            for buffered in self.buffer.iter() {
+                self.head.apply(buffered);
+            }
+            trace_record!(span, "replayed", self.buffer.size());
+            self.observer.on_rollback(&key, self.buffer.size());
+            UpdateOutcome::Reordered {
+                replayed: self.buffer.size(),
+            }
+        };
+
+        self.fold_expired();
+        if self.tail_key != tail_key {
+            trace_span!(_span, "swap", len = self.buffer.size());
+            self.forget_folded();
+            self.observer.on_swap(&self.tail);
+            self.stats.record_swap();
+            outcome = UpdateOutcome::Swapped {
+                replayed: outcome.replayed(),
+            };
+        }
+
+        Ok(outcome)
+    }
+
+    /// Updates the buffer with a batch of events, reconciling all of them with a single replay.
+    ///
+    /// The batch is sorted by key first. Events that are too late are handed to the
+    /// [`LatePolicy`] and events that are not older than the newest buffered event are applied
+    /// in order, both exactly like in [`update`](Self::update). All other events are merged into
+    /// the buffer and the head state is replayed once from the tail state.
+    ///
+    /// # Returns
+    ///
+    /// A [`BatchOutcome`] summarizing how the events were handled.
+    pub fn update_batch(&mut self, events: impl IntoIterator<Item = S::Event>) -> BatchOutcome {
+        batch::update_batch(self, events)
+    }
+
+    /// Removes a buffered event and reconstructs the head state as though it never happened.
+    ///
+    /// Events that were already folded into the tail state can't be retracted.
+    ///
+    /// # Arguments
+    ///
+    /// - `key`: The order key of the event to remove. If several events share the key, the oldest
+    ///   one is removed.
+    ///
+    /// # Returns
+    ///
+    /// The removed event, or `None` if no buffered event has the given key.
+    pub fn retract(&mut self, key: &OrderKey) -> Option<S::Event> {
+        if !self.buffer.iter().any(|e| e.get_order_key() == *key) {
+            return None;
+        }
+
+        let mut retracted = None;
+        let mut remaining = CircularBuffer::<S::Event>::with_capacity(self.capacity());
+        while let Some(buffered) = self.buffer.pop() {
+            if retracted.is_none() && buffered.get_order_key() == *key {
+                retracted = Some(buffered);
+            } else {
+                remaining.push(buffered);
             }
         }
+        self.buffer = remaining;
+
+        trace_span!(_span, "reconstruct", replayed = self.buffer.size());
+        self.head = self.tail.clone();
+        for buffered in self.buffer.iter() {
+            self.head.apply(buffered);
+        }
+        self.observer.on_rollback(key, self.buffer.size());
+
+        retracted
+    }
+
+    /// Replaces the buffered event with the same order key and reconstructs the head state.
+    ///
+    /// If no buffered event has the key, the event is handled like in [`try_update`](Self::try_update).
+    ///
+    /// # Arguments
+    ///
+    /// - `event`: The corrected event. If several buffered events share its key, the oldest one is
+    ///   replaced. With [`TieBreak::SecondaryKey`], the corrected event is moved among them to
+    ///   where the function orders it.
+    ///
+    /// # Errors
+    ///
+    /// Returns the [`TooLateError`] of [`try_update`](Self::try_update) for an event that is
+    /// handled like there.
+    pub fn amend(
+        &mut self,
+        event: S::Event,
+    ) -> Result<AmendOutcome<S::Event>, TooLateError<S::Event>> {
+        let keys = self.key_order();
+        let key = event.get_order_key();
+        let Some(position) = self.buffer.iter().position(|e| e.get_order_key() == key) else {
+            return self.try_update(event).map(AmendOutcome::Updated);
+        };
+        let amended = self
+            .buffer
+            .get_mut(position)
+            .map(|buffered| std::mem::replace(buffered, event))
+            .expect("the position is within the buffer");
+        let target = self
+            .tie_break
+            .reposition(&keys, self.buffer.iter(), position);
+        for index in position..target {
+            self.buffer.swap(index, index + 1);
+        }
+
+        let replayed = self.buffer.size();
+        trace_span!(_span, "reconstruct", replayed);
+        self.head = self.tail.clone();
+        for buffered in self.buffer.iter() {
+            self.head.apply(buffered);
+        }
+        self.observer.on_rollback(&key, replayed);
+
+        Ok(AmendOutcome::Replaced {
+            event: amended,
+            replayed,
+        })
+    }
+
+    /// Reconstructs the state as of the given order key without touching the current state.
+    ///
+    /// The result contains every retained event whose key is not newer than `key`.
+    ///
+    /// # Returns
+    ///
+    /// `None` if `key` is older than the [`oldest_reconcilable_key`](Self::oldest_reconcilable_key).
+    pub fn state_at(&self, key: &OrderKey) -> Option<S> {
+        if self.is_too_late(key) {
+            return None;
+        }
+        let keys = self.key_order();
+        if self
+            .head_key()
+            .is_none_or(|head_key| keys.le(&head_key, key))
+        {
+            return Some(self.head.clone());
+        }
+        let mut state = self.tail.clone();
+        for buffered in self
+            .buffer
+            .iter()
+            .take_while(|e| keys.le(&e.get_order_key(), key))
+        {
+            state.apply(buffered);
+        }
+        Some(state)
+    }
+
+    /// Returns the oldest order key that can still be reconciled.
+    ///
+    /// Events with an older key are handed to the [`LatePolicy`]. Returns `None` while no
+    /// events have been folded into the tail state, i.e. while every key can be reconciled.
+    pub fn oldest_reconcilable_key(&self) -> Option<OrderKey> {
+        self.key_order()
+            .max(self.tail_key.clone(), self.watermark.clone())
+    }
+
+    /// Returns the number of buffered events.
+    pub fn len(&self) -> usize {
+        self.buffer.size()
+    }
+
+    /// Returns `true` if no events are buffered.
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Returns the number of events the buffer holds before folding the oldest into the tail.
+    pub fn capacity(&self) -> usize {
+        self.buffer.capacity()
+    }
+
+    /// Changes the number of events the buffer holds while keeping the current state.
+    ///
+    /// When shrinking below the number of buffered events, the oldest events are folded into the
+    /// tail state and can no longer be reconciled.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `new_capacity` is 0.
+    pub fn resize(&mut self, new_capacity: usize) {
+        let dropped = self.buffer.resize(new_capacity);
+        let folded = !dropped.is_empty();
+        for event in dropped {
+            self.fold_into_tail(event);
+        }
+        if folded {
+            self.forget_folded();
+            self.observer.on_swap(&self.tail);
+        }
+    }
+
+    /// Commits every buffered event up to and including `key`.
+    ///
+    /// The committed events are folded into the tail state and passed to the
+    /// [`on_commit`](Self::with_on_commit) callback. Afterwards, events older than `key` are
+    /// handed to the [`LatePolicy`]. A watermark older than the current one has no effect.
+    pub fn advance_watermark(&mut self, key: OrderKey) {
+        if self.is_too_late(&key) {
+            return;
+        }
+        let keys = self.key_order();
+        let tail_key = self.tail_key.clone();
+        while self
+            .buffer
+            .peek()
+            .is_some_and(|oldest| keys.le(&oldest.get_order_key(), &key))
+        {
+            if let Some(committed) = self.buffer.pop() {
+                self.fold_into_tail(committed);
+            }
+        }
+        // Only folded events set the tail key, `RejectDuplicates` compares ties against it
+        let watermark = Some(key);
+        if self.tail_key != tail_key || self.watermark != watermark {
+            self.watermark = watermark;
+            self.forget_folded();
+            self.observer.on_swap(&self.tail);
+        }
+    }
+
+    /// Returns the state that contains every committed event and nothing else.
+    ///
+    /// Unlike [`state_ref`](Self::state_ref), this state is final and no late event can change it.
+    pub fn committed_state(&self) -> &S {
+        &self.tail
+    }
+
+    /// Returns the order key of the newest buffered event.
+    pub fn head_key(&self) -> Option<OrderKey> {
+        self.buffer.peek_end().map(S::Event::get_order_key)
+    }
+
+    /// Returns the order key of the newest buffered event, like [`head_key`](Self::head_key).
+    pub fn newest_key(&self) -> Option<OrderKey> {
+        self.head_key()
+    }
+
+    /// Returns the order key of the oldest buffered event.
+    pub fn oldest_key(&self) -> Option<OrderKey> {
+        self.events().next().map(S::Event::get_order_key)
+    }
+
+    /// Returns an iterator over the buffered events in key order.
+    pub fn events(&self) -> impl Iterator<Item = &S::Event> {
+        self.buffer.iter()
+    }
+
+    /// Returns an iterator over the buffered events whose key lies in `range`, in key order.
+    pub fn events_in_range<R: RangeBounds<OrderKey>>(
+        &self,
+        range: R,
+    ) -> impl Iterator<Item = &S::Event> {
+        let keys = self.key_order();
+        self.events()
+            .filter(move |e| keys.contains(&range, &e.get_order_key()))
+    }
+
+    /// Folds all buffered events into the tail state, keeping the current state.
+    pub fn clear(&mut self) {
+        let Some(head_key) = self.head_key() else {
+            return;
+        };
+        self.tail_key = Some(head_key);
+        while let Some(committed) = self.buffer.pop() {
+            if let Some(on_commit) = &mut self.on_commit {
+                on_commit(&committed);
+            }
+        }
+        self.tail = self.head.clone();
+        self.forget_folded();
+        self.observer.on_swap(&self.tail);
+    }
+
+    /// Discards all buffered events and restarts from the given state.
+    pub fn reset(&mut self, state: S) {
+        while self.buffer.pop().is_some() {}
+        self.head = state.clone();
+        self.tail = state;
+        self.tail_key = None;
+        self.watermark = None;
+    }
+
+    fn is_too_late(&self, key: &OrderKey) -> bool {
+        self.oldest_reconcilable_key()
+            .is_some_and(|tail_key| self.key_order().lt(key, &tail_key))
+    }
+
+    fn fold_expired(&mut self) {
+        let (Some(is_expired), Some(head_key)) = (&self.horizon, self.head_key()) else {
+            return;
+        };
+        let expired = self
+            .buffer
+            .iter()
+            .take_while(|oldest| is_expired(&head_key, &oldest.get_order_key()))
+            .count();
+        for _ in 0..expired {
+            if let Some(expired) = self.buffer.pop() {
+                self.fold_into_tail(expired);
+            }
+        }
+    }
+
+    fn fold_into_tail(&mut self, event: S::Event) {
+        self.tail.apply(&event);
+        self.tail_key = Some(event.get_order_key());
+        if let Some(on_commit) = &mut self.on_commit {
+            on_commit(&event);
+        }
+    }
+
+    // Lets the kept states drop what they track for events older than the tail key
+    fn forget_folded(&mut self) {
+        let keys = self.key_order();
+        if let Some(tail_key) = &self.tail_key {
+            let is_folded = |key: &OrderKey| keys.lt(key, tail_key);
+            self.tail.forget(&is_folded);
+            self.head.forget(&is_folded);
+        }
+    }
+
+    // Orders keys for one operation, relative to the oldest key the buffer compares against
+    fn key_order(&self) -> KeyOrder<OrderKey> {
+        KeyOrder::new(self.relative_order, || {
+            self.tail_key.clone().or_else(|| self.oldest_key())
+        })
+    }
+
+    /// Returns the reconciliation metrics collected since the buffer was created or the stats
+    /// were last reset.
+    pub fn stats(&self) -> &LagBufferStats {
+        &self.stats.stats
+    }
+
+    /// Resets the reconciliation metrics.
+    pub fn reset_stats(&mut self) {
+        self.stats.stats = LagBufferStats::default();
     }
 
     /// Returns a reference to the current state.
@@ -167,92 +753,122 @@ impl<S: State<OrderKey>, const SIZE: usize, OrderKey: Ord> DoubleEndedLagBuffer<
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    // Example State and Event implementation for testing.
-
-    #[derive(Clone, PartialEq)]
-    struct MyState {
-        pub data: Vec<i32>,
+impl<
+        S: State<OrderKey>,
+        const SIZE: usize,
+        OrderKey: KeyDistance + Clone,
+        Observer: LagBufferObserver<S, OrderKey>,
+    > DoubleEndedLagBuffer<S, SIZE, OrderKey, Observer>
+where
+    OrderKey::Distance: Send + 'static,
+{
+    /// Adds time-horizon retention to the event-count retention.
+    ///
+    /// Events are folded into the tail state as soon as their order key falls more than `horizon`
+    /// behind the newest key. The [`capacity`](Self::capacity) stays the upper bound: once the
+    /// buffer is full, the oldest events are folded even if they haven't expired yet. Choose it
+    /// large enough for the bursts the buffer should reconcile, only the slots are allocated
+    /// upfront.
+    ///
+    /// # Arguments
+    ///
+    /// - `horizon`: The distance behind the head key within which events can still be reconciled.
+    pub fn with_horizon(mut self, horizon: OrderKey::Distance) -> Self {
+        self.horizon = Some(Box::new(move |head_key: &OrderKey, key: &OrderKey| {
+            head_key.distance_from(key) > horizon
+        }));
+        self
     }
 
-    impl MyState {
-        pub fn new() -> Self {
-            Self { data: Vec::new() }
-        }
+    /// Measures the lateness of out-of-order events in the [`stats`](Self::stats).
+    ///
+    /// Lateness is the [`KeyDistance`] between the newest buffered key and the event's key.
+    pub fn with_lateness_stats(mut self) -> Self {
+        self.stats.lateness = Some(|head_key, key| head_key.distance_units(key));
+        self
     }
+}
 
-    impl State<usize> for MyState {
-        type Event = MyEvent;
+impl<
+        S: State<OrderKey>,
+        const SIZE: usize,
+        OrderKey: Ord + Clone,
+        Observer: LagBufferObserver<S, OrderKey>,
+    > BatchBuffer<S, OrderKey> for DoubleEndedLagBuffer<S, SIZE, OrderKey, Observer>
+{
+    const NAME: &'static str = "double_ended";
+
+    fn tie_break(&self) -> &TieBreak<S, OrderKey> {
+        &self.tie_break
+    }
 
-        fn apply(&mut self, event: &Self::Event) {
-            match event.action {
-                Action::Insert => self.data.push(event.value),
-                Action::Replace => {
-                    if let Some(pos) = self.data.iter().position(|&x| x == event.target) {
-                        self.data[pos] = event.value;
-                    }
-                }
-            }
-        }
+    fn key_order(&self) -> KeyOrder<OrderKey> {
+        self.key_order()
     }
 
-    #[derive(Clone)]
-    enum Action {
-        Insert,
-        Replace,
+    fn stats_collector(&mut self) -> &mut StatsCollector<OrderKey> {
+        &mut self.stats
     }
 
-    #[derive(Clone)]
-    struct MyEvent {
-        id: usize,
-        value: i32,
-        target: i32, // Used for replacing a specific element
-        action: Action,
+    fn folded_key(&self) -> Option<OrderKey> {
+        self.tail_key.clone()
     }
 
-    impl Event<usize> for MyEvent {
-        fn get_order_key(&self) -> usize {
-            self.id
+    // Merges sorted out-of-order events into the buffer and replays it once, see `update_batch`
+    fn merge_batch(&mut self, events: Vec<S::Event>) -> UpdateOutcome {
+        let keys = self.key_order();
+        let tail_key = self.tail_key.clone();
+        let from_key = events[0].get_order_key();
+        for event in &events {
+            self.observer.on_apply(event);
         }
+
+        let mut buffered = Vec::with_capacity(self.buffer.size());
+        while let Some(event) = self.buffer.pop() {
+            buffered.push(event);
+        }
+        let mut merged = CircularBuffer::<S::Event>::with_capacity(self.capacity());
+        for event in merge_by_key(buffered, events, &self.tie_break, &keys) {
+            if let Some(dropped) = merged.push(event) {
+                self.fold_into_tail(dropped);
+            }
+        }
+        self.buffer = merged;
+
+        trace_span!(_span, "reconstruct", replayed = self.buffer.size());
+        self.head = self.tail.clone();
+        for buffered in self.buffer.iter() {
+            self.head.apply(buffered);
+        }
+        self.observer.on_rollback(&from_key, self.buffer.size());
+        let replayed = self.buffer.size();
+
+        self.fold_expired();
+        if self.tail_key != tail_key {
+            self.forget_folded();
+            self.observer.on_swap(&self.tail);
+            self.stats.record_swap();
+            return UpdateOutcome::Swapped { replayed };
+        }
+        UpdateOutcome::Reordered { replayed }
     }
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{insert, replace, MyEvent, MyState, Recorder};
+    use std::sync::{Arc, Mutex};
     #[test]
     fn test_event_application_in_order() {
         let mut buffer = DoubleEndedLagBuffer::<MyState, 4>::new(MyState::new());
 
         // Apply 4 insert events in order.
-        buffer.update(MyEvent {
-            id: 1,
-            value: 10,
-            target: 0,
-            action: Action::Insert,
-        });
-        buffer.update(MyEvent {
-            id: 2,
-            value: 20,
-            target: 0,
-            action: Action::Insert,
-        });
-        buffer.update(MyEvent {
-            id: 3,
-            value: 30,
-            target: 0,
-            action: Action::Insert,
-        });
-        buffer.update(MyEvent {
-            id: 4,
-            value: 40,
-            target: 0,
-            action: Action::Insert,
-        });
-        buffer.update(MyEvent {
-            id: 5,
-            value: 50,
-            target: 0,
-            action: Action::Insert,
-        });
+        buffer.update(insert(1));
+        buffer.update(insert(2));
+        buffer.update(insert(3));
+        buffer.update(insert(4));
+        buffer.update(insert(5));
 
         // Verify that the current state is as expected (order matters here).
         assert_eq!(buffer.state_ref().data, vec![10, 20, 30, 40, 50]);
@@ -263,24 +879,9 @@ mod tests {
         let mut buffer = DoubleEndedLagBuffer::<MyState, 4>::new(MyState::new());
 
         // Apply some insert events.
-        buffer.update(MyEvent {
-            id: 1,
-            value: 10,
-            target: 0,
-            action: Action::Insert,
-        });
-        buffer.update(MyEvent {
-            id: 3,
-            value: 30,
-            target: 0,
-            action: Action::Insert,
-        });
-        buffer.update(MyEvent {
-            id: 2,
-            value: 20,
-            target: 0,
-            action: Action::Insert,
-        }); // Out-of-order event.
+        buffer.update(insert(1));
+        buffer.update(insert(3));
+        buffer.update(insert(2)); // Out-of-order event.
 
         // The state should reflect that the event with id=2 was applied in the correct order.
         assert_eq!(buffer.state_ref().data, vec![10, 20, 30]);
@@ -291,52 +892,58 @@ mod tests {
         let mut buffer = DoubleEndedLagBuffer::<MyState, 4>::new(MyState::new());
 
         // Apply insert events.
-        buffer.update(MyEvent {
-            id: 1,
-            value: 10,
-            target: 0,
-            action: Action::Insert,
-        });
-        buffer.update(MyEvent {
-            id: 2,
-            value: 20,
-            target: 0,
-            action: Action::Insert,
-        });
-        buffer.update(MyEvent {
-            id: 3,
-            value: 30,
-            target: 0,
-            action: Action::Insert,
-        });
+        buffer.update(insert(1));
+        buffer.update(insert(2));
+        buffer.update(insert(3));
 
         // Apply a replace event.
-        buffer.update(MyEvent {
-            id: 4,
-            value: 99,
-            target: 20,
-            action: Action::Replace,
-        });
+        buffer.update(replace(4, 20, 99));
 
         // Verify that the replace action was correctly applied.
         assert_eq!(buffer.state_ref().data, vec![10, 99, 30]);
     }
 
+    #[test]
+    fn test_update_outcome() {
+        let mut buffer = DoubleEndedLagBuffer::<MyState, 4>::new(MyState::new());
+
+        assert_eq!(buffer.update(insert(1)), UpdateOutcome::AppliedInOrder);
+        assert_eq!(buffer.update(insert(3)), UpdateOutcome::AppliedInOrder);
+        assert_eq!(
+            buffer.update(insert(2)),
+            UpdateOutcome::Reordered { replayed: 3 }
+        );
+        assert_eq!(buffer.update(insert(5)), UpdateOutcome::AppliedInOrder);
+
+        // The reordered events must still be buffered for later reconciliation.
+        assert_eq!(
+            buffer.update(insert(4)),
+            UpdateOutcome::Swapped { replayed: 4 }
+        );
+        assert_eq!(buffer.state_ref().data, vec![10, 20, 30, 40, 50]);
+
+        // Every event that moves the tail reports it.
+        let mut buffer = DoubleEndedLagBuffer::<MyState, 2>::new(MyState::new());
+        let outcomes: Vec<_> = (1..=5).map(|id| buffer.update(insert(id))).collect();
+        assert_eq!(outcomes[2..], [UpdateOutcome::Swapped { replayed: 0 }; 3]);
+        assert_eq!(buffer.oldest_reconcilable_key(), Some(3));
+    }
+
     #[test]
     fn test_push_to_empty_buffer() {
-        let mut buffer = CircularBuffer::<usize, 3>::new();
+        let mut buffer = CircularBuffer::<usize>::with_capacity(3);
 
         assert_eq!(buffer.push(1), None);
         assert_eq!(buffer.push(2), None);
         assert_eq!(buffer.push(3), None);
 
-        assert_eq!(buffer.is_full(), true);
-        assert_eq!(buffer.is_empty(), false);
+        assert!(buffer.is_full());
+        assert!(!buffer.is_empty());
     }
 
     #[test]
     fn test_push_when_full() {
-        let mut buffer = CircularBuffer::<usize, 3>::new();
+        let mut buffer = CircularBuffer::<usize>::with_capacity(3);
 
         buffer.push(1);
         buffer.push(2);
@@ -347,12 +954,12 @@ mod tests {
         assert_eq!(buffer.push(5), Some(2));
         assert_eq!(buffer.push(6), Some(3));
 
-        assert_eq!(buffer.is_full(), true);
+        assert!(buffer.is_full());
     }
 
     #[test]
     fn test_pop_from_buffer() {
-        let mut buffer = CircularBuffer::<usize, 3>::new();
+        let mut buffer = CircularBuffer::<usize>::with_capacity(3);
 
         buffer.push(1);
         buffer.push(2);
@@ -363,12 +970,12 @@ mod tests {
         assert_eq!(buffer.pop(), Some(3));
         assert_eq!(buffer.pop(), None); // Buffer is empty now
 
-        assert_eq!(buffer.is_empty(), true);
+        assert!(buffer.is_empty());
     }
 
     #[test]
     fn test_push_and_pop_interleaved() {
-        let mut buffer = CircularBuffer::<usize, 3>::new();
+        let mut buffer = CircularBuffer::<usize>::with_capacity(3);
 
         assert_eq!(buffer.push(1), None);
         assert_eq!(buffer.push(2), None);
@@ -385,12 +992,12 @@ mod tests {
 
         // Now the buffer is empty
         assert_eq!(buffer.pop(), None);
-        assert_eq!(buffer.is_empty(), true);
+        assert!(buffer.is_empty());
     }
 
     #[test]
     fn test_buffer_wraparound() {
-        let mut buffer = CircularBuffer::<usize, 3>::new();
+        let mut buffer = CircularBuffer::<usize>::with_capacity(3);
 
         // Fill the buffer
         buffer.push(1);
@@ -406,12 +1013,12 @@ mod tests {
         assert_eq!(buffer.pop(), Some(4));
         assert_eq!(buffer.pop(), Some(5));
 
-        assert_eq!(buffer.is_empty(), true);
+        assert!(buffer.is_empty());
     }
 
     #[test]
     fn test_size_and_capacity() {
-        let mut buffer = CircularBuffer::<usize, 3>::new();
+        let mut buffer = CircularBuffer::<usize>::with_capacity(3);
 
         assert_eq!(buffer.size(), 0);
         assert_eq!(buffer.capacity(), 3);
@@ -424,10 +1031,183 @@ mod tests {
 
         buffer.push(3);
         assert_eq!(buffer.size(), 3);
-        assert_eq!(buffer.is_full(), true);
+        assert!(buffer.is_full());
 
         // Buffer is full, now overwriting
         buffer.push(4);
         assert_eq!(buffer.size(), 3);
     }
+
+    #[test]
+    fn test_late_policy() {
+        let mut buffer = DoubleEndedLagBuffer::<MyState, 2>::new(MyState::new());
+        assert_eq!(buffer.oldest_reconcilable_key(), None);
+        for id in [2, 4, 5] {
+            buffer.update(insert(id));
+        }
+        assert_eq!(buffer.oldest_reconcilable_key(), Some(2));
+
+        // Still newer than the tail, so it can be reconciled.
+        assert_eq!(
+            buffer.update(insert(3)),
+            UpdateOutcome::Swapped { replayed: 2 }
+        );
+        assert_eq!(buffer.state_ref().data, vec![20, 30, 40, 50]);
+        assert_eq!(buffer.oldest_reconcilable_key(), Some(3));
+
+        // Older than the tail, so it is dropped.
+        assert_eq!(buffer.update(insert(1)), UpdateOutcome::RejectedTooLate);
+        assert_eq!(buffer.state_ref().data, vec![20, 30, 40, 50]);
+
+        let mut buffer = DoubleEndedLagBuffer::<MyState, 2>::new(MyState::new())
+            .with_late_policy(LatePolicy::Error);
+        for id in [2, 4, 5] {
+            buffer.update(insert(id));
+        }
+        assert_eq!(buffer.try_update(insert(1)).unwrap_err().event.id, 1);
+    }
+
+    #[test]
+    fn test_resize_buffer() {
+        let mut buffer = CircularBuffer::<usize>::with_capacity(4);
+        assert_eq!(buffer.capacity(), 4);
+
+        for i in 1..=4 {
+            buffer.push(i);
+        }
+
+        // Shrinking drops the oldest elements
+        assert_eq!(buffer.resize(2), vec![1, 2]);
+        assert_eq!(buffer.capacity(), 2);
+        assert_eq!(buffer.push(5), Some(3));
+
+        // Growing keeps everything
+        assert_eq!(buffer.resize(3), vec![]);
+        assert_eq!(buffer.push(6), None);
+        assert_eq!(buffer.iter().copied().collect::<Vec<_>>(), vec![4, 5, 6]);
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut buffer = DynDoubleEndedLagBuffer::<MyState>::with_capacity(MyState::new(), 4);
+
+        for id in [1, 2, 4, 5] {
+            buffer.update(insert(id));
+        }
+
+        buffer.resize(2);
+        assert_eq!(buffer.capacity(), 2);
+        assert_eq!(buffer.oldest_reconcilable_key(), Some(2));
+        assert_eq!(
+            buffer.update(insert(3)),
+            UpdateOutcome::Swapped { replayed: 2 }
+        );
+        assert_eq!(buffer.state_ref().data, vec![10, 20, 30, 40, 50]);
+    }
+
+    #[test]
+    fn test_horizon_retention() {
+        let mut buffer = DoubleEndedLagBuffer::<MyState, 8>::new(MyState::new()).with_horizon(10);
+
+        // A burst of events is retained as long as it is within the horizon.
+        for id in [1, 2, 3, 4, 5, 6] {
+            buffer.update(insert(id));
+        }
+        assert_eq!(buffer.len(), 6);
+        assert_eq!(buffer.oldest_reconcilable_key(), None);
+
+        // Events more than 10 behind the head are folded into the tail.
+        buffer.update(insert(14));
+        assert_eq!(buffer.len(), 4);
+        assert_eq!(buffer.oldest_reconcilable_key(), Some(3));
+
+        assert_eq!(buffer.update(insert(2)), UpdateOutcome::RejectedTooLate);
+        assert_eq!(
+            buffer.update(insert(7)),
+            UpdateOutcome::Reordered { replayed: 5 }
+        );
+        assert_eq!(
+            buffer.state_ref().data,
+            vec![10, 20, 30, 40, 50, 60, 70, 140]
+        );
+
+        // The capacity bounds bursts that haven't expired yet.
+        let mut buffer = DoubleEndedLagBuffer::<MyState, 4>::new(MyState::new()).with_horizon(10);
+        for id in [1, 2, 3, 4, 5, 6] {
+            buffer.update(insert(id));
+        }
+        assert_eq!(buffer.len(), 4);
+        assert_eq!(buffer.capacity(), 4);
+        assert_eq!(buffer.oldest_reconcilable_key(), Some(2));
+    }
+
+    #[test]
+    fn test_advance_watermark() {
+        let committed = Arc::new(Mutex::new(Vec::new()));
+        let mut buffer = DoubleEndedLagBuffer::<MyState, 4>::new(MyState::new()).with_on_commit({
+            let committed = Arc::clone(&committed);
+            move |e: &MyEvent| committed.lock().unwrap().push(e.id)
+        });
+
+        for id in [1, 2, 4, 5] {
+            buffer.update(insert(id));
+        }
+        buffer.advance_watermark(3);
+        assert_eq!(*committed.lock().unwrap(), vec![1, 2]);
+        assert_eq!(buffer.committed_state().data, vec![10, 20]);
+        assert_eq!(buffer.oldest_reconcilable_key(), Some(3));
+        assert_eq!(buffer.update(insert(2)), UpdateOutcome::RejectedTooLate);
+
+        // Events pushed out of the full buffer are committed as well.
+        for id in [6, 7, 8] {
+            buffer.update(insert(id));
+        }
+        assert_eq!(*committed.lock().unwrap(), vec![1, 2, 4]);
+        assert_eq!(buffer.committed_state().data, vec![10, 20, 40]);
+        assert_eq!(buffer.state_ref().data, vec![10, 20, 40, 50, 60, 70, 80]);
+    }
+
+    #[test]
+    fn test_advance_watermark_reject_duplicates() {
+        let mut buffer = DoubleEndedLagBuffer::<MyState, 4>::new(MyState::new())
+            .with_tie_break(TieBreak::RejectDuplicates);
+
+        for id in [1, 2, 4] {
+            buffer.update(insert(id));
+        }
+
+        // No event with the watermark's key was folded, so it is not a duplicate
+        buffer.advance_watermark(3);
+        assert_eq!(buffer.update_batch([insert(3)]).accepted, 1);
+        assert_eq!(buffer.state_ref().data, vec![10, 20, 30, 40]);
+
+        buffer.advance_watermark(4);
+        assert_eq!(buffer.update(insert(4)), UpdateOutcome::Duplicate);
+    }
+
+    #[test]
+    fn test_observer() {
+        let mut buffer = DoubleEndedLagBuffer::<MyState, 4>::new(MyState::new())
+            .with_observer(Recorder::default());
+
+        for id in [1, 2, 4, 3, 5, 0] {
+            buffer.update(insert(id));
+        }
+
+        let observer = buffer.observer();
+        assert_eq!(observer.applied, vec![1, 2, 4, 3, 5]);
+        assert_eq!(observer.rollbacks, vec![(3, 4)]);
+        assert_eq!(observer.swaps, 1);
+        assert_eq!(observer.dropped, vec![0]);
+
+        // Every other path that moves the base is a swap as well.
+        buffer.advance_watermark(2);
+        buffer.advance_watermark(2);
+        assert_eq!(buffer.observer().swaps, 2);
+        buffer.resize(2);
+        assert_eq!(buffer.observer().swaps, 3);
+        buffer.clear();
+        buffer.clear();
+        assert_eq!(buffer.observer().swaps, 4);
+    }
 }
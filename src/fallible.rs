@@ -0,0 +1,304 @@
+use std::collections::BTreeMap;
+
+use crate::{Event, ReversibleState, State, TryReversibleState, TryState};
+
+/// Adapts a [`TryState`] to the [`State`] trait, so it can be used with any lag buffer.
+///
+/// Every event is applied to a copy of the state first, so a failed event leaves the state
+/// untouched. The error is recorded against the event's order key. Buffers replay events from
+/// an older state when a late event arrives, which evaluates failed events again: an event that
+/// failed because an earlier event was still missing succeeds after the replay and its failure
+/// is cleared.
+///
+/// Failures are tracked per order key, so events should have unique keys. Once the buffer folds
+/// events into its base, the failures of events older than the last folded one are dropped
+/// through [`State::forget`].
+///
+/// # Type Parameters
+///
+/// - `S`: The wrapped state, which must implement the [`TryState`](trait.TryState.html) trait.
+/// - `OrderKey`: The type of the event's order key. Defaults to `usize`.
+///
+/// # Examples
+///
+/// ```rust
+/// use lagbuffer::{DoubleBufferedLagBuffer, Event, Fallible, TryState};
+///
+/// #[derive(Clone, Debug, PartialEq)]
+/// struct Balance(u32);
+///
+/// #[derive(Clone)]
+/// struct Transfer {
+///     id: usize,
+///     amount: i64,
+/// }
+///
+/// impl Event<usize> for Transfer {
+///     fn get_order_key(&self) -> usize {
+///         self.id
+///     }
+/// }
+///
+/// impl TryState<usize> for Balance {
+///     type Event = Transfer;
+///     type Error = &'static str;
+///
+///     fn try_apply(&mut self, event: &Transfer) -> Result<(), Self::Error> {
+///         let balance = self.0 as i64 + event.amount;
+///         self.0 = u32::try_from(balance).map_err(|_| "insufficient funds")?;
+///         Ok(())
+///     }
+/// }
+///
+/// let mut buffer = DoubleBufferedLagBuffer::<Fallible<Balance>, 8>::new(Fallible::new(Balance(0)));
+///
+/// buffer.update(Transfer { id: 2, amount: -5 });
+/// assert_eq!(buffer.state_ref().failure(&2), Some(&"insufficient funds"));
+///
+/// // The deposit arrives late, the withdrawal is evaluated again and succeeds.
+/// buffer.update(Transfer { id: 1, amount: 10 });
+/// assert_eq!(buffer.state_ref().failure(&2), None);
+/// assert_eq!(*buffer.state_ref().state(), Balance(5));
+/// ```
+pub struct Fallible<S, OrderKey = usize>
+where
+    S: TryState<OrderKey>,
+    OrderKey: Ord,
+{
+    state: S,
+    failures: BTreeMap<OrderKey, S::Error>,
+}
+
+impl<S, OrderKey> Fallible<S, OrderKey>
+where
+    S: TryState<OrderKey>,
+    OrderKey: Ord,
+{
+    /// Wraps the given state without any failures.
+    pub fn new(state: S) -> Self {
+        Self {
+            state,
+            failures: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the wrapped state, which contains every event that was applied successfully.
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    /// Consumes the `Fallible` and returns the wrapped state.
+    pub fn into_inner(self) -> S {
+        self.state
+    }
+
+    /// Returns the error of the event with the given key, if it failed.
+    pub fn failure(&self, key: &OrderKey) -> Option<&S::Error> {
+        self.failures.get(key)
+    }
+
+    /// Returns an iterator over the keys and errors of all failed events, in key order.
+    pub fn failures(&self) -> impl Iterator<Item = (&OrderKey, &S::Error)> {
+        self.failures.iter()
+    }
+
+    /// Returns `true` if no event failed.
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+impl<S, OrderKey> Clone for Fallible<S, OrderKey>
+where
+    S: TryState<OrderKey>,
+    OrderKey: Ord + Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+            failures: self.failures.clone(),
+        }
+    }
+}
+
+impl<S, OrderKey> State<OrderKey> for Fallible<S, OrderKey>
+where
+    S: TryState<OrderKey>,
+    OrderKey: Ord + Clone,
+{
+    type Event = S::Event;
+
+    fn apply(&mut self, event: &Self::Event) {
+        let key = event.get_order_key();
+        let mut state = self.state.clone();
+        match state.try_apply(event) {
+            Ok(()) => {
+                self.state = state;
+                self.failures.remove(&key);
+            }
+            Err(error) => {
+                self.failures.insert(key, error);
+            }
+        }
+    }
+
+    fn forget(&mut self, is_folded: &dyn Fn(&OrderKey) -> bool) {
+        self.failures.retain(|key, _| !is_folded(key));
+    }
+}
+
+impl<S, OrderKey> ReversibleState<OrderKey> for Fallible<S, OrderKey>
+where
+    S: TryReversibleState<OrderKey>,
+    OrderKey: Ord + Clone,
+{
+    fn unapply(&mut self, event: &Self::Event) {
+        // A failed event never changed the state, so only its failure is reverted
+        if self.failures.remove(&event.get_order_key()).is_none() {
+            self.state.unapply(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{insert_value, replace, Action, MyEvent};
+    use crate::{DoubleBufferedLagBuffer, DoubleEndedLagBuffer, ReversibleLagBuffer, WrappingSeq};
+    // Example TryState implementation for testing.
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct MyState {
+        pub data: Vec<i32>,
+    }
+
+    impl MyState {
+        pub fn new() -> Self {
+            Self { data: Vec::new() }
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    enum MyError {
+        MissingTarget(i32),
+    }
+
+    impl<K: Ord + Clone> TryState<K> for MyState {
+        type Event = MyEvent<K>;
+        type Error = MyError;
+
+        fn try_apply(&mut self, event: &Self::Event) -> Result<(), MyError> {
+            match event.action {
+                Action::Insert => self.data.push(event.value),
+                Action::Replace => {
+                    // Partially applied before failing, `Fallible` has to discard it
+                    self.data.push(event.value);
+                    let pos = self
+                        .data
+                        .iter()
+                        .position(|&x| x == event.target)
+                        .ok_or(MyError::MissingTarget(event.target))?;
+                    self.data.pop();
+                    self.data[pos] = event.value;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl<K: Ord + Clone> TryReversibleState<K> for MyState {
+        fn unapply(&mut self, event: &Self::Event) {
+            match event.action {
+                Action::Insert => {
+                    self.data.pop();
+                }
+                Action::Replace => {
+                    if let Some(pos) = self.data.iter().position(|&x| x == event.value) {
+                        self.data[pos] = event.target;
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_failure_is_reevaluated() {
+        let mut buffer =
+            DoubleBufferedLagBuffer::<Fallible<MyState>, 8>::new(Fallible::new(MyState::new()));
+
+        buffer.update(insert_value(10, 10));
+        buffer.update(replace(30, 20, 25));
+        assert_eq!(buffer.state_ref().state().data, vec![10]);
+        assert_eq!(
+            buffer.state_ref().failure(&30),
+            Some(&MyError::MissingTarget(20))
+        );
+
+        // The target arrives late, the replay applies the replace.
+        buffer.update(insert_value(20, 20));
+        assert_eq!(buffer.state_ref().state().data, vec![10, 25]);
+        assert!(buffer.state_ref().is_ok());
+
+        // An earlier replace arrives late and takes the target away again.
+        buffer.update(replace(25, 20, 22));
+        assert_eq!(buffer.state_ref().state().data, vec![10, 22]);
+        assert_eq!(
+            buffer.state_ref().failures().collect::<Vec<_>>(),
+            vec![(&30, &MyError::MissingTarget(20))]
+        );
+    }
+
+    #[test]
+    fn test_folded_failures_are_forgotten() {
+        let mut buffer =
+            DoubleEndedLagBuffer::<Fallible<MyState>, 2>::new(Fallible::new(MyState::new()));
+
+        buffer.update(replace(1, 20, 25));
+        buffer.update(insert_value(2, 20));
+        buffer.update(insert_value(3, 30));
+        // 1 is the last folded event, its failure is kept
+        assert!(buffer.state_ref().failure(&1).is_some());
+
+        buffer.update(insert_value(5, 50));
+        buffer.update(insert_value(4, 40));
+        // The replay starts from the tail, which has forgotten the failure as well
+        assert!(buffer.state_ref().is_ok());
+        assert_eq!(buffer.state_ref().state().data, vec![20, 30, 40, 50]);
+    }
+
+    #[test]
+    fn test_reversible() {
+        let mut buffer =
+            ReversibleLagBuffer::<Fallible<MyState>, 8>::new(Fallible::new(MyState::new()));
+
+        buffer.update(insert_value(1, 10));
+        buffer.update(replace(3, 20, 25));
+        buffer.update(insert_value(4, 40));
+        assert_eq!(buffer.state_ref().state().data, vec![10, 40]);
+
+        buffer.update(insert_value(2, 20));
+        assert_eq!(buffer.state_ref().state().data, vec![10, 25, 40]);
+        assert!(buffer.state_ref().is_ok());
+    }
+
+    #[test]
+    fn test_failures_across_wrap() {
+        let mut buffer = DoubleBufferedLagBuffer::<Fallible<MyState, WrappingSeq<u16>>, 4, _>::new(
+            Fallible::new(MyState::new()),
+        )
+        .with_relative_keys();
+
+        for seq in 65530..=u16::MAX {
+            buffer.update(insert_value(WrappingSeq(seq), seq as i32));
+        }
+        buffer.update(replace(WrappingSeq(0), 20, 25));
+        buffer.update(insert_value(WrappingSeq(1), 1));
+
+        // The buffer folded up to 65534, 0 can still be reconciled and keeps its failure
+        assert_eq!(buffer.oldest_reconcilable_key(), Some(WrappingSeq(65534)));
+        assert_eq!(
+            buffer.state_ref().failures().collect::<Vec<_>>(),
+            vec![(&WrappingSeq(0), &MyError::MissingTarget(20))]
+        );
+    }
+}
@@ -0,0 +1,367 @@
+use std::cmp::Ordering;
+use std::ops::{Bound, RangeBounds};
+use std::time::{Duration, Instant};
+
+/// An order key that can measure how far it is ahead of another key.
+///
+/// Used by time-horizon retention, where events are kept until their key falls more than a
+/// given distance behind the newest key.
+/// Also used to measure the lateness of out-of-order events in the [`LagBufferStats`](crate::LagBufferStats).
+pub trait KeyDistance: Ord {
+    /// The type of the distance between two keys, e.g. a number of ticks or a [`Duration`].
+    type Distance: Ord;
+
+    /// Returns how far this key is ahead of `earlier`, or the zero distance if it isn't.
+    fn distance_from(&self, earlier: &Self) -> Self::Distance;
+
+    /// Returns [`distance_from`](Self::distance_from) as a number, for metrics.
+    ///
+    /// Integer keys are measured in steps, time keys in seconds.
+    fn distance_units(&self, earlier: &Self) -> f64;
+}
+
+/// An order key whose order only holds relative to a nearby key, e.g. a sequence number that wraps
+/// around.
+///
+/// `Ord` has to be a total order, so such keys implement it by their raw value, and a buffer built
+/// `with_relative_keys` compares its keys with [`cmp_relative`](Self::cmp_relative) instead. The
+/// anchor is the last key the buffer folded into its base state, or its oldest buffered key
+/// before anything was folded. Every key the buffer still reconciles lies ahead of it.
+///
+/// Buffers only compare relatively in their own bookkeeping and through
+/// [`BaseLagBuffer::compare_keys`](crate::BaseLagBuffer::compare_keys), which the
+/// [`PredictionBuffer`](crate::PredictionBuffer) uses. Adapters like the
+/// [`MultiSourceLagBuffer`](crate::MultiSourceLagBuffer) or the stream wrappers compare keys with
+/// `Ord`.
+pub trait RelativeOrd: Ord {
+    /// Compares `self` with `other` as seen from `anchor`.
+    ///
+    /// For a fixed anchor, this has to be a total order on the keys that lie within the range the
+    /// buffer retains ahead of it.
+    fn cmp_relative(&self, other: &Self, anchor: &Self) -> Ordering;
+}
+
+// Compares two keys relative to an anchor, see `RelativeOrd`.
+pub(crate) type RelativeCmp<K> = fn(&K, &K, &K) -> Ordering;
+
+// How a buffer orders keys during one operation: with `Ord`, or with `RelativeOrd` anchored at
+// the oldest key the buffer compares against. Without an anchor, the first key compared is used.
+pub(crate) struct KeyOrder<K> {
+    relative: Option<(RelativeCmp<K>, Option<K>)>,
+}
+
+impl<K> Default for KeyOrder<K> {
+    fn default() -> Self {
+        Self { relative: None }
+    }
+}
+
+impl<K: Ord> KeyOrder<K> {
+    pub(crate) fn new(
+        relative: Option<RelativeCmp<K>>,
+        anchor: impl FnOnce() -> Option<K>,
+    ) -> Self {
+        Self {
+            relative: relative.map(|relative| (relative, anchor())),
+        }
+    }
+
+    // Anchors the order at `anchor` if the buffer had no key to anchor it at
+    pub(crate) fn or_anchor(mut self, anchor: impl FnOnce() -> Option<K>) -> Self {
+        if let Some((_, slot @ None)) = &mut self.relative {
+            *slot = anchor();
+        }
+        self
+    }
+
+    pub(crate) fn cmp(&self, a: &K, b: &K) -> Ordering {
+        match &self.relative {
+            Some((relative, Some(anchor))) => relative(a, b, anchor),
+            Some((relative, None)) => relative(a, b, a),
+            None => a.cmp(b),
+        }
+    }
+
+    pub(crate) fn lt(&self, a: &K, b: &K) -> bool {
+        self.cmp(a, b).is_lt()
+    }
+
+    pub(crate) fn le(&self, a: &K, b: &K) -> bool {
+        self.cmp(a, b).is_le()
+    }
+
+    // Returns the newer of two optional keys, `None` counts as the oldest
+    pub(crate) fn max(&self, a: Option<K>, b: Option<K>) -> Option<K> {
+        match (a, b) {
+            (Some(a), Some(b)) if self.lt(&a, &b) => Some(b),
+            (Some(a), _) => Some(a),
+            (None, b) => b,
+        }
+    }
+
+    pub(crate) fn contains(&self, range: &impl RangeBounds<K>, key: &K) -> bool {
+        let after_start = match range.start_bound() {
+            Bound::Included(start) => self.le(start, key),
+            Bound::Excluded(start) => self.lt(start, key),
+            Bound::Unbounded => true,
+        };
+        let before_end = match range.end_bound() {
+            Bound::Included(end) => self.le(key, end),
+            Bound::Excluded(end) => self.lt(key, end),
+            Bound::Unbounded => true,
+        };
+        after_start && before_end
+    }
+}
+
+macro_rules! impl_key_distance_unsigned {
+    ($($t:ty),*) => {
+        $(
+            impl KeyDistance for $t {
+                type Distance = $t;
+
+                fn distance_from(&self, earlier: &Self) -> Self::Distance {
+                    self.saturating_sub(*earlier)
+                }
+
+                fn distance_units(&self, earlier: &Self) -> f64 {
+                    self.distance_from(earlier) as f64
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! impl_key_distance_signed {
+    ($($t:ty => $d:ty),*) => {
+        $(
+            impl KeyDistance for $t {
+                type Distance = $d;
+
+                fn distance_from(&self, earlier: &Self) -> Self::Distance {
+                    if self > earlier {
+                        self.abs_diff(*earlier)
+                    } else {
+                        0
+                    }
+                }
+
+                fn distance_units(&self, earlier: &Self) -> f64 {
+                    self.distance_from(earlier) as f64
+                }
+            }
+        )*
+    };
+}
+
+impl_key_distance_unsigned!(u8, u16, u32, u64, u128, usize);
+impl_key_distance_signed!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128, isize => usize);
+
+impl KeyDistance for Duration {
+    type Distance = Duration;
+
+    fn distance_from(&self, earlier: &Self) -> Self::Distance {
+        self.saturating_sub(*earlier)
+    }
+
+    fn distance_units(&self, earlier: &Self) -> f64 {
+        self.distance_from(earlier).as_secs_f64()
+    }
+}
+
+impl KeyDistance for Instant {
+    type Distance = Duration;
+
+    fn distance_from(&self, earlier: &Self) -> Self::Distance {
+        self.saturating_duration_since(*earlier)
+    }
+
+    fn distance_units(&self, earlier: &Self) -> f64 {
+        self.distance_from(earlier).as_secs_f64()
+    }
+}
+
+/// A sequence number that wraps around, ordered by serial number arithmetic (RFC 1982).
+///
+/// Wire protocols often number their messages with a `u16` or `u32` that wraps back to 0. As a
+/// plain integer order key, every event after the wrap looks older than the events before it.
+/// `WrappingSeq` implements [`RelativeOrd`], which orders numbers by how far they lie ahead of an
+/// anchor, so as seen from 65520, `WrappingSeq(2u16)` is newer than `WrappingSeq(65530u16)`.
+/// Build the buffer `with_relative_keys` to use that order.
+///
+/// The relative order only holds for numbers less than half the range ahead of the anchor. A lag
+/// buffer anchors it at its base key, so it has to retain noticeably fewer than
+/// [`HALF_RANGE`](WrappingSeq::<u16>::HALF_RANGE) events, and events that are more than half the
+/// range behind the base look like new ones. `Ord` compares the raw numbers, so it is total, but
+/// doesn't follow the wrap.
+///
+/// # Examples
+///
+/// ```rust
+/// use std::cmp::Ordering;
+///
+/// use lagbuffer::{RelativeOrd, WrappingSeq};
+///
+/// let anchor = WrappingSeq(65520u16);
+/// assert_eq!(
+///     WrappingSeq(2u16).cmp_relative(&WrappingSeq(65530), &anchor),
+///     Ordering::Greater
+/// );
+/// assert!(WrappingSeq(2u16) < WrappingSeq(65530));
+/// assert_eq!(WrappingSeq(u16::MAX).next(), WrappingSeq(0));
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct WrappingSeq<T>(pub T);
+
+macro_rules! impl_wrapping_seq {
+    ($($t:ty => $signed:ty),*) => {
+        $(
+            impl WrappingSeq<$t> {
+                /// Half the range of the sequence numbers. Numbers less than this far apart are
+                /// ordered correctly.
+                pub const HALF_RANGE: $t = 1 << (<$t>::BITS - 1);
+
+                /// Returns the next sequence number, wrapping to 0 after the maximum.
+                pub fn next(self) -> Self {
+                    self.wrapping_add(1)
+                }
+
+                /// Returns the sequence number `steps` ahead, wrapping around.
+                pub fn wrapping_add(self, steps: $t) -> Self {
+                    Self(self.0.wrapping_add(steps))
+                }
+            }
+
+            impl RelativeOrd for WrappingSeq<$t> {
+                fn cmp_relative(&self, other: &Self, anchor: &Self) -> Ordering {
+                    // Numbers up to half the range behind the anchor are older than it
+                    let offset = |seq: &Self| seq.0.wrapping_sub(anchor.0) as $signed;
+                    offset(self).cmp(&offset(other))
+                }
+            }
+
+            impl From<$t> for WrappingSeq<$t> {
+                fn from(value: $t) -> Self {
+                    Self(value)
+                }
+            }
+
+            impl KeyDistance for WrappingSeq<$t> {
+                type Distance = $t;
+
+                fn distance_from(&self, earlier: &Self) -> Self::Distance {
+                    match self.0.wrapping_sub(earlier.0) {
+                        ahead if ahead < Self::HALF_RANGE => ahead,
+                        _ => 0,
+                    }
+                }
+
+                fn distance_units(&self, earlier: &Self) -> f64 {
+                    self.distance_from(earlier) as f64
+                }
+            }
+        )*
+    };
+}
+
+impl_wrapping_seq!(u16 => i16, u32 => i32);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{insert_value, MyEvent, MyState};
+    use crate::{
+        CheckpointedLagBuffer, DoubleBufferedLagBuffer, DoubleEndedLagBuffer, LagBufferStateRef,
+        ManualLagBuffer, UpdateOutcome,
+    };
+
+    fn event(seq: u16) -> MyEvent<WrappingSeq<u16>> {
+        insert_value(WrappingSeq(seq), seq as i32)
+    }
+
+    #[test]
+    fn test_distance() {
+        assert_eq!(10u16.distance_from(&4), 6);
+        assert_eq!(4u16.distance_from(&10), 0);
+        assert_eq!(3i32.distance_from(&-4), 7);
+        assert_eq!((-4i32).distance_from(&3), 0);
+        assert_eq!(
+            Duration::from_millis(700).distance_from(&Duration::from_millis(200)),
+            Duration::from_millis(500)
+        );
+        assert_eq!(3i32.distance_units(&-4), 7.0);
+        assert_eq!(
+            Duration::from_millis(700).distance_units(&Duration::from_millis(200)),
+            0.5
+        );
+    }
+
+    #[test]
+    fn test_wrapping_seq() {
+        let cmp = |a: u16, b: u16, anchor: u16| {
+            WrappingSeq(a).cmp_relative(&WrappingSeq(b), &WrappingSeq(anchor))
+        };
+        assert_eq!(cmp(2, 65530, 65500), Ordering::Greater);
+        assert_eq!(cmp(65530, 2, 65500), Ordering::Less);
+        assert_eq!(cmp(100, 200, 50), Ordering::Less);
+        assert_eq!(
+            WrappingSeq(u32::MAX).cmp_relative(&WrappingSeq(0), &WrappingSeq(u32::MAX)),
+            Ordering::Less
+        );
+        assert_eq!(WrappingSeq(u16::MAX).next(), WrappingSeq(0));
+
+        // Transitive for a fixed anchor across the wrap
+        assert_eq!(cmp(65000, 10000, 60000), Ordering::Less);
+        assert_eq!(cmp(10000, 25000, 60000), Ordering::Less);
+        assert_eq!(cmp(65000, 25000, 60000), Ordering::Less);
+
+        // Ord is the raw value, so it is total
+        assert!(WrappingSeq(2u16) < WrappingSeq(65530));
+
+        assert_eq!(WrappingSeq(3u16).distance_from(&WrappingSeq(65534)), 5);
+        assert_eq!(WrappingSeq(65534u16).distance_from(&WrappingSeq(3)), 0);
+    }
+
+    #[test]
+    fn test_wrapping_keys_all_strategies() {
+        let buffers: Vec<Box<dyn LagBufferStateRef<MyState, WrappingSeq<u16>>>> = vec![
+            Box::new(
+                DoubleBufferedLagBuffer::<MyState, 8, _>::new(MyState::new()).with_relative_keys(),
+            ),
+            Box::new(
+                DoubleEndedLagBuffer::<MyState, 8, _>::new(MyState::new()).with_relative_keys(),
+            ),
+            Box::new(ManualLagBuffer::<MyState, 8, _>::new(MyState::new()).with_relative_keys()),
+            Box::new(
+                CheckpointedLagBuffer::<MyState, 8, 2, _>::new(MyState::new()).with_relative_keys(),
+            ),
+        ];
+
+        for mut buffer in buffers {
+            for seq in [65533, 65534, 0, 65535, 1] {
+                assert!(buffer.update(event(seq)).is_accepted());
+            }
+            assert_eq!(buffer.state_ref().data, vec![65533, 65534, 65535, 0, 1]);
+            assert_eq!(buffer.head_key(), Some(WrappingSeq(1)));
+
+            // Events from before the wrap are still older than the base key
+            buffer.clear();
+            assert_eq!(buffer.update(event(65535)), UpdateOutcome::RejectedTooLate);
+            assert_eq!(buffer.update(event(2)), UpdateOutcome::AppliedInOrder);
+        }
+    }
+
+    #[test]
+    fn test_wrapping_horizon() {
+        let mut buffer = DoubleEndedLagBuffer::<MyState, 8, _>::new(MyState::new())
+            .with_relative_keys()
+            .with_horizon(2);
+        for seq in [65534, 65535, 0, 1] {
+            buffer.update(event(seq));
+        }
+
+        // 65534 is 3 steps behind the head across the wrap
+        assert_eq!(buffer.oldest_reconcilable_key(), Some(WrappingSeq(65534)));
+        assert_eq!(buffer.len(), 3);
+    }
+}
@@ -0,0 +1,86 @@
+use std::fmt;
+
+use crate::{BaseLagBuffer, State, UpdateOutcome};
+
+/// A callback that is notified about a late event.
+pub type LateCallback<E> = Box<dyn FnMut(&E) + Send>;
+
+/// A callback that returns the authoritative state to reset to after a late event.
+pub type ResetCallback<S, E> = Box<dyn FnMut(&E) -> S + Send>;
+
+/// Decides what a lag buffer does with an event that is older than anything it can still reconcile.
+///
+/// Every buffer folds old events into a base state at some point. An event whose order key is
+/// older than the last folded event can no longer be placed correctly, because the base already
+/// contains newer events. Instead of silently applying it on top of that base, the buffer hands
+/// it to its `LatePolicy`.
+///
+/// The policy defaults to [`LatePolicy::Drop`].
+///
+/// # Type Parameters
+///
+/// - `S`: The type of the state, which must implement the [`State`](trait.State.html) trait.
+/// - `OrderKey`: The type of the event's order key. Defaults to `usize`.
+#[derive(Default)]
+pub enum LatePolicy<S: State<OrderKey>, OrderKey: Ord = usize> {
+    /// Discards the event. `update` reports [`UpdateOutcome::RejectedTooLate`](crate::UpdateOutcome::RejectedTooLate).
+    #[default]
+    Drop,
+    /// Discards the event and returns it as a [`TooLateError`] from `try_update`.
+    /// `update` reports [`UpdateOutcome::RejectedTooLate`](crate::UpdateOutcome::RejectedTooLate).
+    Error,
+    /// Passes the event to the callback, then discards it.
+    Callback(LateCallback<S::Event>),
+    /// Passes the event to the callback and resets the buffer to the authoritative state it returns.
+    /// `update` reports [`UpdateOutcome::Reset`](crate::UpdateOutcome::Reset).
+    Reset(ResetCallback<S, S::Event>),
+}
+
+impl<S: State<OrderKey>, OrderKey: Ord> LatePolicy<S, OrderKey> {
+    /// Applies the policy to a late event.
+    ///
+    /// Returns the state the buffer has to be reset to, if any.
+    pub(crate) fn handle(&mut self, event: S::Event) -> Result<Option<S>, TooLateError<S::Event>> {
+        match self {
+            LatePolicy::Drop => Ok(None),
+            LatePolicy::Error => Err(TooLateError { event }),
+            LatePolicy::Callback(callback) => {
+                callback(&event);
+                Ok(None)
+            }
+            LatePolicy::Reset(callback) => Ok(Some(callback(&event))),
+        }
+    }
+
+    /// Hands a late event to the policy of `buffer` and resets the buffer if the policy asks for
+    /// it. `policy` selects the policy of the buffer.
+    pub(crate) fn reject<B: BaseLagBuffer<S, OrderKey>>(
+        buffer: &mut B,
+        policy: fn(&mut B) -> &mut Self,
+        event: S::Event,
+    ) -> Result<UpdateOutcome, TooLateError<S::Event>> {
+        Ok(match policy(buffer).handle(event)? {
+            Some(state) => {
+                buffer.reset(state);
+                UpdateOutcome::Reset
+            }
+            None => UpdateOutcome::RejectedTooLate,
+        })
+    }
+}
+
+/// The error returned by `try_update` when the buffer uses [`LatePolicy::Error`] and
+/// receives an event it can no longer reconcile.
+#[derive(Clone, Debug)]
+pub struct TooLateError<E> {
+    /// The rejected event.
+    pub event: E,
+}
+
+impl<E> fmt::Display for TooLateError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "event is older than the oldest reconcilable order key")
+    }
+}
+
+impl<E: fmt::Debug> std::error::Error for TooLateError<E> {}
@@ -1,12 +1,66 @@
+use std::cmp::Ordering;
+
+use batch::BatchBuffer;
+
+#[macro_use]
+mod trace;
+
 mod double_buffered;
-pub use double_buffered::DoubleBufferedLagBuffer;
+pub use double_buffered::{DoubleBufferedLagBuffer, DynDoubleBufferedLagBuffer};
 
 mod double_ended;
-pub use double_ended::DoubleEndedLagBuffer;
+pub use double_ended::{DoubleEndedLagBuffer, DynDoubleEndedLagBuffer};
+
+mod checkpointed;
+pub use checkpointed::CheckpointedLagBuffer;
+
+mod reversible;
+pub use reversible::ReversibleLagBuffer;
 
 mod manual;
 pub use manual::ManualLagBuffer;
 
+mod prediction;
+pub use prediction::{Confirmation, PredictionBuffer};
+
+mod rollback;
+pub use rollback::{FrameAdvance, FrameInput, InputPredictor, PredictCallback, RollbackSession};
+
+mod multi_source;
+pub use multi_source::MultiSourceLagBuffer;
+
+mod shared;
+pub use shared::SharedLagBuffer;
+
+#[cfg(feature = "async")]
+mod stream;
+#[cfg(feature = "async")]
+pub use stream::{HeadWatch, OutcomeStream, StateStream, WaitForKey};
+
+mod batch;
+pub use batch::BatchOutcome;
+
+mod fallible;
+pub use fallible::Fallible;
+
+mod key;
+pub use key::{KeyDistance, RelativeOrd, WrappingSeq};
+
+mod observer;
+pub use observer::LagBufferObserver;
+
+mod stats;
+pub use stats::{LagBufferStats, REPLAY_BUCKETS};
+
+mod late;
+pub use late::{LateCallback, LatePolicy, ResetCallback, TooLateError};
+
+mod tie_break;
+pub use tie_break::{TieBreak, TieBreakFn};
+
+#[cfg(test)]
+mod testing;
+
 /// A trait representing an event that has an associated order key of type `OrderKey`.
 ///
 /// Events modify the state, and the order in which they are applied is determined by the `OrderKey`.
@@ -34,42 +88,699 @@ pub trait State<OrderKey: Ord>: Clone {
     /// # Arguments
     /// - `event`: The event that will be applied to the state.
     fn apply(&mut self, event: &Self::Event);
+
+    /// Drops whatever the state tracks for single events that can't be replayed anymore.
+    ///
+    /// Buffers call this on every state they keep once their base has moved. The keys are
+    /// compared in the buffer's key order, which isn't `Ord` for a buffer built
+    /// `with_relative_keys`. The default does nothing, [`Fallible`] drops the failures of those
+    /// events.
+    ///
+    /// # Arguments
+    /// - `is_folded`: Returns `true` for the order keys that are older than the last event folded
+    ///   into the base state.
+    fn forget(&mut self, _is_folded: &dyn Fn(&OrderKey) -> bool) {}
+}
+
+/// A state whose events can be reverted.
+///
+/// Buffers built on `ReversibleState` reconcile late events by rolling the current state back
+/// to the insertion point instead of cloning a base state and replaying everything after it.
+///
+/// # Type Parameters
+/// - `OrderKey`: The type that determines the order of events, which must implement `Ord`.
+pub trait ReversibleState<OrderKey: Ord>: State<OrderKey> {
+    /// Reverts an event, restoring the state from before it was applied.
+    ///
+    /// Events are always reverted newest first, so `event` is the last event applied to the state.
+    ///
+    /// # Arguments
+    /// - `event`: The event that will be reverted.
+    fn unapply(&mut self, event: &Self::Event);
+}
+
+/// A state whose events can fail to apply.
+///
+/// Buffers only work with [`State`], so a `TryState` is wrapped in a [`Fallible`] first. A failed
+/// event leaves the state untouched and its error is recorded against the event's order key.
+/// Since buffers replay events when earlier ones arrive late, a failed event is evaluated again
+/// on every replay and succeeds once the state it depends on is there.
+///
+/// # Type Parameters
+/// - `OrderKey`: The type that determines the order of events, which must implement `Ord`.
+pub trait TryState<OrderKey: Ord>: Clone {
+    /// The type of event that modifies the state.
+    type Event: Clone + Event<OrderKey>;
+
+    /// The reason an event could not be applied.
+    type Error: Clone;
+
+    /// Applies an event to the current state, or fails if the event is invalid against it.
+    ///
+    /// The state may be left in any condition when an error is returned, [`Fallible`] restores
+    /// it from a copy.
+    ///
+    /// # Arguments
+    /// - `event`: The event that will be applied to the state.
+    fn try_apply(&mut self, event: &Self::Event) -> Result<(), Self::Error>;
+}
+
+/// A [`TryState`] whose events can be reverted, for use with the
+/// [`ReversibleLagBuffer`].
+///
+/// # Type Parameters
+/// - `OrderKey`: The type that determines the order of events, which must implement `Ord`.
+pub trait TryReversibleState<OrderKey: Ord>: TryState<OrderKey> {
+    /// Reverts an event, restoring the state from before it was applied.
+    ///
+    /// Only events that were applied successfully are reverted, newest first.
+    ///
+    /// # Arguments
+    /// - `event`: The event that will be reverted.
+    fn unapply(&mut self, event: &Self::Event);
+}
+
+/// The result of feeding an event into a lag buffer.
+///
+/// Every `update` call reports what the buffer did with the event, so callers can drive
+/// metrics, resend logic or corrections from it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpdateOutcome {
+    /// The event was not older than any buffered event and was applied directly to the current state.
+    AppliedInOrder,
+    /// The event arrived out of order and the state was reconstructed by replaying `replayed` events.
+    Reordered { replayed: usize },
+    /// The event was accepted and caused the buffer to retire its oldest events.
+    /// `replayed` is the number of events replayed while applying it (0 if it arrived in order).
+    Swapped { replayed: usize },
+    /// The event was older than anything the buffer can still reconcile and was discarded.
+    RejectedTooLate,
+    /// The event was older than anything the buffer can still reconcile and the buffer was
+    /// reset to an authoritative state by its [`LatePolicy`].
+    Reset,
+    /// The event duplicates one that is already buffered and was discarded.
+    Duplicate,
+}
+
+impl UpdateOutcome {
+    /// Returns `true` if the event became part of the state.
+    pub fn is_accepted(&self) -> bool {
+        !matches!(self, Self::RejectedTooLate | Self::Reset | Self::Duplicate)
+    }
+
+    /// Returns the number of events that had to be replayed to apply the event.
+    pub fn replayed(&self) -> usize {
+        match self {
+            Self::Reordered { replayed } | Self::Swapped { replayed } => *replayed,
+            _ => 0,
+        }
+    }
 }
 
+/// The result of amending a buffered event.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AmendOutcome<E> {
+    /// The buffered event with the same order key was replaced and the state was reconstructed by
+    /// replaying `replayed` events.
+    Replaced { event: E, replayed: usize },
+    /// No buffered event had the key, so the event was handled like in `update`.
+    Updated(UpdateOutcome),
+}
+
+impl<E> AmendOutcome<E> {
+    /// Returns the replaced event, or `None` if the event was handled like in `update`.
+    pub fn replaced(self) -> Option<E> {
+        match self {
+            Self::Replaced { event, .. } => Some(event),
+            Self::Updated(_) => None,
+        }
+    }
+}
+
+/// A callback that receives events once they are committed, i.e. can no longer be reconciled.
+pub type CommitCallback<E> = Box<dyn FnMut(&E) + Send>;
+
+/// The common interface of all lag buffers.
+///
+/// Every buffer strategy implements this trait, so the strategy can be swapped without
+/// touching the code that feeds it.
 pub trait BaseLagBuffer<S: State<O>, O: Ord = usize> {
-    fn update(&mut self, event: S::Event);
+    /// Updates the buffer with a new event and reports how it was handled.
+    fn update(&mut self, event: S::Event) -> UpdateOutcome;
+
+    /// Returns the number of buffered events that can still be reconciled.
+    fn len(&self) -> usize;
+
+    /// Returns `true` if no events are buffered.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of events the buffer retains before it folds them into its base state.
+    fn capacity(&self) -> usize;
+
+    /// Returns the order key of the newest buffered event.
+    fn head_key(&self) -> Option<O>;
+
+    /// Returns the order key of the oldest buffered event.
+    fn oldest_key(&self) -> Option<O> {
+        self.events().next().map(S::Event::get_order_key)
+    }
+
+    /// Returns an iterator over the buffered events in key order.
+    fn events(&self) -> Box<dyn Iterator<Item = &S::Event> + '_>;
+
+    /// Returns the order key of the last event folded into the base state.
+    ///
+    /// This is the oldest key that can still be reconciled. Returns `None` while no events
+    /// have been folded.
+    fn base_key(&self) -> Option<O>;
+
+    /// Compares two order keys in the order the buffer applies their events.
+    ///
+    /// That is `Ord`, unless the buffer was built `with_relative_keys`, which compares them with
+    /// [`RelativeOrd::cmp_relative`] as seen from the [`base_key`](Self::base_key).
+    fn compare_keys(&self, a: &O, b: &O) -> Ordering {
+        a.cmp(b)
+    }
+
+    /// Removes a buffered event and reconstructs the current state as though it never happened.
+    ///
+    /// Returns `None` if no buffered event has the given key.
+    fn retract(&mut self, key: &O) -> Option<S::Event>;
+
+    /// Replaces the buffered event with the same order key and reconstructs the current state.
+    ///
+    /// If no buffered event has the key, the event is handled like in [`update`](Self::update)
+    /// and its outcome is reported. An event that is too late goes to the buffer's
+    /// [`LatePolicy`], which returns it as a [`TooLateError`] with [`LatePolicy::Error`].
+    fn amend(&mut self, event: S::Event) -> Result<AmendOutcome<S::Event>, TooLateError<S::Event>>;
+
+    /// Folds all buffered events into the base state, keeping the current state.
+    fn clear(&mut self);
+
+    /// Discards all buffered events and restarts from the given state.
+    fn reset(&mut self, state: S);
+
+    /// Returns the reconciliation metrics of the buffer.
+    fn stats(&self) -> &LagBufferStats;
+
+    /// Resets the reconciliation metrics.
+    fn reset_stats(&mut self);
 }
 
+/// A lag buffer that can hand out a copy of its current state.
 pub trait LagBufferState<S: State<O>, O: Ord = usize>: BaseLagBuffer<S, O> {
+    /// Returns a clone of the current state.
     fn state(&self) -> S;
+
+    /// Reconstructs the state as of the given order key without touching the current state.
+    ///
+    /// Returns `None` if the key is older than the [`base_key`](BaseLagBuffer::base_key).
+    fn state_at(&self, key: &O) -> Option<S>;
 }
+
+/// A lag buffer that can hand out a reference to its current state.
 pub trait LagBufferStateRef<S: State<O>, O: Ord = usize>: BaseLagBuffer<S, O> {
+    /// Returns a reference to the current state.
     fn state_ref(&self) -> &S;
 }
 
-impl<S: State<OrderKey>, const SIZE: usize, OrderKey: Ord> BaseLagBuffer<S, OrderKey>
-    for DoubleBufferedLagBuffer<S, SIZE, OrderKey>
+impl<
+        S: State<OrderKey>,
+        const SIZE: usize,
+        OrderKey: Ord + Clone,
+        Observer: LagBufferObserver<S, OrderKey>,
+    > BaseLagBuffer<S, OrderKey> for DoubleBufferedLagBuffer<S, SIZE, OrderKey, Observer>
 {
-    fn update(&mut self, event: S::Event) {
-        (self as &mut DoubleBufferedLagBuffer<S, SIZE, OrderKey>).update(event);
+    fn update(&mut self, event: S::Event) -> UpdateOutcome {
+        (self as &mut DoubleBufferedLagBuffer<S, SIZE, OrderKey, Observer>).update(event)
+    }
+
+    fn len(&self) -> usize {
+        (self as &DoubleBufferedLagBuffer<S, SIZE, OrderKey, Observer>).len()
+    }
+
+    fn capacity(&self) -> usize {
+        (self as &DoubleBufferedLagBuffer<S, SIZE, OrderKey, Observer>).capacity()
+    }
+
+    fn head_key(&self) -> Option<OrderKey> {
+        (self as &DoubleBufferedLagBuffer<S, SIZE, OrderKey, Observer>).head_key()
+    }
+
+    fn events(&self) -> Box<dyn Iterator<Item = &S::Event> + '_> {
+        Box::new((self as &DoubleBufferedLagBuffer<S, SIZE, OrderKey, Observer>).events())
+    }
+
+    fn base_key(&self) -> Option<OrderKey> {
+        (self as &DoubleBufferedLagBuffer<S, SIZE, OrderKey, Observer>).oldest_reconcilable_key()
+    }
+
+    fn compare_keys(&self, a: &OrderKey, b: &OrderKey) -> Ordering {
+        BatchBuffer::key_order(self).cmp(a, b)
+    }
+
+    fn retract(&mut self, key: &OrderKey) -> Option<S::Event> {
+        (self as &mut DoubleBufferedLagBuffer<S, SIZE, OrderKey, Observer>).retract(key)
+    }
+
+    fn amend(&mut self, event: S::Event) -> Result<AmendOutcome<S::Event>, TooLateError<S::Event>> {
+        (self as &mut DoubleBufferedLagBuffer<S, SIZE, OrderKey, Observer>).amend(event)
+    }
+
+    fn clear(&mut self) {
+        (self as &mut DoubleBufferedLagBuffer<S, SIZE, OrderKey, Observer>).clear()
+    }
+
+    fn reset(&mut self, state: S) {
+        (self as &mut DoubleBufferedLagBuffer<S, SIZE, OrderKey, Observer>).reset(state)
+    }
+
+    fn stats(&self) -> &LagBufferStats {
+        (self as &DoubleBufferedLagBuffer<S, SIZE, OrderKey, Observer>).stats()
+    }
+
+    fn reset_stats(&mut self) {
+        (self as &mut DoubleBufferedLagBuffer<S, SIZE, OrderKey, Observer>).reset_stats()
     }
 }
 
-impl<S: State<OrderKey>, const SIZE: usize, OrderKey: Ord> LagBufferState<S, OrderKey>
-    for DoubleBufferedLagBuffer<S, SIZE, OrderKey>
+impl<
+        S: State<OrderKey>,
+        const SIZE: usize,
+        OrderKey: Ord + Clone,
+        Observer: LagBufferObserver<S, OrderKey>,
+    > LagBufferState<S, OrderKey> for DoubleBufferedLagBuffer<S, SIZE, OrderKey, Observer>
 {
     fn state(&self) -> S {
-        (self as &DoubleBufferedLagBuffer<S, SIZE, OrderKey>)
+        (self as &DoubleBufferedLagBuffer<S, SIZE, OrderKey, Observer>)
             .state_ref()
             .clone()
     }
+
+    fn state_at(&self, key: &OrderKey) -> Option<S> {
+        (self as &DoubleBufferedLagBuffer<S, SIZE, OrderKey, Observer>).state_at(key)
+    }
 }
 
-impl<S: State<OrderKey>, const SIZE: usize, OrderKey: Ord> LagBufferStateRef<S, OrderKey>
-    for DoubleBufferedLagBuffer<S, SIZE, OrderKey>
+impl<
+        S: State<OrderKey>,
+        const SIZE: usize,
+        OrderKey: Ord + Clone,
+        Observer: LagBufferObserver<S, OrderKey>,
+    > LagBufferStateRef<S, OrderKey> for DoubleBufferedLagBuffer<S, SIZE, OrderKey, Observer>
 {
     fn state_ref(&self) -> &S {
-        (self as &DoubleBufferedLagBuffer<S, SIZE, OrderKey>).state_ref()
+        (self as &DoubleBufferedLagBuffer<S, SIZE, OrderKey, Observer>).state_ref()
+    }
+}
+
+impl<
+        S: State<OrderKey>,
+        const SIZE: usize,
+        OrderKey: Ord + Clone,
+        Observer: LagBufferObserver<S, OrderKey>,
+    > BaseLagBuffer<S, OrderKey> for DoubleEndedLagBuffer<S, SIZE, OrderKey, Observer>
+{
+    fn update(&mut self, event: S::Event) -> UpdateOutcome {
+        (self as &mut DoubleEndedLagBuffer<S, SIZE, OrderKey, Observer>).update(event)
+    }
+
+    fn len(&self) -> usize {
+        (self as &DoubleEndedLagBuffer<S, SIZE, OrderKey, Observer>).len()
+    }
+
+    fn capacity(&self) -> usize {
+        (self as &DoubleEndedLagBuffer<S, SIZE, OrderKey, Observer>).capacity()
+    }
+
+    fn head_key(&self) -> Option<OrderKey> {
+        (self as &DoubleEndedLagBuffer<S, SIZE, OrderKey, Observer>).head_key()
+    }
+
+    fn events(&self) -> Box<dyn Iterator<Item = &S::Event> + '_> {
+        Box::new((self as &DoubleEndedLagBuffer<S, SIZE, OrderKey, Observer>).events())
+    }
+
+    fn base_key(&self) -> Option<OrderKey> {
+        (self as &DoubleEndedLagBuffer<S, SIZE, OrderKey, Observer>).oldest_reconcilable_key()
+    }
+
+    fn compare_keys(&self, a: &OrderKey, b: &OrderKey) -> Ordering {
+        BatchBuffer::key_order(self).cmp(a, b)
+    }
+
+    fn retract(&mut self, key: &OrderKey) -> Option<S::Event> {
+        (self as &mut DoubleEndedLagBuffer<S, SIZE, OrderKey, Observer>).retract(key)
+    }
+
+    fn amend(&mut self, event: S::Event) -> Result<AmendOutcome<S::Event>, TooLateError<S::Event>> {
+        (self as &mut DoubleEndedLagBuffer<S, SIZE, OrderKey, Observer>).amend(event)
+    }
+
+    fn clear(&mut self) {
+        (self as &mut DoubleEndedLagBuffer<S, SIZE, OrderKey, Observer>).clear()
+    }
+
+    fn reset(&mut self, state: S) {
+        (self as &mut DoubleEndedLagBuffer<S, SIZE, OrderKey, Observer>).reset(state)
+    }
+
+    fn stats(&self) -> &LagBufferStats {
+        (self as &DoubleEndedLagBuffer<S, SIZE, OrderKey, Observer>).stats()
+    }
+
+    fn reset_stats(&mut self) {
+        (self as &mut DoubleEndedLagBuffer<S, SIZE, OrderKey, Observer>).reset_stats()
+    }
+}
+
+impl<
+        S: State<OrderKey>,
+        const SIZE: usize,
+        OrderKey: Ord + Clone,
+        Observer: LagBufferObserver<S, OrderKey>,
+    > LagBufferState<S, OrderKey> for DoubleEndedLagBuffer<S, SIZE, OrderKey, Observer>
+{
+    fn state(&self) -> S {
+        (self as &DoubleEndedLagBuffer<S, SIZE, OrderKey, Observer>)
+            .state_ref()
+            .clone()
+    }
+
+    fn state_at(&self, key: &OrderKey) -> Option<S> {
+        (self as &DoubleEndedLagBuffer<S, SIZE, OrderKey, Observer>).state_at(key)
+    }
+}
+
+impl<
+        S: State<OrderKey>,
+        const SIZE: usize,
+        OrderKey: Ord + Clone,
+        Observer: LagBufferObserver<S, OrderKey>,
+    > LagBufferStateRef<S, OrderKey> for DoubleEndedLagBuffer<S, SIZE, OrderKey, Observer>
+{
+    fn state_ref(&self) -> &S {
+        (self as &DoubleEndedLagBuffer<S, SIZE, OrderKey, Observer>).state_ref()
+    }
+}
+
+impl<
+        S: State<OrderKey>,
+        const SIZE: usize,
+        OrderKey: Ord + Clone,
+        Observer: LagBufferObserver<S, OrderKey>,
+    > BaseLagBuffer<S, OrderKey> for ManualLagBuffer<S, SIZE, OrderKey, Observer>
+{
+    fn update(&mut self, event: S::Event) -> UpdateOutcome {
+        (self as &mut ManualLagBuffer<S, SIZE, OrderKey, Observer>).update(event)
+    }
+
+    fn len(&self) -> usize {
+        (self as &ManualLagBuffer<S, SIZE, OrderKey, Observer>).len()
+    }
+
+    fn capacity(&self) -> usize {
+        (self as &ManualLagBuffer<S, SIZE, OrderKey, Observer>).capacity()
+    }
+
+    fn head_key(&self) -> Option<OrderKey> {
+        (self as &ManualLagBuffer<S, SIZE, OrderKey, Observer>).head_key()
+    }
+
+    fn events(&self) -> Box<dyn Iterator<Item = &S::Event> + '_> {
+        Box::new((self as &ManualLagBuffer<S, SIZE, OrderKey, Observer>).events())
+    }
+
+    fn base_key(&self) -> Option<OrderKey> {
+        (self as &ManualLagBuffer<S, SIZE, OrderKey, Observer>).oldest_reconcilable_key()
+    }
+
+    fn compare_keys(&self, a: &OrderKey, b: &OrderKey) -> Ordering {
+        BatchBuffer::key_order(self).cmp(a, b)
+    }
+
+    fn retract(&mut self, key: &OrderKey) -> Option<S::Event> {
+        (self as &mut ManualLagBuffer<S, SIZE, OrderKey, Observer>).retract(key)
+    }
+
+    fn amend(&mut self, event: S::Event) -> Result<AmendOutcome<S::Event>, TooLateError<S::Event>> {
+        (self as &mut ManualLagBuffer<S, SIZE, OrderKey, Observer>).amend(event)
+    }
+
+    fn clear(&mut self) {
+        (self as &mut ManualLagBuffer<S, SIZE, OrderKey, Observer>).clear()
+    }
+
+    fn reset(&mut self, state: S) {
+        (self as &mut ManualLagBuffer<S, SIZE, OrderKey, Observer>).reset(state)
+    }
+
+    fn stats(&self) -> &LagBufferStats {
+        (self as &ManualLagBuffer<S, SIZE, OrderKey, Observer>).stats()
+    }
+
+    fn reset_stats(&mut self) {
+        (self as &mut ManualLagBuffer<S, SIZE, OrderKey, Observer>).reset_stats()
+    }
+}
+
+impl<
+        S: State<OrderKey>,
+        const SIZE: usize,
+        OrderKey: Ord + Clone,
+        Observer: LagBufferObserver<S, OrderKey>,
+    > LagBufferState<S, OrderKey> for ManualLagBuffer<S, SIZE, OrderKey, Observer>
+{
+    fn state(&self) -> S {
+        (self as &ManualLagBuffer<S, SIZE, OrderKey, Observer>)
+            .state_ref()
+            .clone()
+    }
+
+    fn state_at(&self, key: &OrderKey) -> Option<S> {
+        (self as &ManualLagBuffer<S, SIZE, OrderKey, Observer>).state_at(key)
+    }
+}
+
+impl<
+        S: State<OrderKey>,
+        const SIZE: usize,
+        OrderKey: Ord + Clone,
+        Observer: LagBufferObserver<S, OrderKey>,
+    > LagBufferStateRef<S, OrderKey> for ManualLagBuffer<S, SIZE, OrderKey, Observer>
+{
+    fn state_ref(&self) -> &S {
+        (self as &ManualLagBuffer<S, SIZE, OrderKey, Observer>).state_ref()
+    }
+}
+
+impl<S: State<OrderKey>, const SIZE: usize, const INTERVAL: usize, OrderKey: Ord + Clone>
+    BaseLagBuffer<S, OrderKey> for CheckpointedLagBuffer<S, SIZE, INTERVAL, OrderKey>
+{
+    fn update(&mut self, event: S::Event) -> UpdateOutcome {
+        (self as &mut CheckpointedLagBuffer<S, SIZE, INTERVAL, OrderKey>).update(event)
+    }
+
+    fn len(&self) -> usize {
+        (self as &CheckpointedLagBuffer<S, SIZE, INTERVAL, OrderKey>).len()
+    }
+
+    fn capacity(&self) -> usize {
+        (self as &CheckpointedLagBuffer<S, SIZE, INTERVAL, OrderKey>).capacity()
+    }
+
+    fn head_key(&self) -> Option<OrderKey> {
+        (self as &CheckpointedLagBuffer<S, SIZE, INTERVAL, OrderKey>).head_key()
+    }
+
+    fn events(&self) -> Box<dyn Iterator<Item = &S::Event> + '_> {
+        Box::new((self as &CheckpointedLagBuffer<S, SIZE, INTERVAL, OrderKey>).events())
+    }
+
+    fn base_key(&self) -> Option<OrderKey> {
+        (self as &CheckpointedLagBuffer<S, SIZE, INTERVAL, OrderKey>).oldest_reconcilable_key()
+    }
+
+    fn compare_keys(&self, a: &OrderKey, b: &OrderKey) -> Ordering {
+        BatchBuffer::key_order(self).cmp(a, b)
+    }
+
+    fn retract(&mut self, key: &OrderKey) -> Option<S::Event> {
+        (self as &mut CheckpointedLagBuffer<S, SIZE, INTERVAL, OrderKey>).retract(key)
+    }
+
+    fn amend(&mut self, event: S::Event) -> Result<AmendOutcome<S::Event>, TooLateError<S::Event>> {
+        (self as &mut CheckpointedLagBuffer<S, SIZE, INTERVAL, OrderKey>).amend(event)
+    }
+
+    fn clear(&mut self) {
+        (self as &mut CheckpointedLagBuffer<S, SIZE, INTERVAL, OrderKey>).clear()
+    }
+
+    fn reset(&mut self, state: S) {
+        (self as &mut CheckpointedLagBuffer<S, SIZE, INTERVAL, OrderKey>).reset(state)
+    }
+
+    fn stats(&self) -> &LagBufferStats {
+        (self as &CheckpointedLagBuffer<S, SIZE, INTERVAL, OrderKey>).stats()
+    }
+
+    fn reset_stats(&mut self) {
+        (self as &mut CheckpointedLagBuffer<S, SIZE, INTERVAL, OrderKey>).reset_stats()
+    }
+}
+
+impl<S: State<OrderKey>, const SIZE: usize, const INTERVAL: usize, OrderKey: Ord + Clone>
+    LagBufferState<S, OrderKey> for CheckpointedLagBuffer<S, SIZE, INTERVAL, OrderKey>
+{
+    fn state(&self) -> S {
+        (self as &CheckpointedLagBuffer<S, SIZE, INTERVAL, OrderKey>)
+            .state_ref()
+            .clone()
+    }
+
+    fn state_at(&self, key: &OrderKey) -> Option<S> {
+        (self as &CheckpointedLagBuffer<S, SIZE, INTERVAL, OrderKey>).state_at(key)
+    }
+}
+
+impl<S: State<OrderKey>, const SIZE: usize, const INTERVAL: usize, OrderKey: Ord + Clone>
+    LagBufferStateRef<S, OrderKey> for CheckpointedLagBuffer<S, SIZE, INTERVAL, OrderKey>
+{
+    fn state_ref(&self) -> &S {
+        (self as &CheckpointedLagBuffer<S, SIZE, INTERVAL, OrderKey>).state_ref()
+    }
+}
+
+impl<S: ReversibleState<OrderKey>, const SIZE: usize, OrderKey: Ord + Clone>
+    BaseLagBuffer<S, OrderKey> for ReversibleLagBuffer<S, SIZE, OrderKey>
+{
+    fn update(&mut self, event: S::Event) -> UpdateOutcome {
+        (self as &mut ReversibleLagBuffer<S, SIZE, OrderKey>).update(event)
+    }
+
+    fn len(&self) -> usize {
+        (self as &ReversibleLagBuffer<S, SIZE, OrderKey>).len()
+    }
+
+    fn capacity(&self) -> usize {
+        (self as &ReversibleLagBuffer<S, SIZE, OrderKey>).capacity()
+    }
+
+    fn head_key(&self) -> Option<OrderKey> {
+        (self as &ReversibleLagBuffer<S, SIZE, OrderKey>).head_key()
+    }
+
+    fn events(&self) -> Box<dyn Iterator<Item = &S::Event> + '_> {
+        Box::new((self as &ReversibleLagBuffer<S, SIZE, OrderKey>).events())
+    }
+
+    fn base_key(&self) -> Option<OrderKey> {
+        (self as &ReversibleLagBuffer<S, SIZE, OrderKey>).oldest_reconcilable_key()
+    }
+
+    fn compare_keys(&self, a: &OrderKey, b: &OrderKey) -> Ordering {
+        BatchBuffer::key_order(self).cmp(a, b)
+    }
+
+    fn retract(&mut self, key: &OrderKey) -> Option<S::Event> {
+        (self as &mut ReversibleLagBuffer<S, SIZE, OrderKey>).retract(key)
+    }
+
+    fn amend(&mut self, event: S::Event) -> Result<AmendOutcome<S::Event>, TooLateError<S::Event>> {
+        (self as &mut ReversibleLagBuffer<S, SIZE, OrderKey>).amend(event)
+    }
+
+    fn clear(&mut self) {
+        (self as &mut ReversibleLagBuffer<S, SIZE, OrderKey>).clear()
+    }
+
+    fn reset(&mut self, state: S) {
+        (self as &mut ReversibleLagBuffer<S, SIZE, OrderKey>).reset(state)
+    }
+
+    fn stats(&self) -> &LagBufferStats {
+        (self as &ReversibleLagBuffer<S, SIZE, OrderKey>).stats()
+    }
+
+    fn reset_stats(&mut self) {
+        (self as &mut ReversibleLagBuffer<S, SIZE, OrderKey>).reset_stats()
+    }
+}
+
+impl<S: ReversibleState<OrderKey>, const SIZE: usize, OrderKey: Ord + Clone>
+    LagBufferState<S, OrderKey> for ReversibleLagBuffer<S, SIZE, OrderKey>
+{
+    fn state(&self) -> S {
+        (self as &ReversibleLagBuffer<S, SIZE, OrderKey>)
+            .state_ref()
+            .clone()
+    }
+
+    fn state_at(&self, key: &OrderKey) -> Option<S> {
+        (self as &ReversibleLagBuffer<S, SIZE, OrderKey>).state_at(key)
+    }
+}
+
+impl<S: ReversibleState<OrderKey>, const SIZE: usize, OrderKey: Ord + Clone>
+    LagBufferStateRef<S, OrderKey> for ReversibleLagBuffer<S, SIZE, OrderKey>
+{
+    fn state_ref(&self) -> &S {
+        (self as &ReversibleLagBuffer<S, SIZE, OrderKey>).state_ref()
+    }
+}
+
+impl<
+        S: State<OrderKey>,
+        const SIZE: usize,
+        OrderKey: Ord + Clone,
+        Observer: LagBufferObserver<S, OrderKey>,
+    > Extend<S::Event> for DoubleBufferedLagBuffer<S, SIZE, OrderKey, Observer>
+{
+    fn extend<I: IntoIterator<Item = S::Event>>(&mut self, events: I) {
+        self.update_batch(events);
+    }
+}
+
+impl<
+        S: State<OrderKey>,
+        const SIZE: usize,
+        OrderKey: Ord + Clone,
+        Observer: LagBufferObserver<S, OrderKey>,
+    > Extend<S::Event> for DoubleEndedLagBuffer<S, SIZE, OrderKey, Observer>
+{
+    fn extend<I: IntoIterator<Item = S::Event>>(&mut self, events: I) {
+        self.update_batch(events);
+    }
+}
+
+impl<
+        S: State<OrderKey>,
+        const SIZE: usize,
+        OrderKey: Ord + Clone,
+        Observer: LagBufferObserver<S, OrderKey>,
+    > Extend<S::Event> for ManualLagBuffer<S, SIZE, OrderKey, Observer>
+{
+    fn extend<I: IntoIterator<Item = S::Event>>(&mut self, events: I) {
+        self.update_batch(events);
+    }
+}
+
+impl<S: State<OrderKey>, const SIZE: usize, const INTERVAL: usize, OrderKey: Ord + Clone>
+    Extend<S::Event> for CheckpointedLagBuffer<S, SIZE, INTERVAL, OrderKey>
+{
+    fn extend<I: IntoIterator<Item = S::Event>>(&mut self, events: I) {
+        self.update_batch(events);
+    }
+}
+
+impl<S: ReversibleState<OrderKey>, const SIZE: usize, OrderKey: Ord + Clone> Extend<S::Event>
+    for ReversibleLagBuffer<S, SIZE, OrderKey>
+{
+    fn extend<I: IntoIterator<Item = S::Event>>(&mut self, events: I) {
+        self.update_batch(events);
     }
 }
 
@@ -78,80 +789,369 @@ impl<S: State<OrderKey>, const SIZE: usize, OrderKey: Ord> LagBufferStateRef<S,
 #[cfg(test)]
 mod tests {
     use super::*;
-    // Example State and Event implementation for testing.
+    use crate::testing::{insert, insert_value, MyEvent, MyState};
+
+    #[test]
+    fn test_trait() {
+        let mut buffer: Box<dyn LagBufferStateRef<MyState>> =
+            Box::new(DoubleBufferedLagBuffer::<MyState, 4>::new(MyState::new()));
+
+        // Apply 4 insert events in order.
+        buffer.update(insert(1));
+        buffer.update(insert(2));
+        buffer.update(insert(3));
+        buffer.update(insert(4));
+        buffer.update(insert(5));
+
+        // Verify that the current state is as expected (order matters here).
+        assert_eq!(buffer.state_ref().data, vec![10, 20, 30, 40, 50]);
+    }
+
+    #[test]
+    fn test_trait_reports_outcome() {
+        let mut buffer: Box<dyn LagBufferStateRef<MyState>> =
+            Box::new(DoubleBufferedLagBuffer::<MyState, 4>::new(MyState::new()));
+
+        let outcome = buffer.update(insert(2));
+        assert_eq!(outcome, UpdateOutcome::AppliedInOrder);
 
-    #[derive(Clone, Debug, PartialEq)]
-    struct MyState {
-        pub data: Vec<i32>,
+        let outcome = buffer.update(insert(1));
+        assert_eq!(outcome, UpdateOutcome::Reordered { replayed: 2 });
+        assert_eq!(outcome.replayed(), 2);
+        assert!(outcome.is_accepted());
     }
 
-    impl MyState {
-        pub fn new() -> Self {
-            Self { data: Vec::new() }
+    #[test]
+    fn test_trait_checkpointed() {
+        let mut buffer: Box<dyn LagBufferStateRef<MyState>> =
+            Box::new(CheckpointedLagBuffer::<MyState, 8, 2>::new(MyState::new()));
+
+        for id in [1, 2, 4, 5, 3] {
+            buffer.update(insert(id));
         }
+
+        assert_eq!(buffer.state_ref().data, vec![10, 20, 30, 40, 50]);
     }
 
-    impl State<usize> for MyState {
-        type Event = MyEvent;
+    #[test]
+    fn test_trait_all_strategies() {
+        let buffers: Vec<Box<dyn LagBufferStateRef<MyState>>> = vec![
+            Box::new(DoubleBufferedLagBuffer::<MyState, 8>::new(MyState::new())),
+            Box::new(DoubleEndedLagBuffer::<MyState, 8>::new(MyState::new())),
+            Box::new(ManualLagBuffer::<MyState, 8>::new(MyState::new())),
+            Box::new(CheckpointedLagBuffer::<MyState, 8, 2>::new(MyState::new())),
+            Box::new(ReversibleLagBuffer::<MyState, 8>::new(MyState::new())),
+        ];
 
-        fn apply(&mut self, event: &Self::Event) {
-            match event.action {
-                Action::Insert => self.data.push(event.value),
+        for mut buffer in buffers {
+            assert!(buffer.is_empty());
+            for id in [1, 2, 4, 5, 3] {
+                buffer.update(insert(id));
             }
+
+            assert_eq!(buffer.state_ref().data, vec![10, 20, 30, 40, 50]);
+            assert_eq!(buffer.len(), 5);
+            assert_eq!(buffer.head_key(), Some(5));
+            assert_eq!(buffer.base_key(), None);
+
+            buffer.clear();
+            assert_eq!(buffer.len(), 0);
+            assert_eq!(buffer.base_key(), Some(5));
+            assert_eq!(buffer.state_ref().data, vec![10, 20, 30, 40, 50]);
+
+            buffer.reset(MyState::new());
+            assert_eq!(buffer.base_key(), None);
+            assert!(buffer.state_ref().data.is_empty());
         }
     }
 
-    #[derive(Clone, Debug)]
-    enum Action {
-        Insert,
+    #[test]
+    fn test_stats() {
+        let buffers: Vec<Box<dyn LagBufferState<MyState>>> = vec![
+            Box::new(
+                DoubleBufferedLagBuffer::<MyState, 8>::new(MyState::new()).with_lateness_stats(),
+            ),
+            Box::new(DoubleEndedLagBuffer::<MyState, 8>::new(MyState::new()).with_lateness_stats()),
+            Box::new(ManualLagBuffer::<MyState, 8>::new(MyState::new()).with_lateness_stats()),
+            Box::new(
+                CheckpointedLagBuffer::<MyState, 8, 2>::new(MyState::new()).with_lateness_stats(),
+            ),
+            Box::new(ReversibleLagBuffer::<MyState, 8>::new(MyState::new()).with_lateness_stats()),
+        ];
+
+        for mut buffer in buffers {
+            for id in [1, 2, 4, 5, 3] {
+                buffer.update(insert(id));
+            }
+            buffer.clear();
+            buffer.update(insert(2));
+
+            let stats = buffer.stats();
+            assert_eq!(stats.updates, 6);
+            assert_eq!(stats.in_order, 4);
+            assert_eq!(stats.out_of_order, 1);
+            assert_eq!(stats.drops, 1);
+            assert_eq!(stats.replay_histogram[0], 4);
+            assert_eq!(stats.max_lateness, 2.0);
+            assert_eq!(stats.mean_lateness(), Some(2.0));
+
+            buffer.reset_stats();
+            assert_eq!(*buffer.stats(), LagBufferStats::default());
+        }
     }
 
-    #[derive(Clone, Debug)]
-    struct MyEvent {
-        id: usize,
-        value: i32,
-        action: Action,
+    #[test]
+    fn test_swap_stats_all_strategies() {
+        let buffers: Vec<Box<dyn LagBufferState<MyState>>> = vec![
+            Box::new(DoubleBufferedLagBuffer::<MyState, 4>::new(MyState::new())),
+            Box::new(DoubleEndedLagBuffer::<MyState, 4>::new(MyState::new())),
+            Box::new(ManualLagBuffer::<MyState, 4>::new(MyState::new())),
+            Box::new(CheckpointedLagBuffer::<MyState, 4, 2>::new(MyState::new())),
+            Box::new(ReversibleLagBuffer::<MyState, 4>::new(MyState::new())),
+        ];
+
+        for mut buffer in buffers {
+            let swapped = [1, 2, 4, 3, 5, 6, 8, 7, 9]
+                .map(|id| buffer.update(insert(id)))
+                .iter()
+                .filter(|outcome| matches!(outcome, UpdateOutcome::Swapped { .. }))
+                .count();
+
+            // Only the manual buffer never folds on its own
+            assert_eq!(buffer.stats().swaps, swapped as u64);
+            assert_eq!(swapped > 0, buffer.base_key().is_some());
+        }
     }
 
-    impl Event<usize> for MyEvent {
-        fn get_order_key(&self) -> usize {
-            self.id
+    fn check_update_batch<B: LagBufferStateRef<MyState> + Extend<MyEvent>>(mut buffer: B) {
+        for id in [1, 2, 6, 8] {
+            buffer.update(insert(id));
         }
+        buffer.extend([7, 3, 9, 5, 4].map(insert));
+
+        assert_eq!(
+            buffer.state_ref().data,
+            vec![10, 20, 30, 40, 50, 60, 70, 80, 90]
+        );
+        let stats = buffer.stats();
+        assert_eq!(stats.updates, 9);
+        assert_eq!(stats.out_of_order, 4);
+        // 3, 4, 5 and 7 are merged with one replay, which is a single observation
+        assert_eq!(stats.replay_histogram.iter().sum::<u64>(), 6);
     }
 
     #[test]
-    fn test_trait() {
-        let mut buffer: Box<dyn LagBufferStateRef<MyState>> =
-            Box::new(DoubleBufferedLagBuffer::<MyState, 4>::new(MyState::new()));
+    fn test_update_batch_all_strategies() {
+        check_update_batch(DoubleBufferedLagBuffer::<MyState, 16>::new(MyState::new()));
+        check_update_batch(DoubleEndedLagBuffer::<MyState, 16>::new(MyState::new()));
+        check_update_batch(ManualLagBuffer::<MyState, 16>::new(MyState::new()));
+        check_update_batch(CheckpointedLagBuffer::<MyState, 16, 4>::new(MyState::new()));
+        check_update_batch(ReversibleLagBuffer::<MyState, 16>::new(MyState::new()));
+    }
 
-        // Apply 4 insert events in order.
-        buffer.update(MyEvent {
-            id: 1,
-            value: 10,
-            action: Action::Insert,
-        });
-        buffer.update(MyEvent {
-            id: 2,
-            value: 20,
-            action: Action::Insert,
-        });
-        buffer.update(MyEvent {
-            id: 3,
-            value: 30,
-            action: Action::Insert,
-        });
-        buffer.update(MyEvent {
-            id: 4,
-            value: 40,
-            action: Action::Insert,
-        });
-        buffer.update(MyEvent {
-            id: 5,
-            value: 50,
-            action: Action::Insert,
-        });
+    #[test]
+    fn test_retract_all_strategies() {
+        let buffers: Vec<Box<dyn LagBufferStateRef<MyState>>> = vec![
+            Box::new(DoubleBufferedLagBuffer::<MyState, 8>::new(MyState::new())),
+            Box::new(DoubleEndedLagBuffer::<MyState, 8>::new(MyState::new())),
+            Box::new(ManualLagBuffer::<MyState, 8>::new(MyState::new())),
+            Box::new(CheckpointedLagBuffer::<MyState, 8, 2>::new(MyState::new())),
+            Box::new(ReversibleLagBuffer::<MyState, 8>::new(MyState::new())),
+        ];
 
-        // Verify that the current state is as expected (order matters here).
-        assert_eq!(buffer.state_ref().data, vec![10, 20, 30, 40, 50]);
+        for mut buffer in buffers {
+            for id in [1, 2, 4, 5, 3] {
+                buffer.update(insert(id));
+            }
+
+            assert_eq!(buffer.retract(&3).map(|e| e.value), Some(30));
+            assert_eq!(buffer.retract(&3).map(|e| e.value), None);
+            assert_eq!(buffer.state_ref().data, vec![10, 20, 40, 50]);
+            assert_eq!(buffer.len(), 4);
+
+            assert!(buffer.retract(&5).is_some());
+            assert_eq!(buffer.head_key(), Some(4));
+            assert_eq!(buffer.state_ref().data, vec![10, 20, 40]);
+        }
+    }
+
+    // Feeds the same events in two arrival orders to every strategy
+    fn check_tie_break(tie_break: fn() -> TieBreak<MyState>, expected: [Vec<i32>; 2]) {
+        let arrivals = [
+            [(1, 10), (2, 21), (3, 30), (2, 22)],
+            [(2, 22), (3, 30), (2, 21), (1, 10)],
+        ];
+
+        for (events, expected) in arrivals.iter().zip(expected) {
+            let buffers: Vec<Box<dyn LagBufferStateRef<MyState>>> = vec![
+                Box::new(
+                    DoubleBufferedLagBuffer::<MyState, 8>::new(MyState::new())
+                        .with_tie_break(tie_break()),
+                ),
+                Box::new(
+                    DoubleEndedLagBuffer::<MyState, 8>::new(MyState::new())
+                        .with_tie_break(tie_break()),
+                ),
+                Box::new(
+                    ManualLagBuffer::<MyState, 8>::new(MyState::new()).with_tie_break(tie_break()),
+                ),
+                Box::new(
+                    CheckpointedLagBuffer::<MyState, 8, 2>::new(MyState::new())
+                        .with_tie_break(tie_break()),
+                ),
+                Box::new(
+                    ReversibleLagBuffer::<MyState, 8>::new(MyState::new())
+                        .with_tie_break(tie_break()),
+                ),
+            ];
+
+            for mut buffer in buffers {
+                let outcomes: Vec<_> = events
+                    .iter()
+                    .map(|&(id, value)| buffer.update(insert_value(id, value)))
+                    .collect();
+
+                assert_eq!(buffer.state_ref().data, expected);
+                assert_eq!(
+                    outcomes.contains(&UpdateOutcome::Duplicate),
+                    expected.len() == 3
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_tie_break_all_strategies() {
+        check_tie_break(
+            || TieBreak::ArrivalOrder,
+            [vec![10, 21, 22, 30], vec![10, 22, 21, 30]],
+        );
+        check_tie_break(
+            || TieBreak::RejectDuplicates,
+            [vec![10, 21, 30], vec![10, 22, 30]],
+        );
+        check_tie_break(
+            || TieBreak::SecondaryKey(Box::new(|a: &MyEvent, b: &MyEvent| a.value.cmp(&b.value))),
+            [vec![10, 21, 22, 30], vec![10, 21, 22, 30]],
+        );
+    }
+
+    #[test]
+    fn test_amend_all_strategies() {
+        let buffers: Vec<Box<dyn LagBufferStateRef<MyState>>> = vec![
+            Box::new(DoubleBufferedLagBuffer::<MyState, 8>::new(MyState::new())),
+            Box::new(DoubleEndedLagBuffer::<MyState, 8>::new(MyState::new())),
+            Box::new(ManualLagBuffer::<MyState, 8>::new(MyState::new())),
+            Box::new(CheckpointedLagBuffer::<MyState, 8, 2>::new(MyState::new())),
+            Box::new(ReversibleLagBuffer::<MyState, 8>::new(MyState::new())),
+        ];
+        for mut buffer in buffers {
+            for id in [1, 2, 4, 5, 3] {
+                buffer.update(insert(id));
+            }
+
+            let amended = buffer.amend(insert_value(2, 25)).unwrap().replaced();
+            assert_eq!(amended.map(|e| e.value), Some(20));
+            assert_eq!(buffer.state_ref().data, vec![10, 25, 30, 40, 50]);
+            assert_eq!(buffer.len(), 5);
+
+            // Unknown keys are handled like an update.
+            assert!(matches!(
+                buffer.amend(insert_value(6, 60)),
+                Ok(AmendOutcome::Updated(UpdateOutcome::AppliedInOrder))
+            ));
+            assert_eq!(buffer.state_ref().data, vec![10, 25, 30, 40, 50, 60]);
+        }
+    }
+
+    #[test]
+    fn test_amend_secondary_key_all_strategies() {
+        let by_value =
+            || TieBreak::SecondaryKey(Box::new(|a: &MyEvent, b: &MyEvent| a.value.cmp(&b.value)));
+        let buffers: Vec<Box<dyn LagBufferStateRef<MyState>>> = vec![
+            Box::new(
+                DoubleBufferedLagBuffer::<MyState, 8>::new(MyState::new())
+                    .with_tie_break(by_value()),
+            ),
+            Box::new(
+                DoubleEndedLagBuffer::<MyState, 8>::new(MyState::new()).with_tie_break(by_value()),
+            ),
+            Box::new(ManualLagBuffer::<MyState, 8>::new(MyState::new()).with_tie_break(by_value())),
+            Box::new(
+                CheckpointedLagBuffer::<MyState, 8, 2>::new(MyState::new())
+                    .with_tie_break(by_value()),
+            ),
+            Box::new(
+                ReversibleLagBuffer::<MyState, 8>::new(MyState::new()).with_tie_break(by_value()),
+            ),
+        ];
+        for mut buffer in buffers {
+            for (id, value) in [(1, 10), (2, 21), (2, 22), (2, 23), (3, 30)] {
+                buffer.update(insert_value(id, value));
+            }
+
+            // The oldest event with the key is replaced and moved to where its value sorts
+            let amended = buffer.amend(insert_value(2, 25)).unwrap().replaced();
+            assert_eq!(amended.map(|e| e.value), Some(21));
+            assert_eq!(buffer.len(), 5);
+            assert_eq!(buffer.state_ref().data, vec![10, 22, 23, 25, 30]);
+
+            // An event that can't be reconciled anymore reports the outcome of the update
+            buffer.clear();
+            assert!(matches!(
+                buffer.amend(insert_value(1, 15)),
+                Ok(AmendOutcome::Updated(UpdateOutcome::RejectedTooLate))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_state_at() {
+        let buffers: Vec<Box<dyn LagBufferState<MyState>>> = vec![
+            Box::new(DoubleBufferedLagBuffer::<MyState, 4>::new(MyState::new())),
+            Box::new(DoubleEndedLagBuffer::<MyState, 4>::new(MyState::new())),
+            Box::new(ManualLagBuffer::<MyState, 4>::new(MyState::new())),
+            Box::new(CheckpointedLagBuffer::<MyState, 4, 2>::new(MyState::new())),
+            Box::new(ReversibleLagBuffer::<MyState, 4>::new(MyState::new())),
+        ];
+
+        for mut buffer in buffers {
+            for id in [1, 3, 4, 2, 5, 6, 7] {
+                buffer.update(insert(id));
+            }
+
+            let expected = (1..=5).map(|id| id * 10).collect::<Vec<_>>();
+            assert_eq!(buffer.state_at(&5).unwrap().data, expected);
+            assert_eq!(buffer.state_at(&100).unwrap().data, buffer.state().data);
+            if let Some(base_key) = buffer.base_key() {
+                assert!(buffer.state_at(&base_key).is_some());
+                assert!(buffer.state_at(&(base_key - 1)).is_none());
+            }
+
+            // The current state is left untouched.
+            assert_eq!(buffer.state().data, vec![10, 20, 30, 40, 50, 60, 70]);
+        }
+    }
+
+    #[test]
+    fn test_events() {
+        let buffers: Vec<Box<dyn LagBufferStateRef<MyState>>> = vec![
+            Box::new(DoubleBufferedLagBuffer::<MyState, 8>::new(MyState::new())),
+            Box::new(DoubleEndedLagBuffer::<MyState, 8>::new(MyState::new())),
+            Box::new(ManualLagBuffer::<MyState, 8>::new(MyState::new())),
+            Box::new(CheckpointedLagBuffer::<MyState, 8, 2>::new(MyState::new())),
+            Box::new(ReversibleLagBuffer::<MyState, 8>::new(MyState::new())),
+        ];
+
+        for mut buffer in buffers {
+            assert_eq!(buffer.oldest_key(), None);
+            for id in [3, 1, 4, 2] {
+                buffer.update(insert(id));
+            }
+
+            let ids = buffer.events().map(|e| e.id).collect::<Vec<_>>();
+            assert_eq!(ids, vec![1, 2, 3, 4]);
+            assert_eq!(buffer.oldest_key(), Some(1));
+        }
     }
 }
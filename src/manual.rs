@@ -1,6 +1,15 @@
 use core::panic;
+use std::fmt;
+use std::ops::RangeBounds;
 
-use crate::{Event, State};
+use crate::batch::{self, BatchBuffer};
+use crate::key::{KeyOrder, RelativeCmp};
+use crate::stats::StatsCollector;
+use crate::trace::KeyFormat;
+use crate::{
+    AmendOutcome, BatchOutcome, Event, KeyDistance, LagBufferObserver, LagBufferStats, LatePolicy,
+    RelativeOrd, State, TieBreak, TooLateError, UpdateOutcome,
+};
 
 #[derive(Clone)]
 enum EventOrSnapshot<S: State<OrderKey>, OrderKey: Ord = usize>
@@ -24,56 +33,765 @@ impl<S: State<OrderKey>, OrderKey: Ord + Clone> EventOrSnapshot<S, OrderKey> {
         }
         panic!("Should never happen!");
     }
-    pub fn as_event(&self) -> &S::Event {
+    pub fn as_event(&self) -> Option<&S::Event> {
         if let EventOrSnapshot::Event(e) = self {
-            return e;
+            return Some(e);
         }
-        panic!("Should never happen!");
+        None
+    }
+    pub fn order_key(&self) -> Option<OrderKey> {
+        if let EventOrSnapshot::Event(e) = self {
+            return Some(e.get_order_key());
+        }
+        None
     }
 }
 
-pub struct ManualLagBuffer<S: State<OrderKey>, const SIZE: usize, OrderKey: Ord + Clone = usize> {
+/// A lag buffer where the user decides when state snapshots are taken.
+///
+/// Events are kept in order together with the snapshots taken via [`take_snapshot`](Self::take_snapshot).
+/// An out-of-order event is inserted at its position and the state is replayed from the nearest
+/// snapshot preceding it, so the cost of reconciliation depends on how often snapshots are taken.
+/// History is only discarded when [`compact_before`](Self::compact_before) is called.
+///
+/// # Type Parameters
+///
+/// - `S`: The type of the state, which must implement the [`State`](trait.State.html) trait.
+/// - `SIZE`: The number of entries the buffer reserves space for up front.
+/// - `OrderKey`: The type of the event's order key. Defaults to `usize`.
+/// - `Observer`: The [`LagBufferObserver`] notified by [`update`](Self::update). Defaults to `()`,
+///   which observes nothing.
+pub struct ManualLagBuffer<
+    S: State<OrderKey>,
+    const SIZE: usize,
+    OrderKey: Ord + Clone = usize,
+    Observer = (),
+> {
+    // Always starts with the base snapshot.
     buffer: Vec<EventOrSnapshot<S, OrderKey>>,
+    current_state: S,
+    base_key: Option<OrderKey>,
+    late_policy: LatePolicy<S, OrderKey>,
+    tie_break: TieBreak<S, OrderKey>,
+    stats: StatsCollector<OrderKey>,
+    key_format: Option<KeyFormat<OrderKey>>,
+    relative_order: Option<RelativeCmp<OrderKey>>,
+    observer: Observer,
 }
 
 impl<S: State<OrderKey>, const SIZE: usize, OrderKey: Ord + Clone>
     ManualLagBuffer<S, SIZE, OrderKey>
 {
     pub fn new(initial_state: S) -> Self {
+        let mut buffer = Vec::with_capacity(SIZE.max(1));
+        buffer.push(EventOrSnapshot::Snapshot(initial_state.clone()));
         Self {
-            buffer: vec![EventOrSnapshot::Snapshot(initial_state)],
+            buffer,
+            current_state: initial_state,
+            base_key: None,
+            late_policy: LatePolicy::default(),
+            tie_break: TieBreak::default(),
+            stats: StatsCollector::default(),
+            key_format: None,
+            relative_order: None,
+            observer: (),
         }
     }
+}
 
-    pub fn update(&mut self, event: S::Event) {
-        let in_order = match self.buffer.last() {
-            Some(EventOrSnapshot::Event(last_event)) => {
-                last_event.get_order_key() <= event.get_order_key()
-            }
-            _ => true,
+impl<
+        S: State<OrderKey>,
+        const SIZE: usize,
+        OrderKey: Ord + Clone,
+        Observer: LagBufferObserver<S, OrderKey>,
+    > ManualLagBuffer<S, SIZE, OrderKey, Observer>
+{
+    /// Sets the observer that is notified about what [`update`](Self::update) does.
+    ///
+    /// Manual buffers only move their base in [`compact_before`](Self::compact_before) and
+    /// [`clear`](Self::clear), so the observer only sees a swap there.
+    pub fn with_observer<O: LagBufferObserver<S, OrderKey>>(
+        self,
+        observer: O,
+    ) -> ManualLagBuffer<S, SIZE, OrderKey, O> {
+        ManualLagBuffer {
+            buffer: self.buffer,
+            current_state: self.current_state,
+            base_key: self.base_key,
+            late_policy: self.late_policy,
+            tie_break: self.tie_break,
+            stats: self.stats,
+            key_format: self.key_format,
+            relative_order: self.relative_order,
+            observer,
+        }
+    }
+
+    /// Returns a reference to the observer.
+    pub fn observer(&self) -> &Observer {
+        &self.observer
+    }
+
+    /// Returns a mutable reference to the observer.
+    pub fn observer_mut(&mut self) -> &mut Observer {
+        &mut self.observer
+    }
+
+    /// Sets the policy for events that are too old to be reconciled.
+    pub fn with_late_policy(mut self, late_policy: LatePolicy<S, OrderKey>) -> Self {
+        self.late_policy = late_policy;
+        self
+    }
+
+    /// Sets how events with equal order keys are ordered.
+    pub fn with_tie_break(mut self, tie_break: TieBreak<S, OrderKey>) -> Self {
+        self.tie_break = tie_break;
+        self
+    }
+
+    /// Records the order key of every update on its trace span.
+    ///
+    /// Only has an effect with the `tracing` feature.
+    pub fn with_traced_keys(mut self) -> Self
+    where
+        OrderKey: fmt::Debug,
+    {
+        self.key_format = Some(|key| format!("{key:?}"));
+        self
+    }
+
+    /// Compares order keys with [`RelativeOrd::cmp_relative`], anchored at the last folded key.
+    ///
+    /// Use this for keys like [`WrappingSeq`](crate::WrappingSeq), whose `Ord` doesn't follow
+    /// the order in which the events were produced.
+    pub fn with_relative_keys(mut self) -> Self
+    where
+        OrderKey: RelativeOrd,
+    {
+        self.relative_order = Some(OrderKey::cmp_relative);
+        self
+    }
+
+    /// Updates the buffer with a new event.
+    ///
+    /// In-order events are applied directly to the current state. Out-of-order events are inserted
+    /// at their position and the state is replayed from the nearest preceding snapshot. Snapshots
+    /// after the insertion point are brought up to date along the way.
+    pub fn update(&mut self, event: S::Event) -> UpdateOutcome {
+        self.try_update(event)
+            .unwrap_or(UpdateOutcome::RejectedTooLate)
+    }
+
+    /// Updates the buffer with a new event, like [`update`](Self::update).
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`TooLateError`] holding the event if it is too old to be reconciled and the
+    /// buffer uses [`LatePolicy::Error`].
+    pub fn try_update(&mut self, event: S::Event) -> Result<UpdateOutcome, TooLateError<S::Event>> {
+        trace_span!(
+            span,
+            "update",
+            buffer = "manual",
+            len = self.len(),
+            key = tracing::field::Empty,
+            outcome = tracing::field::Empty
+        );
+        let key = event.get_order_key();
+        trace_key!(span, self.key_format, &key);
+        let head_key = self.head_key();
+        let result = self.reconcile(event);
+        trace_record!(
+            span,
+            "outcome",
+            tracing::field::debug(&result.as_ref().ok())
+        );
+        self.stats.record(&result, head_key.as_ref(), &key);
+        result
+    }
+
+    // Places the event and reconciles the state, see `update`
+    fn reconcile(&mut self, event: S::Event) -> Result<UpdateOutcome, TooLateError<S::Event>> {
+        let keys = self.key_order();
+        let key = event.get_order_key();
+
+        let too_late = self
+            .base_key
+            .as_ref()
+            .is_some_and(|base_key| keys.lt(&key, base_key));
+        if too_late {
+            self.observer.on_drop(&event);
+            return LatePolicy::reject(self, |buffer| &mut buffer.late_policy, event);
+        }
+
+        // Events go after every buffered event that doesn't sort after them
+        let previous = self.buffer.iter().rposition(|entry| {
+            entry
+                .as_event()
+                .is_some_and(|e| self.tie_break.is_after(&keys, e, &event))
+        });
+        let previous_key = match previous {
+            Some(previous) => self.buffer[previous].order_key(),
+            None => self.base_key.clone(),
         };
+        if self.tie_break.is_duplicate(previous_key.as_ref(), &key) {
+            return Ok(UpdateOutcome::Duplicate);
+        }
+
+        let in_order = self.buffer.iter().rposition(|entry| !entry.is_snapshot()) == previous;
+        self.observer.on_apply(&event);
         if in_order {
+            self.current_state.apply(&event);
             self.buffer.push(EventOrSnapshot::Event(event));
-        } else {
+            return Ok(UpdateOutcome::AppliedInOrder);
         }
+
+        // Insert after the last event that is not newer, but behind any snapshots taken right
+        // after it, so those stay valid.
+        let mut insert_position = previous.map_or(0, |i| i + 1);
+        insert_position += self.buffer[insert_position..]
+            .iter()
+            .take_while(|entry| entry.is_snapshot())
+            .count();
+        self.buffer
+            .insert(insert_position, EventOrSnapshot::Event(event));
+        trace_span!(
+            span,
+            "reconstruct",
+            insert_position,
+            replayed = tracing::field::Empty
+        );
+
+        let replayed = self.replay_from(insert_position);
+        trace_record!(span, "replayed", replayed);
+        self.observer.on_rollback(&key, replayed);
+
+        Ok(UpdateOutcome::Reordered { replayed })
     }
 
-    /// Returns a reference to the current state.
+    /// Updates the buffer with a batch of events, reconciling all of them with a single replay.
+    ///
+    /// The batch is sorted by key first. Events that are too late are handed to the
+    /// [`LatePolicy`] and events that are not older than the newest buffered event are applied
+    /// in order, both exactly like in [`update`](Self::update). All other events are inserted at
+    /// their positions and the state is replayed once from the snapshot preceding the oldest of
+    /// them.
+    pub fn update_batch(&mut self, events: impl IntoIterator<Item = S::Event>) -> BatchOutcome {
+        batch::update_batch(self, events)
+    }
+
+    // Replays from the nearest snapshot before `position`, refreshing later snapshots.
+    // Returns the number of replayed events.
+    fn replay_from(&mut self, position: usize) -> usize {
+        let snapshot_position = self.buffer[..position]
+            .iter()
+            .rposition(EventOrSnapshot::is_snapshot)
+            .unwrap_or(0);
+        let mut state = self.buffer[snapshot_position].as_snapshot().clone();
+        let mut replayed = 0;
+        for entry in &mut self.buffer[snapshot_position + 1..] {
+            match entry {
+                EventOrSnapshot::Event(e) => {
+                    state.apply(e);
+                    replayed += 1;
+                }
+                EventOrSnapshot::Snapshot(snapshot) => *snapshot = state.clone(),
+            }
+        }
+        self.current_state = state;
+        replayed
+    }
+
+    // Lets the kept states drop what they track for events older than the base key
+    fn forget_folded(&mut self) {
+        let keys = self.key_order();
+        if let Some(base_key) = &self.base_key {
+            let is_folded = |key: &OrderKey| keys.lt(key, base_key);
+            for entry in &mut self.buffer {
+                if let EventOrSnapshot::Snapshot(state) = entry {
+                    state.forget(&is_folded);
+                }
+            }
+            self.current_state.forget(&is_folded);
+        }
+    }
+
+    /// Removes a buffered event and reconstructs the current state as though it never happened.
+    ///
+    /// The state is replayed from the nearest snapshot preceding the event and later snapshots
+    /// are brought up to date. Events that were already compacted can't be retracted.
+    ///
+    /// # Arguments
+    ///
+    /// - `key`: The order key of the event to remove. If several events share the key, the oldest
+    ///   one is removed.
     ///
     /// # Returns
     ///
-    /// A reference to the current state after applying all events.
-    pub fn state(&self) -> S {
-        let pos = self
+    /// The removed event, or `None` if no buffered event has the given key.
+    pub fn retract(&mut self, key: &OrderKey) -> Option<S::Event> {
+        let position = self
+            .buffer
+            .iter()
+            .position(|entry| entry.order_key().is_some_and(|k| k == *key))?;
+        let EventOrSnapshot::Event(event) = self.buffer.remove(position) else {
+            unreachable!("only events have an order key");
+        };
+        // A snapshot right after the event now duplicates the one before it
+        if self.buffer[position - 1].is_snapshot()
+            && self
+                .buffer
+                .get(position)
+                .is_some_and(EventOrSnapshot::is_snapshot)
+        {
+            self.buffer.remove(position);
+        }
+
+        let replayed = self.replay_from(position);
+        trace_span!(_span, "reconstruct", position, replayed);
+        self.observer.on_rollback(key, replayed);
+
+        Some(event)
+    }
+
+    /// Replaces the buffered event with the same order key and reconstructs the current state.
+    ///
+    /// The state is replayed from the nearest snapshot preceding the event. If no buffered event
+    /// has the key, the event is handled like in [`try_update`](Self::try_update).
+    ///
+    /// # Arguments
+    ///
+    /// - `event`: The corrected event. If several buffered events share its key, the oldest one is
+    ///   replaced. With [`TieBreak::SecondaryKey`], the corrected event is moved among them to
+    ///   where the function orders it.
+    ///
+    /// # Errors
+    ///
+    /// Returns the [`TooLateError`] of [`try_update`](Self::try_update) for an event that is
+    /// handled like there.
+    pub fn amend(
+        &mut self,
+        event: S::Event,
+    ) -> Result<AmendOutcome<S::Event>, TooLateError<S::Event>> {
+        let keys = self.key_order();
+        let key = event.get_order_key();
+        // The positions of the events with the key, snapshots may lie between them
+        let tied: Vec<_> = self
+            .buffer
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.order_key().is_some_and(|k| k == key))
+            .map(|(position, _)| position)
+            .collect();
+        let Some(&position) = tied.first() else {
+            return self.try_update(event).map(AmendOutcome::Updated);
+        };
+        let EventOrSnapshot::Event(amended) =
+            std::mem::replace(&mut self.buffer[position], EventOrSnapshot::Event(event))
+        else {
+            unreachable!("only events have an order key");
+        };
+        let target = self.tie_break.reposition(
+            &keys,
+            tied.iter()
+                .filter_map(|&position| self.buffer[position].as_event()),
+            0,
+        );
+        for index in 0..target {
+            self.buffer.swap(tied[index], tied[index + 1]);
+        }
+
+        let replayed = self.replay_from(position);
+        trace_span!(_span, "reconstruct", position, replayed);
+        self.observer.on_rollback(&key, replayed);
+
+        Ok(AmendOutcome::Replaced {
+            event: amended,
+            replayed,
+        })
+    }
+
+    /// Records a snapshot of the current state.
+    ///
+    /// Out-of-order events newer than the snapshot only need to replay events from here on.
+    pub fn take_snapshot(&mut self) {
+        if let Some(EventOrSnapshot::Snapshot(_)) = self.buffer.last() {
+            return;
+        }
+        self.buffer
+            .push(EventOrSnapshot::Snapshot(self.current_state.clone()));
+    }
+
+    /// Discards all events older than `key` by folding them into the base state.
+    ///
+    /// Afterwards, events older than the newest compacted event can no longer be reconciled and
+    /// are handed to the [`LatePolicy`], see
+    /// [`oldest_reconcilable_key`](Self::oldest_reconcilable_key). Late events between that
+    /// event and `key` are still placed and replayed.
+    ///
+    /// # Arguments
+    ///
+    /// - `key`: The oldest order key to keep.
+    pub fn compact_before(&mut self, key: &OrderKey) {
+        let keys = self.key_order();
+        let split = self
             .buffer
+            .iter()
+            .position(|entry| entry.order_key().is_some_and(|k| keys.le(key, &k)))
+            .unwrap_or(self.buffer.len());
+        let snapshot_position = self.buffer[..split]
+            .iter()
+            .rposition(EventOrSnapshot::is_snapshot)
+            .unwrap_or(0);
+
+        let mut base = self.buffer[snapshot_position].as_snapshot().clone();
+        for entry in &self.buffer[snapshot_position + 1..split] {
+            if let EventOrSnapshot::Event(e) = entry {
+                base.apply(e);
+            }
+        }
+        let last_key = self.buffer[..split]
             .iter()
             .rev()
-            .position(|i| i.is_snapshot())
-            .unwrap();
-        let mut state = self.buffer[self.buffer.len() - pos].as_snapshot().clone();
-        for e in &self.buffer[self.buffer.len() - (pos - 1)..] {
-            state.apply(e.as_event());
+            .find_map(EventOrSnapshot::order_key);
+
+        self.buffer.drain(..split);
+        self.buffer.insert(0, EventOrSnapshot::Snapshot(base));
+        if let Some(last_key) = last_key {
+            self.base_key = Some(last_key);
+            self.forget_folded();
+            self.observer.on_swap(self.buffer[0].as_snapshot());
+        }
+    }
+
+    /// Reconstructs the state as of the given order key without touching the current state.
+    ///
+    /// The result contains every retained event whose key is not newer than `key`.
+    ///
+    /// # Returns
+    ///
+    /// `None` if `key` is older than the [`oldest_reconcilable_key`](Self::oldest_reconcilable_key).
+    pub fn state_at(&self, key: &OrderKey) -> Option<S> {
+        let keys = self.key_order();
+        if self
+            .base_key
+            .as_ref()
+            .is_some_and(|base_key| keys.lt(key, base_key))
+        {
+            return None;
         }
-        state
+        let end = self
+            .buffer
+            .iter()
+            .position(|entry| entry.order_key().is_some_and(|k| keys.lt(key, &k)))
+            .unwrap_or(self.buffer.len());
+        if end == self.buffer.len() {
+            return Some(self.current_state.clone());
+        }
+        let snapshot_position = self.buffer[..end]
+            .iter()
+            .rposition(EventOrSnapshot::is_snapshot)
+            .unwrap_or(0);
+        let mut state = self.buffer[snapshot_position].as_snapshot().clone();
+        for entry in &self.buffer[snapshot_position + 1..end] {
+            if let EventOrSnapshot::Event(e) = entry {
+                state.apply(e);
+            }
+        }
+        Some(state)
+    }
+
+    /// Returns the oldest order key that can still be reconciled.
+    ///
+    /// Events with an older key are handed to the [`LatePolicy`]. Returns `None` while no
+    /// events have been compacted, i.e. while every key can be reconciled.
+    pub fn oldest_reconcilable_key(&self) -> Option<OrderKey> {
+        self.base_key.clone()
+    }
+
+    /// Returns the number of buffered events.
+    pub fn len(&self) -> usize {
+        self.buffer
+            .iter()
+            .filter(|entry| !entry.is_snapshot())
+            .count()
+    }
+
+    /// Returns `true` if no events are buffered.
+    pub fn is_empty(&self) -> bool {
+        self.buffer.iter().all(EventOrSnapshot::is_snapshot)
+    }
+
+    /// Returns the number of events the buffer holds before discarding any.
+    ///
+    /// Manual buffers only discard events in [`compact_before`](Self::compact_before), so this is
+    /// always `usize::MAX`.
+    pub fn capacity(&self) -> usize {
+        usize::MAX
+    }
+
+    /// Returns the order key of the newest buffered event.
+    pub fn head_key(&self) -> Option<OrderKey> {
+        self.buffer
+            .iter()
+            .rev()
+            .find_map(EventOrSnapshot::order_key)
+    }
+
+    /// Returns the order key of the newest buffered event, like [`head_key`](Self::head_key).
+    pub fn newest_key(&self) -> Option<OrderKey> {
+        self.head_key()
+    }
+
+    /// Returns the order key of the oldest buffered event.
+    pub fn oldest_key(&self) -> Option<OrderKey> {
+        self.events().next().map(S::Event::get_order_key)
+    }
+
+    /// Returns an iterator over the buffered events in key order.
+    pub fn events(&self) -> impl Iterator<Item = &S::Event> {
+        self.buffer.iter().filter_map(EventOrSnapshot::as_event)
+    }
+
+    /// Returns an iterator over the buffered events whose key lies in `range`, in key order.
+    pub fn events_in_range<R: RangeBounds<OrderKey>>(
+        &self,
+        range: R,
+    ) -> impl Iterator<Item = &S::Event> {
+        let keys = self.key_order();
+        self.events()
+            .filter(move |e| keys.contains(&range, &e.get_order_key()))
+    }
+
+    /// Folds all buffered events into the base state, keeping the current state.
+    pub fn clear(&mut self) {
+        let Some(head_key) = self.head_key() else {
+            return;
+        };
+        self.base_key = Some(head_key);
+        self.buffer.clear();
+        self.buffer
+            .push(EventOrSnapshot::Snapshot(self.current_state.clone()));
+        self.forget_folded();
+        self.observer.on_swap(&self.current_state);
+    }
+
+    /// Discards all buffered events and snapshots and restarts from the given state.
+    pub fn reset(&mut self, state: S) {
+        self.buffer.clear();
+        self.buffer.push(EventOrSnapshot::Snapshot(state.clone()));
+        self.current_state = state;
+        self.base_key = None;
+    }
+
+    /// Returns the current state.
+    ///
+    /// # Returns
+    ///
+    /// A clone of the current state after applying all events.
+    pub fn state(&self) -> S {
+        self.current_state.clone()
+    }
+
+    // Orders keys for one operation, relative to the oldest key the buffer compares against
+    fn key_order(&self) -> KeyOrder<OrderKey> {
+        KeyOrder::new(self.relative_order, || {
+            self.base_key.clone().or_else(|| self.oldest_key())
+        })
+    }
+
+    /// Returns the reconciliation metrics collected since the buffer was created or the stats
+    /// were last reset.
+    pub fn stats(&self) -> &LagBufferStats {
+        &self.stats.stats
+    }
+
+    /// Resets the reconciliation metrics.
+    pub fn reset_stats(&mut self) {
+        self.stats.stats = LagBufferStats::default();
+    }
+
+    /// Returns a reference to the base state, i.e. the state after all compacted events.
+    pub fn base_state(&self) -> &S {
+        self.buffer[0].as_snapshot()
+    }
+
+    /// Returns a reference to the current state.
+    ///
+    /// # Returns
+    ///
+    /// A reference to the current state after applying all events.
+    pub fn state_ref(&self) -> &S {
+        &self.current_state
+    }
+}
+
+impl<
+        S: State<OrderKey>,
+        const SIZE: usize,
+        OrderKey: KeyDistance + Clone,
+        Observer: LagBufferObserver<S, OrderKey>,
+    > ManualLagBuffer<S, SIZE, OrderKey, Observer>
+{
+    /// Measures the lateness of out-of-order events in the [`stats`](Self::stats).
+    ///
+    /// Lateness is the [`KeyDistance`] between the newest buffered key and the event's key.
+    pub fn with_lateness_stats(mut self) -> Self {
+        self.stats.lateness = Some(|head_key, key| head_key.distance_units(key));
+        self
+    }
+}
+
+impl<
+        S: State<OrderKey>,
+        const SIZE: usize,
+        OrderKey: Ord + Clone,
+        Observer: LagBufferObserver<S, OrderKey>,
+    > BatchBuffer<S, OrderKey> for ManualLagBuffer<S, SIZE, OrderKey, Observer>
+{
+    const NAME: &'static str = "manual";
+
+    fn tie_break(&self) -> &TieBreak<S, OrderKey> {
+        &self.tie_break
+    }
+
+    fn key_order(&self) -> KeyOrder<OrderKey> {
+        self.key_order()
+    }
+
+    fn stats_collector(&mut self) -> &mut StatsCollector<OrderKey> {
+        &mut self.stats
+    }
+
+    fn folded_key(&self) -> Option<OrderKey> {
+        self.base_key.clone()
+    }
+
+    // Inserts sorted out-of-order events at their positions and replays once, see `update_batch`
+    fn merge_batch(&mut self, events: Vec<S::Event>) -> UpdateOutcome {
+        let keys = self.key_order();
+        let from_key = events[0].get_order_key();
+        for event in &events {
+            self.observer.on_apply(event);
+        }
+
+        // Every event goes right before the first newer event, which is behind any snapshots
+        // taken after the last event that is not newer.
+        let mut events = events.into_iter().peekable();
+        let mut merged = Vec::with_capacity(self.buffer.len() + events.len());
+        let mut first_position = None;
+        for entry in self.buffer.drain(..) {
+            if let EventOrSnapshot::Event(buffered) = &entry {
+                while let Some(event) =
+                    events.next_if(|e| !self.tie_break.is_after(&keys, buffered, e))
+                {
+                    first_position.get_or_insert(merged.len());
+                    merged.push(EventOrSnapshot::Event(event));
+                }
+            }
+            merged.push(entry);
+        }
+        self.buffer = merged;
+        let first_position = first_position.unwrap_or(self.buffer.len());
+
+        let replayed = self.replay_from(first_position);
+        trace_span!(
+            _span,
+            "reconstruct",
+            insert_position = first_position,
+            replayed
+        );
+        self.observer.on_rollback(&from_key, replayed);
+
+        UpdateOutcome::Reordered { replayed }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{insert, MyState, Recorder};
+
+    #[test]
+    fn test_event_application_out_of_order() {
+        let mut buffer = ManualLagBuffer::<MyState, 8>::new(MyState::new());
+
+        buffer.update(insert(1));
+        buffer.update(insert(3));
+        assert_eq!(
+            buffer.update(insert(2)),
+            UpdateOutcome::Reordered { replayed: 3 }
+        );
+
+        assert_eq!(buffer.state_ref().data, vec![10, 20, 30]);
+        assert_eq!(buffer.state(), *buffer.state_ref());
+    }
+
+    #[test]
+    fn test_replay_from_snapshot() {
+        let mut buffer = ManualLagBuffer::<MyState, 8>::new(MyState::new());
+
+        buffer.update(insert(2));
+        buffer.update(insert(4));
+        buffer.take_snapshot();
+        buffer.update(insert(8));
+        buffer.update(insert(12));
+        buffer.take_snapshot();
+        buffer.update(insert(14));
+
+        // Only the events after the first snapshot are replayed.
+        assert_eq!(
+            buffer.update(insert(6)),
+            UpdateOutcome::Reordered { replayed: 4 }
+        );
+        assert_eq!(buffer.state_ref().data, vec![20, 40, 60, 80, 120, 140]);
+
+        // The second snapshot was refreshed, so replaying from it includes the late event.
+        assert_eq!(
+            buffer.update(insert(13)),
+            UpdateOutcome::Reordered { replayed: 2 }
+        );
+        assert_eq!(buffer.state_ref().data, vec![20, 40, 60, 80, 120, 130, 140]);
+    }
+
+    #[test]
+    fn test_compact_before() {
+        let mut buffer = ManualLagBuffer::<MyState, 8>::new(MyState::new());
+
+        for id in [1, 2, 4, 5] {
+            buffer.update(insert(id));
+        }
+        buffer.compact_before(&4);
+        assert_eq!(buffer.oldest_reconcilable_key(), Some(2));
+
+        // Only keys older than the newest compacted event are too late
+        assert_eq!(
+            buffer.update(insert(3)),
+            UpdateOutcome::Reordered { replayed: 3 }
+        );
+        assert_eq!(buffer.update(insert(1)), UpdateOutcome::RejectedTooLate);
+        assert_eq!(buffer.state_ref().data, vec![10, 20, 30, 40, 50]);
+    }
+
+    #[test]
+    fn test_observer() {
+        let mut buffer =
+            ManualLagBuffer::<MyState, 8>::new(MyState::new()).with_observer(Recorder::default());
+
+        buffer.update(insert(1));
+        buffer.update(insert(3));
+        buffer.update(insert(2));
+        buffer.compact_before(&2);
+        buffer.update(insert(0));
+
+        let observer = buffer.observer();
+        assert_eq!(observer.applied, vec![1, 3, 2]);
+        assert_eq!(observer.rollbacks, vec![(2, 3)]);
+        assert_eq!(observer.swaps, 1);
+        assert_eq!(observer.dropped, vec![0]);
+
+        buffer.clear();
+        buffer.clear();
+        assert_eq!(buffer.observer().swaps, 2);
     }
 }
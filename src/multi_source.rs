@@ -0,0 +1,230 @@
+use std::collections::{btree_map::Entry, BTreeMap};
+use std::marker::PhantomData;
+
+use crate::{Event, LagBufferState, State, UpdateOutcome};
+
+// How far a single source has been seen.
+struct SourceProgress<OrderKey> {
+    next_sequence: u64,
+    key: Option<OrderKey>,
+    ahead: BTreeMap<u64, OrderKey>,
+}
+
+impl<OrderKey> Default for SourceProgress<OrderKey> {
+    fn default() -> Self {
+        Self {
+            next_sequence: 0,
+            key: None,
+            ahead: BTreeMap::new(),
+        }
+    }
+}
+
+/// Merges the events of several sources into one lag buffer.
+///
+/// Every source numbers its events with its own sequence, starting at 0, and sends them with
+/// strictly increasing order keys. The buffer tracks for every source the order key of the newest
+/// event up to which the sequence has no gaps. The smallest of these keys is the low-watermark:
+/// no source can still send an event at or before it, so the state up to the watermark is final.
+/// Everything after it may still change when a slower source catches up.
+///
+/// Events are passed on to the wrapped buffer as soon as they arrive, which merges them in key
+/// order and reconciles the ones that arrive late.
+///
+/// # Type Parameters
+///
+/// - `S`: The type of the state, which must implement the [`State`](trait.State.html) trait.
+/// - `B`: The lag buffer the events are merged into.
+/// - `Source`: The type identifying a source, e.g. a peer id.
+/// - `OrderKey`: The type of the event's order key. Defaults to `usize`.
+pub struct MultiSourceLagBuffer<S, B, Source, OrderKey = usize>
+where
+    S: State<OrderKey>,
+    B: LagBufferState<S, OrderKey>,
+    Source: Ord,
+    OrderKey: Ord + Clone,
+{
+    buffer: B,
+    sources: BTreeMap<Source, SourceProgress<OrderKey>>,
+    _state: PhantomData<S>,
+}
+
+impl<S, B, Source, OrderKey> MultiSourceLagBuffer<S, B, Source, OrderKey>
+where
+    S: State<OrderKey>,
+    B: LagBufferState<S, OrderKey>,
+    Source: Ord,
+    OrderKey: Ord + Clone,
+{
+    /// Creates a new `MultiSourceLagBuffer` that merges into the given buffer.
+    pub fn new(buffer: B) -> Self {
+        Self {
+            buffer,
+            sources: BTreeMap::new(),
+            _state: PhantomData,
+        }
+    }
+
+    /// Registers the given sources, like [`add_source`](Self::add_source).
+    pub fn with_sources(mut self, sources: impl IntoIterator<Item = Source>) -> Self {
+        for source in sources {
+            self.add_source(source);
+        }
+        self
+    }
+
+    /// Registers a source that hasn't sent any events yet.
+    ///
+    /// The watermark doesn't advance until every registered source has sent its first event.
+    /// Sources also register themselves with their first event.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the source was already registered.
+    pub fn add_source(&mut self, source: Source) -> bool {
+        match self.sources.entry(source) {
+            Entry::Vacant(entry) => {
+                entry.insert(SourceProgress::default());
+                true
+            }
+            Entry::Occupied(_) => false,
+        }
+    }
+
+    /// Unregisters a source, e.g. a peer that left, so it no longer holds back the watermark.
+    ///
+    /// # Returns
+    ///
+    /// `false` if the source wasn't registered.
+    pub fn remove_source(&mut self, source: &Source) -> bool {
+        self.sources.remove(source).is_some()
+    }
+
+    /// Passes an event of a source to the buffer.
+    ///
+    /// # Arguments
+    ///
+    /// - `source`: The source that sent the event.
+    /// - `sequence`: The position of the event in the source's sequence.
+    /// - `event`: The event.
+    ///
+    /// # Returns
+    ///
+    /// [`UpdateOutcome::Duplicate`] if the source already sent an event with this sequence,
+    /// otherwise the outcome reported by the buffer.
+    pub fn update(&mut self, source: Source, sequence: u64, event: S::Event) -> UpdateOutcome {
+        let progress = self.sources.entry(source).or_default();
+        if sequence < progress.next_sequence || progress.ahead.contains_key(&sequence) {
+            return UpdateOutcome::Duplicate;
+        }
+
+        let key = event.get_order_key();
+        if sequence == progress.next_sequence {
+            progress.key = Some(key);
+            progress.next_sequence += 1;
+            // Close the gap with the events that arrived ahead of this one
+            while let Some(key) = progress.ahead.remove(&progress.next_sequence) {
+                progress.key = Some(key);
+                progress.next_sequence += 1;
+            }
+        } else {
+            progress.ahead.insert(sequence, key);
+        }
+
+        self.buffer.update(event)
+    }
+
+    /// Returns the last sequence up to which every event of the source has been seen.
+    ///
+    /// Returns `None` if the source is unknown or its first event hasn't arrived yet.
+    pub fn last_sequence(&self, source: &Source) -> Option<u64> {
+        self.sources.get(source)?.next_sequence.checked_sub(1)
+    }
+
+    /// Returns the order key up to which every event of the source has been seen.
+    pub fn source_key(&self, source: &Source) -> Option<OrderKey> {
+        self.sources.get(source)?.key.clone()
+    }
+
+    /// Returns the global low-watermark, the smallest [`source_key`](Self::source_key) of all
+    /// registered sources.
+    ///
+    /// Returns `None` while no source is registered or any source hasn't sent its first event.
+    pub fn watermark(&self) -> Option<OrderKey> {
+        self.sources
+            .values()
+            .map(|progress| progress.key.clone())
+            .min()
+            .flatten()
+    }
+
+    /// Returns `true` if the state up to the given key can no longer change.
+    pub fn is_final(&self, key: &OrderKey) -> bool {
+        self.watermark().is_some_and(|watermark| *key <= watermark)
+    }
+
+    /// Returns the state up to the [`watermark`](Self::watermark), which can no longer change.
+    ///
+    /// Returns `None` if there is no watermark yet or the buffer has already folded events
+    /// after it.
+    pub fn final_state(&self) -> Option<S> {
+        self.buffer.state_at(&self.watermark()?)
+    }
+
+    /// Returns the state after applying all events received so far, final or not.
+    pub fn state(&self) -> S {
+        self.buffer.state()
+    }
+
+    /// Returns the buffer the events are merged into.
+    pub fn buffer(&self) -> &B {
+        &self.buffer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{insert, MyState};
+    use crate::DoubleBufferedLagBuffer;
+
+    #[test]
+    fn test_watermark() {
+        let buffer = DoubleBufferedLagBuffer::<MyState, 16>::new(MyState::new());
+        let mut merged = MultiSourceLagBuffer::new(buffer).with_sources(["a", "b"]);
+
+        merged.update("a", 0, insert(1));
+        merged.update("a", 1, insert(4));
+        assert_eq!(merged.watermark(), None);
+        assert_eq!(merged.final_state(), None);
+
+        merged.update("b", 0, insert(2));
+        assert_eq!(merged.watermark(), Some(2));
+        assert!(merged.is_final(&2));
+        assert!(!merged.is_final(&3));
+
+        // Arrives ahead of b's sequence 1, so b's progress doesn't move.
+        merged.update("b", 2, insert(6));
+        assert_eq!(merged.last_sequence(&"b"), Some(0));
+        assert_eq!(merged.watermark(), Some(2));
+
+        assert_eq!(
+            merged.update("b", 1, insert(3)),
+            UpdateOutcome::Reordered { replayed: 5 }
+        );
+        assert_eq!(merged.last_sequence(&"b"), Some(2));
+        assert_eq!(merged.source_key(&"b"), Some(6));
+        assert_eq!(merged.watermark(), Some(4));
+        assert_eq!(merged.final_state().unwrap().data, vec![10, 20, 30, 40]);
+        assert_eq!(merged.state().data, vec![10, 20, 30, 40, 60]);
+
+        assert_eq!(merged.update("a", 1, insert(4)), UpdateOutcome::Duplicate);
+        assert_eq!(merged.update("b", 2, insert(6)), UpdateOutcome::Duplicate);
+
+        // A source that leaves no longer holds the watermark back.
+        merged.add_source("c");
+        assert_eq!(merged.watermark(), None);
+        assert!(merged.remove_source(&"c"));
+        assert_eq!(merged.watermark(), Some(4));
+    }
+}
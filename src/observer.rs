@@ -0,0 +1,78 @@
+use crate::State;
+
+/// Receives notifications about what a lag buffer does with its events.
+///
+/// Every method has an empty default, so an observer only implements the callbacks it needs.
+/// Buffers use the `()` observer unless one is set with `with_observer`. Its calls are empty and
+/// compile away, so buffers without an observer pay nothing for the hooks.
+///
+/// # Type Parameters
+///
+/// - `S`: The type of the state, which must implement the [`State`](trait.State.html) trait.
+/// - `OrderKey`: The type of the event's order key. Defaults to `usize`.
+///
+/// # Examples
+///
+/// ```rust
+/// use lagbuffer::{DoubleBufferedLagBuffer, Event, LagBufferObserver, State};
+///
+/// #[derive(Clone)]
+/// struct Counter(i32);
+///
+/// #[derive(Clone)]
+/// struct Add(usize, i32);
+///
+/// impl Event<usize> for Add {
+///     fn get_order_key(&self) -> usize {
+///         self.0
+///     }
+/// }
+///
+/// impl State<usize> for Counter {
+///     type Event = Add;
+///
+///     fn apply(&mut self, event: &Add) {
+///         self.0 += event.1;
+///     }
+/// }
+///
+/// #[derive(Default)]
+/// struct Rollbacks(usize);
+///
+/// impl LagBufferObserver<Counter> for Rollbacks {
+///     fn on_rollback(&mut self, _from_key: &usize, replayed: usize) {
+///         self.0 += replayed;
+///     }
+/// }
+///
+/// let mut buffer =
+///     DoubleBufferedLagBuffer::<Counter, 8>::new(Counter(0)).with_observer(Rollbacks::default());
+/// buffer.update(Add(1, 1));
+/// buffer.update(Add(3, 3));
+/// buffer.update(Add(2, 2));
+/// assert_eq!(buffer.observer().0, 3);
+/// ```
+pub trait LagBufferObserver<S: State<OrderKey>, OrderKey: Ord = usize> {
+    /// Called when an event is accepted by the buffer.
+    fn on_apply(&mut self, _event: &S::Event) {}
+
+    /// Called after the current state was reconstructed for an out-of-order event.
+    ///
+    /// `from_key` is the key of the out-of-order event and `replayed` the number of events that
+    /// were applied again.
+    fn on_rollback(&mut self, _from_key: &OrderKey, _replayed: usize) {}
+
+    /// Called after the buffer moved its base state forward, i.e. older events can no longer
+    /// be reconciled.
+    ///
+    /// Every path that folds events into the base or moves its key calls it: swaps, batches that
+    /// don't fit, watermarks, resizing, compaction and `clear`. `reset` replaces the base instead
+    /// and doesn't call it.
+    fn on_swap(&mut self, _new_base: &S) {}
+
+    /// Called when an event is discarded because it is too old to be reconciled, before it is
+    /// handed to the [`LatePolicy`](crate::LatePolicy).
+    fn on_drop(&mut self, _event: &S::Event) {}
+}
+
+impl<S: State<OrderKey>, OrderKey: Ord> LagBufferObserver<S, OrderKey> for () {}
@@ -0,0 +1,335 @@
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+
+use crate::{Event, LagBufferState, LagBufferStateRef, State, UpdateOutcome};
+
+/// The result of confirming a predicted event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Confirmation {
+    /// How the confirmed buffer handled the authoritative event.
+    pub outcome: UpdateOutcome,
+    /// `true` if the predicted state changed, i.e. the prediction was wrong.
+    pub diverged: bool,
+}
+
+/// Client-side prediction on top of a lag buffer.
+///
+/// Local events are applied immediately as predictions. Authoritative events go into the
+/// confirmed buffer, which reconciles them like any other lag buffer. The predicted state is
+/// re-derived from the confirmed state and the remaining predictions whenever a prediction is
+/// confirmed or rejected.
+///
+/// Predictions that fall behind the oldest reconcilable key of the confirmed buffer are dropped
+/// when the predicted state is re-derived, since the confirmed buffer can't place them anymore.
+///
+/// # Type Parameters
+///
+/// - `S`: The type of the state, which must implement the [`State`](trait.State.html) trait.
+/// - `B`: The lag buffer holding the confirmed events.
+/// - `OrderKey`: The type of the event's order key. Defaults to `usize`.
+///
+/// # Examples
+///
+/// ```rust
+/// use lagbuffer::{DoubleBufferedLagBuffer, Event, PredictionBuffer, State};
+///
+/// #[derive(Clone, Debug, PartialEq)]
+/// struct Position(i32);
+///
+/// #[derive(Clone)]
+/// struct Move {
+///     tick: usize,
+///     delta: i32,
+/// }
+///
+/// impl Event<usize> for Move {
+///     fn get_order_key(&self) -> usize {
+///         self.tick
+///     }
+/// }
+///
+/// impl State<usize> for Position {
+///     type Event = Move;
+///
+///     fn apply(&mut self, event: &Move) {
+///         self.0 += event.delta;
+///     }
+/// }
+///
+/// let confirmed = DoubleBufferedLagBuffer::<Position, 16>::new(Position(0));
+/// let mut prediction = PredictionBuffer::new(confirmed);
+///
+/// prediction.predict(Move { tick: 1, delta: 1 });
+/// prediction.predict(Move { tick: 2, delta: 1 });
+/// assert_eq!(*prediction.predicted_state(), Position(2));
+///
+/// // The server only allowed half of the second move.
+/// prediction.confirm(&1, Move { tick: 1, delta: 1 });
+/// let confirmation = prediction.confirm(&2, Move { tick: 2, delta: 0 });
+/// assert!(confirmation.diverged);
+/// assert_eq!(*prediction.predicted_state(), Position(1));
+/// ```
+pub struct PredictionBuffer<S, B, OrderKey = usize>
+where
+    S: State<OrderKey>,
+    B: LagBufferState<S, OrderKey> + LagBufferStateRef<S, OrderKey>,
+    OrderKey: Ord + Clone,
+{
+    confirmed: B,
+    predictions: VecDeque<S::Event>,
+    predicted_state: S,
+    _order_key: PhantomData<OrderKey>,
+}
+
+impl<S, B, OrderKey> PredictionBuffer<S, B, OrderKey>
+where
+    S: State<OrderKey> + PartialEq,
+    B: LagBufferState<S, OrderKey> + LagBufferStateRef<S, OrderKey>,
+    OrderKey: Ord + Clone,
+{
+    /// Creates a new `PredictionBuffer` on top of the given confirmed buffer.
+    pub fn new(confirmed: B) -> Self {
+        Self {
+            predicted_state: confirmed.state(),
+            confirmed,
+            predictions: VecDeque::new(),
+            _order_key: PhantomData,
+        }
+    }
+
+    /// Applies a local event as a prediction.
+    ///
+    /// # Arguments
+    ///
+    /// - `event`: The predicted event. It stays a prediction until it is confirmed or rejected.
+    pub fn predict(&mut self, event: S::Event) {
+        let key = event.get_order_key();
+        let confirmed = &self.confirmed;
+        let newest = self
+            .predictions
+            .back()
+            .map(S::Event::get_order_key)
+            .into_iter()
+            .chain(confirmed.head_key())
+            .chain(confirmed.base_key())
+            .all(|newer_key| confirmed.compare_keys(&newer_key, &key).is_le());
+
+        let insert_position = self.predictions.partition_point(|predicted| {
+            confirmed
+                .compare_keys(&predicted.get_order_key(), &key)
+                .is_le()
+        });
+        self.predictions.insert(insert_position, event);
+
+        if newest {
+            if let Some(event) = self.predictions.back() {
+                self.predicted_state.apply(event);
+            }
+        } else {
+            self.rederive();
+        }
+    }
+
+    /// Replaces the prediction with the given key by its authoritative version.
+    ///
+    /// Authoritative events without a matching prediction, e.g. events of other players, can be
+    /// confirmed the same way.
+    ///
+    /// # Arguments
+    ///
+    /// - `key`: The order key of the prediction being confirmed.
+    /// - `authoritative_event`: The authoritative version of the event.
+    pub fn confirm(&mut self, key: &OrderKey, authoritative_event: S::Event) -> Confirmation {
+        self.remove_prediction(key);
+        let outcome = self.confirmed.update(authoritative_event);
+        Confirmation {
+            outcome,
+            diverged: self.rederive(),
+        }
+    }
+
+    /// Discards the prediction with the given key.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the predicted state changed.
+    pub fn reject(&mut self, key: &OrderKey) -> bool {
+        if self.remove_prediction(key) {
+            self.rederive()
+        } else {
+            false
+        }
+    }
+
+    /// Returns the state after applying the confirmed events and all pending predictions.
+    pub fn predicted_state(&self) -> &S {
+        &self.predicted_state
+    }
+
+    /// Returns the state after applying only the confirmed events.
+    pub fn confirmed_state(&self) -> &S {
+        self.confirmed.state_ref()
+    }
+
+    /// Returns the buffer holding the confirmed events.
+    pub fn confirmed(&self) -> &B {
+        &self.confirmed
+    }
+
+    /// Returns an iterator over the pending predictions in key order.
+    pub fn predictions(&self) -> impl Iterator<Item = &S::Event> {
+        self.predictions.iter()
+    }
+
+    fn remove_prediction(&mut self, key: &OrderKey) -> bool {
+        match self
+            .predictions
+            .iter()
+            .position(|predicted| predicted.get_order_key() == *key)
+        {
+            Some(position) => self.predictions.remove(position).is_some(),
+            None => false,
+        }
+    }
+
+    // Rebuilds the predicted state and reports whether it changed.
+    fn rederive(&mut self) -> bool {
+        // Predictions the confirmed buffer can't reconstruct a state for are too late
+        let mut state = None;
+        while let Some(oldest) = self.predictions.front() {
+            state = self.confirmed.state_at(&oldest.get_order_key());
+            if state.is_some() {
+                break;
+            }
+            self.predictions.pop_front();
+        }
+
+        let predicted_state = match (self.predictions.front(), state) {
+            (Some(oldest), Some(mut state)) => {
+                // Interleave the predictions with the confirmed events that are newer
+                let confirmed = &self.confirmed;
+                let oldest_key = oldest.get_order_key();
+                let mut newer = confirmed
+                    .events()
+                    .filter(|e| {
+                        confirmed
+                            .compare_keys(&e.get_order_key(), &oldest_key)
+                            .is_gt()
+                    })
+                    .peekable();
+                for predicted in &self.predictions {
+                    let predicted_key = predicted.get_order_key();
+                    while let Some(e) = newer.next_if(|e| {
+                        confirmed
+                            .compare_keys(&e.get_order_key(), &predicted_key)
+                            .is_le()
+                    }) {
+                        state.apply(e);
+                    }
+                    state.apply(predicted);
+                }
+                for e in newer {
+                    state.apply(e);
+                }
+                state
+            }
+            _ => self.confirmed.state(),
+        };
+
+        let diverged = predicted_state != self.predicted_state;
+        self.predicted_state = predicted_state;
+        diverged
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{insert, insert_value, MyState};
+    use crate::{DoubleBufferedLagBuffer, WrappingSeq};
+
+    #[test]
+    fn test_confirm_and_reject() {
+        let confirmed = DoubleBufferedLagBuffer::<MyState, 8>::new(MyState::new());
+        let mut buffer = PredictionBuffer::new(confirmed);
+
+        buffer.predict(insert(1));
+        buffer.predict(insert(2));
+        buffer.predict(insert(3));
+        assert_eq!(buffer.predicted_state().data, vec![10, 20, 30]);
+
+        // Matching confirmation, nothing changes.
+        let confirmation = buffer.confirm(&1, insert(1));
+        assert_eq!(confirmation.outcome, UpdateOutcome::AppliedInOrder);
+        assert!(!confirmation.diverged);
+
+        // The server disagrees about the second event.
+        assert!(buffer.confirm(&2, insert_value(2, 25)).diverged);
+        assert_eq!(buffer.predicted_state().data, vec![10, 25, 30]);
+        assert_eq!(buffer.confirmed_state().data, vec![10, 25]);
+
+        assert!(buffer.reject(&3));
+        assert!(!buffer.reject(&3));
+        assert_eq!(buffer.predicted_state(), buffer.confirmed_state());
+        assert_eq!(buffer.predictions().count(), 0);
+    }
+
+    #[test]
+    fn test_interleave_with_confirmed_events() {
+        let confirmed = DoubleBufferedLagBuffer::<MyState, 8>::new(MyState::new());
+        let mut buffer = PredictionBuffer::new(confirmed);
+
+        buffer.predict(insert(2));
+        buffer.predict(insert(4));
+
+        // An authoritative event of another player lands between the predictions.
+        assert!(buffer.confirm(&3, insert(3)).diverged);
+        assert_eq!(buffer.predicted_state().data, vec![20, 30, 40]);
+
+        // A late prediction is slotted in at its key.
+        buffer.predict(insert(1));
+        assert_eq!(buffer.predicted_state().data, vec![10, 20, 30, 40]);
+    }
+
+    #[test]
+    fn test_prediction_behind_base_key() {
+        let confirmed = DoubleBufferedLagBuffer::<MyState, 2>::new(MyState::new());
+        let mut buffer = PredictionBuffer::new(confirmed);
+
+        buffer.predict(insert(1));
+        for id in 2..7 {
+            buffer.confirm(&id, insert(id));
+        }
+
+        // The confirmed buffer folded events newer than the prediction, so it was dropped.
+        assert!(buffer
+            .confirmed()
+            .oldest_reconcilable_key()
+            .is_some_and(|base_key| base_key > 1));
+        assert_eq!(buffer.predictions().count(), 0);
+        assert_eq!(buffer.predicted_state().data, vec![20, 30, 40, 50, 60]);
+        assert_eq!(buffer.predicted_state(), buffer.confirmed_state());
+    }
+
+    #[test]
+    fn test_prediction_across_wrap() {
+        let seq = |seq: u16| insert_value(WrappingSeq(seq), seq as i32);
+        let confirmed =
+            DoubleBufferedLagBuffer::<MyState, 4, _>::new(MyState::new()).with_relative_keys();
+        let mut buffer = PredictionBuffer::new(confirmed);
+
+        for id in (65533..=u16::MAX).chain(0..=6) {
+            buffer.confirm(&WrappingSeq(id), seq(id));
+        }
+        let confirmed = buffer.confirmed_state().clone();
+
+        // 65534 was folded before the wrap, so the prediction is dropped.
+        buffer.predict(seq(65534));
+        assert_eq!(buffer.predictions().count(), 0);
+        assert_eq!(*buffer.predicted_state(), confirmed);
+
+        buffer.predict(seq(8));
+        buffer.confirm(&WrappingSeq(7), seq(7));
+        assert_eq!(buffer.predicted_state().data[9..], [6, 7, 8]);
+    }
+}
@@ -0,0 +1,611 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::ops::RangeBounds;
+
+use crate::batch::{self, merge_by_key, BatchBuffer};
+use crate::key::{KeyOrder, RelativeCmp};
+use crate::stats::StatsCollector;
+use crate::trace::KeyFormat;
+use crate::{
+    AmendOutcome, BatchOutcome, Event, KeyDistance, LagBufferStats, LatePolicy, RelativeOrd,
+    ReversibleState, TieBreak, TooLateError, UpdateOutcome,
+};
+
+/// A lag buffer that reconciles late events by rolling the state back instead of cloning it.
+///
+/// The other buffers clone a base state and replay every event after it, which gets expensive for
+/// large states. `ReversibleLagBuffer` only keeps the current state: a late event is handled by
+/// reverting the newer events, applying the late event and re-applying the newer events.
+///
+/// # Type Parameters
+///
+/// - `S`: The type of the state, which must implement the [`ReversibleState`](trait.ReversibleState.html) trait.
+/// - `SIZE`: The number of events the buffer retains for reconciliation.
+/// - `OrderKey`: The type of the event's order key. Defaults to `usize`.
+pub struct ReversibleLagBuffer<
+    S: ReversibleState<OrderKey>,
+    const SIZE: usize,
+    OrderKey: Ord = usize,
+> {
+    events: VecDeque<S::Event>,
+    current_state: S,
+    base_key: Option<OrderKey>,
+    late_policy: LatePolicy<S, OrderKey>,
+    tie_break: TieBreak<S, OrderKey>,
+    stats: StatsCollector<OrderKey>,
+    key_format: Option<KeyFormat<OrderKey>>,
+    relative_order: Option<RelativeCmp<OrderKey>>,
+}
+
+impl<S: ReversibleState<OrderKey>, const SIZE: usize, OrderKey: Ord + Clone>
+    ReversibleLagBuffer<S, SIZE, OrderKey>
+{
+    /// Creates a new `ReversibleLagBuffer` with the given initial state.
+    ///
+    /// Fails to compile if `SIZE` is 0.
+    pub fn new(initial_state: S) -> Self {
+        const { assert!(SIZE > 0, "SIZE must be greater than 0") };
+        Self {
+            events: VecDeque::with_capacity(SIZE + 1),
+            current_state: initial_state,
+            base_key: None,
+            late_policy: LatePolicy::default(),
+            tie_break: TieBreak::default(),
+            stats: StatsCollector::default(),
+            key_format: None,
+            relative_order: None,
+        }
+    }
+
+    /// Sets the policy for events that are too old to be reconciled.
+    pub fn with_late_policy(mut self, late_policy: LatePolicy<S, OrderKey>) -> Self {
+        self.late_policy = late_policy;
+        self
+    }
+
+    /// Sets how events with equal order keys are ordered.
+    pub fn with_tie_break(mut self, tie_break: TieBreak<S, OrderKey>) -> Self {
+        self.tie_break = tie_break;
+        self
+    }
+
+    /// Records the order key of every update on its trace span.
+    ///
+    /// Only has an effect with the `tracing` feature.
+    pub fn with_traced_keys(mut self) -> Self
+    where
+        OrderKey: fmt::Debug,
+    {
+        self.key_format = Some(|key| format!("{key:?}"));
+        self
+    }
+
+    /// Compares order keys with [`RelativeOrd::cmp_relative`], anchored at the last folded key.
+    ///
+    /// Use this for keys like [`WrappingSeq`](crate::WrappingSeq), whose `Ord` doesn't follow
+    /// the order in which the events were produced.
+    pub fn with_relative_keys(mut self) -> Self
+    where
+        OrderKey: RelativeOrd,
+    {
+        self.relative_order = Some(OrderKey::cmp_relative);
+        self
+    }
+
+    /// Updates the buffer with a new event.
+    ///
+    /// # Behavior
+    ///
+    /// - **In-Order Event**: The event is applied directly to the current state.
+    /// - **Out-of-Order Event**: All newer events are reverted newest first, the event is applied
+    ///   and the newer events are applied again.
+    /// - **Retention**: Once more than `SIZE` events are retained, the oldest one is forgotten and
+    ///   can no longer be reverted.
+    pub fn update(&mut self, event: S::Event) -> UpdateOutcome {
+        self.try_update(event)
+            .unwrap_or(UpdateOutcome::RejectedTooLate)
+    }
+
+    /// Updates the buffer with a new event, like [`update`](Self::update).
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`TooLateError`] holding the event if it is too old to be reconciled and the
+    /// buffer uses [`LatePolicy::Error`].
+    pub fn try_update(&mut self, event: S::Event) -> Result<UpdateOutcome, TooLateError<S::Event>> {
+        trace_span!(
+            span,
+            "update",
+            buffer = "reversible",
+            len = self.len(),
+            key = tracing::field::Empty,
+            outcome = tracing::field::Empty
+        );
+        let key = event.get_order_key();
+        trace_key!(span, self.key_format, &key);
+        let head_key = self.head_key();
+        let result = self.reconcile(event);
+        trace_record!(
+            span,
+            "outcome",
+            tracing::field::debug(&result.as_ref().ok())
+        );
+        self.stats.record(&result, head_key.as_ref(), &key);
+        result
+    }
+
+    // Places the event and reconciles the state, see `update`
+    fn reconcile(&mut self, event: S::Event) -> Result<UpdateOutcome, TooLateError<S::Event>> {
+        let keys = self.key_order();
+        let key = event.get_order_key();
+
+        let too_late = self
+            .base_key
+            .as_ref()
+            .is_some_and(|base_key| keys.lt(&key, base_key));
+        if too_late {
+            return LatePolicy::reject(self, |buffer| &mut buffer.late_policy, event);
+        }
+
+        // Events go after every retained event that doesn't sort after them
+        let insert_position = self
+            .events
+            .partition_point(|buffered| self.tie_break.is_after(&keys, buffered, &event));
+        let previous_key = match insert_position.checked_sub(1) {
+            Some(previous) => Some(self.events[previous].get_order_key()),
+            None => self.base_key.clone(),
+        };
+        if self.tie_break.is_duplicate(previous_key.as_ref(), &key) {
+            return Ok(UpdateOutcome::Duplicate);
+        }
+
+        let in_order = insert_position == self.events.len();
+
+        let mut outcome = if in_order {
+            self.current_state.apply(&event);
+            self.events.push_back(event);
+            UpdateOutcome::AppliedInOrder
+        } else {
+            trace_span!(
+                _span,
+                "reconstruct",
+                insert_position,
+                replayed = self.events.len() + 1 - insert_position
+            );
+
+            // Roll back to the insertion point, then roll forward including the late event
+            for newer in self.events.range(insert_position..).rev() {
+                self.current_state.unapply(newer);
+            }
+            self.current_state.apply(&event);
+            for newer in self.events.range(insert_position..) {
+                self.current_state.apply(newer);
+            }
+            self.events.insert(insert_position, event);
+
+            UpdateOutcome::Reordered {
+                replayed: self.events.len() - insert_position,
+            }
+        };
+
+        // Forget the oldest event, it can't be reverted anymore
+        if self.events.len() > SIZE {
+            self.base_key = self.events.pop_front().map(|e| e.get_order_key());
+            self.forget_folded();
+            self.stats.record_swap();
+            outcome = UpdateOutcome::Swapped {
+                replayed: outcome.replayed(),
+            };
+        }
+
+        Ok(outcome)
+    }
+
+    /// Updates the buffer with a batch of events, reconciling all of them with a single replay.
+    ///
+    /// The batch is sorted by key first. Events that are too late are handed to the
+    /// [`LatePolicy`] and events that are not older than the newest retained event are applied
+    /// in order, both exactly like in [`update`](Self::update). For all other events, the events
+    /// newer than the oldest of them are reverted once, and the merged events are applied again.
+    pub fn update_batch(&mut self, events: impl IntoIterator<Item = S::Event>) -> BatchOutcome {
+        batch::update_batch(self, events)
+    }
+
+    /// Removes a retained event and reconstructs the current state as though it never happened.
+    ///
+    /// The event and all newer events are reverted, then the newer events are applied again.
+    /// Events that were already forgotten can't be retracted.
+    ///
+    /// # Arguments
+    ///
+    /// - `key`: The order key of the event to remove. If several events share the key, the oldest
+    ///   one is removed.
+    ///
+    /// # Returns
+    ///
+    /// The removed event, or `None` if no retained event has the given key.
+    pub fn retract(&mut self, key: &OrderKey) -> Option<S::Event> {
+        let position = self
+            .events
+            .iter()
+            .position(|buffered| buffered.get_order_key() == *key)?;
+        trace_span!(
+            _span,
+            "reconstruct",
+            position,
+            replayed = self.events.len() - position - 1
+        );
+
+        for newer in self.events.range(position..).rev() {
+            self.current_state.unapply(newer);
+        }
+        let event = self.events.remove(position);
+        for newer in self.events.range(position..) {
+            self.current_state.apply(newer);
+        }
+        event
+    }
+
+    /// Replaces the retained event with the same order key and reconstructs the current state.
+    ///
+    /// The event and all newer events are reverted, then the corrected event and the newer events
+    /// are applied. If no retained event has the key, the event is handled like in
+    /// [`try_update`](Self::try_update).
+    ///
+    /// # Arguments
+    ///
+    /// - `event`: The corrected event. If several retained events share its key, the oldest one is
+    ///   replaced. With [`TieBreak::SecondaryKey`], the corrected event is moved among them to
+    ///   where the function orders it.
+    ///
+    /// # Errors
+    ///
+    /// Returns the [`TooLateError`] of [`try_update`](Self::try_update) for an event that is
+    /// handled like there.
+    pub fn amend(
+        &mut self,
+        event: S::Event,
+    ) -> Result<AmendOutcome<S::Event>, TooLateError<S::Event>> {
+        let keys = self.key_order();
+        let key = event.get_order_key();
+        let Some(position) = self
+            .events
+            .iter()
+            .position(|buffered| buffered.get_order_key() == key)
+        else {
+            return self.try_update(event).map(AmendOutcome::Updated);
+        };
+        let replayed = self.events.len() - position;
+        trace_span!(_span, "reconstruct", position, replayed);
+
+        for newer in self.events.range(position..).rev() {
+            self.current_state.unapply(newer);
+        }
+        let amended = std::mem::replace(&mut self.events[position], event);
+        let target = self.tie_break.reposition(&keys, &self.events, position);
+        for index in position..target {
+            self.events.swap(index, index + 1);
+        }
+        for newer in self.events.range(position..) {
+            self.current_state.apply(newer);
+        }
+        Ok(AmendOutcome::Replaced {
+            event: amended,
+            replayed,
+        })
+    }
+
+    /// Reconstructs the state as of the given order key without touching the current state.
+    ///
+    /// The result contains every retained event whose key is not newer than `key`.
+    ///
+    /// # Returns
+    ///
+    /// `None` if `key` is older than the [`oldest_reconcilable_key`](Self::oldest_reconcilable_key).
+    ///
+    /// Rolls a clone of the current state back instead of replaying from a base state.
+    pub fn state_at(&self, key: &OrderKey) -> Option<S> {
+        let keys = self.key_order();
+        if self
+            .base_key
+            .as_ref()
+            .is_some_and(|base_key| keys.lt(key, base_key))
+        {
+            return None;
+        }
+        let end = self
+            .events
+            .partition_point(|e| keys.le(&e.get_order_key(), key));
+        let mut state = self.current_state.clone();
+        for newer in self.events.range(end..).rev() {
+            state.unapply(newer);
+        }
+        Some(state)
+    }
+
+    /// Returns the oldest order key that can still be reconciled.
+    ///
+    /// Events with an older key are handed to the [`LatePolicy`]. Returns `None` while no
+    /// events have been forgotten, i.e. while every key can be reconciled.
+    pub fn oldest_reconcilable_key(&self) -> Option<OrderKey> {
+        self.base_key.clone()
+    }
+
+    /// Returns the number of retained events.
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Returns `true` if no events are retained.
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Returns the number of events the buffer retains for reconciliation.
+    pub fn capacity(&self) -> usize {
+        SIZE
+    }
+
+    /// Returns the order key of the newest retained event.
+    pub fn head_key(&self) -> Option<OrderKey> {
+        self.events.back().map(S::Event::get_order_key)
+    }
+
+    /// Returns the order key of the newest retained event, like [`head_key`](Self::head_key).
+    pub fn newest_key(&self) -> Option<OrderKey> {
+        self.head_key()
+    }
+
+    /// Returns the order key of the oldest retained event.
+    pub fn oldest_key(&self) -> Option<OrderKey> {
+        self.events().next().map(S::Event::get_order_key)
+    }
+
+    /// Returns an iterator over the retained events in key order.
+    pub fn events(&self) -> impl Iterator<Item = &S::Event> {
+        self.events.iter()
+    }
+
+    /// Returns an iterator over the retained events whose key lies in `range`, in key order.
+    pub fn events_in_range<R: RangeBounds<OrderKey>>(
+        &self,
+        range: R,
+    ) -> impl Iterator<Item = &S::Event> {
+        let keys = self.key_order();
+        self.events()
+            .filter(move |e| keys.contains(&range, &e.get_order_key()))
+    }
+
+    /// Forgets all retained events, keeping the current state.
+    pub fn clear(&mut self) {
+        if let Some(head_key) = self.head_key() {
+            self.base_key = Some(head_key);
+        }
+        self.events.clear();
+        self.forget_folded();
+    }
+
+    /// Discards all retained events and restarts from the given state.
+    pub fn reset(&mut self, state: S) {
+        self.events.clear();
+        self.current_state = state;
+        self.base_key = None;
+    }
+
+    // Lets the state drop what it tracks for events older than the base key
+    fn forget_folded(&mut self) {
+        let keys = self.key_order();
+        if let Some(base_key) = &self.base_key {
+            self.current_state
+                .forget(&|key: &OrderKey| keys.lt(key, base_key));
+        }
+    }
+
+    // Orders keys for one operation, relative to the oldest key the buffer compares against
+    fn key_order(&self) -> KeyOrder<OrderKey> {
+        KeyOrder::new(self.relative_order, || {
+            self.base_key.clone().or_else(|| self.oldest_key())
+        })
+    }
+
+    /// Returns the reconciliation metrics collected since the buffer was created or the stats
+    /// were last reset.
+    pub fn stats(&self) -> &LagBufferStats {
+        &self.stats.stats
+    }
+
+    /// Resets the reconciliation metrics.
+    pub fn reset_stats(&mut self) {
+        self.stats.stats = LagBufferStats::default();
+    }
+
+    /// Returns a reference to the current state.
+    ///
+    /// # Returns
+    ///
+    /// A reference to the current state after applying all events.
+    pub fn state_ref(&self) -> &S {
+        &self.current_state
+    }
+}
+
+impl<S: ReversibleState<OrderKey>, const SIZE: usize, OrderKey: KeyDistance + Clone>
+    ReversibleLagBuffer<S, SIZE, OrderKey>
+{
+    /// Measures the lateness of out-of-order events in the [`stats`](Self::stats).
+    ///
+    /// Lateness is the [`KeyDistance`] between the newest buffered key and the event's key.
+    pub fn with_lateness_stats(mut self) -> Self {
+        self.stats.lateness = Some(|head_key, key| head_key.distance_units(key));
+        self
+    }
+}
+
+impl<S: ReversibleState<OrderKey>, const SIZE: usize, OrderKey: Ord + Clone>
+    BatchBuffer<S, OrderKey> for ReversibleLagBuffer<S, SIZE, OrderKey>
+{
+    const NAME: &'static str = "reversible";
+
+    fn tie_break(&self) -> &TieBreak<S, OrderKey> {
+        &self.tie_break
+    }
+
+    fn key_order(&self) -> KeyOrder<OrderKey> {
+        self.key_order()
+    }
+
+    fn stats_collector(&mut self) -> &mut StatsCollector<OrderKey> {
+        &mut self.stats
+    }
+
+    fn folded_key(&self) -> Option<OrderKey> {
+        self.base_key.clone()
+    }
+
+    // Rolls back to the oldest out-of-order event and rolls forward through the merged events,
+    // see `update_batch`
+    fn merge_batch(&mut self, events: Vec<S::Event>) -> UpdateOutcome {
+        let keys = self.key_order();
+        let insert_position = self
+            .events
+            .partition_point(|buffered| self.tie_break.is_after(&keys, buffered, &events[0]));
+        for newer in self.events.range(insert_position..).rev() {
+            self.current_state.unapply(newer);
+        }
+
+        let newer: Vec<_> = self.events.drain(insert_position..).collect();
+        for event in merge_by_key(newer, events, &self.tie_break, &keys) {
+            self.current_state.apply(&event);
+            self.events.push_back(event);
+        }
+        let replayed = self.events.len() - insert_position;
+        trace_span!(_span, "reconstruct", insert_position, replayed);
+
+        // Forget the oldest events, they can't be reverted anymore
+        if self.events.len() <= SIZE {
+            return UpdateOutcome::Reordered { replayed };
+        }
+        while self.events.len() > SIZE {
+            self.base_key = self.events.pop_front().map(|e| e.get_order_key());
+        }
+        self.forget_folded();
+        self.stats.record_swap();
+        UpdateOutcome::Swapped { replayed }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{insert, insert_value, MyEvent, MyState};
+
+    #[test]
+    fn test_event_application_out_of_order() {
+        let mut buffer = ReversibleLagBuffer::<MyState, 8>::new(MyState::new());
+
+        buffer.update(insert(1));
+        buffer.update(insert(3));
+        buffer.update(insert(4));
+        assert_eq!(
+            buffer.update(insert(2)),
+            UpdateOutcome::Reordered { replayed: 3 }
+        );
+
+        // Only the two newer events were rolled back.
+        assert_eq!(buffer.state_ref().data, vec![10, 20, 30, 40]);
+        assert_eq!(buffer.state_ref().reverted, 2);
+    }
+
+    #[test]
+    fn test_forget_oldest_event() {
+        let mut buffer = ReversibleLagBuffer::<MyState, 2>::new(MyState::new());
+
+        for id in [1, 3, 4] {
+            buffer.update(insert(id));
+        }
+        assert_eq!(buffer.oldest_reconcilable_key(), Some(1));
+
+        assert_eq!(
+            buffer.update(insert(2)),
+            UpdateOutcome::Swapped { replayed: 3 }
+        );
+        assert_eq!(buffer.oldest_reconcilable_key(), Some(2));
+        assert_eq!(buffer.update(insert(1)), UpdateOutcome::RejectedTooLate);
+        assert_eq!(buffer.state_ref().data, vec![10, 20, 30, 40]);
+    }
+
+    #[test]
+    fn test_state_at() {
+        let mut buffer = ReversibleLagBuffer::<MyState, 4>::new(MyState::new());
+
+        for id in [1, 2, 4, 5, 6] {
+            buffer.update(insert(id));
+        }
+
+        assert_eq!(buffer.state_at(&4).unwrap().data, vec![10, 20, 40]);
+        assert_eq!(buffer.state_at(&3).unwrap().data, vec![10, 20]);
+        assert_eq!(buffer.state_at(&0), None);
+        assert_eq!(buffer.state_ref().data, vec![10, 20, 40, 50, 60]);
+    }
+
+    #[test]
+    fn test_update_batch() {
+        let mut buffer = ReversibleLagBuffer::<MyState, 4>::new(MyState::new());
+        for id in [1, 4, 6] {
+            buffer.update(insert(id));
+        }
+
+        let batch = buffer.update_batch([5, 2, 7].map(insert));
+        assert_eq!(batch.replayed, 4);
+        assert_eq!(buffer.state_ref().data, vec![10, 20, 40, 50, 60, 70]);
+        // Only 4 and 6 were reverted, once.
+        assert_eq!(buffer.state_ref().reverted, 2);
+        assert_eq!(buffer.oldest_reconcilable_key(), Some(2));
+    }
+
+    #[test]
+    fn test_retract() {
+        let mut buffer = ReversibleLagBuffer::<MyState, 4>::new(MyState::new());
+        for id in [1, 2, 3, 4] {
+            buffer.update(insert(id));
+        }
+
+        assert_eq!(buffer.retract(&2).map(|e| e.id), Some(2));
+        assert_eq!(buffer.state_ref().data, vec![10, 30, 40]);
+        assert_eq!(buffer.state_ref().reverted, 3);
+        assert_eq!(buffer.retract(&2).map(|e| e.id), None);
+    }
+
+    #[test]
+    fn test_amend() {
+        let mut buffer = ReversibleLagBuffer::<MyState, 4>::new(MyState::new());
+        for id in [1, 2, 3] {
+            buffer.update(insert(id));
+        }
+
+        let amended = buffer.amend(insert_value(2, 25)).unwrap();
+        assert!(matches!(
+            amended,
+            AmendOutcome::Replaced {
+                event: MyEvent { value: 20, .. },
+                replayed: 2
+            }
+        ));
+        assert_eq!(buffer.state_ref().data, vec![10, 25, 30]);
+        assert_eq!(buffer.state_ref().reverted, 2);
+    }
+
+    #[test]
+    fn test_tie_break() {
+        let mut buffer = ReversibleLagBuffer::<MyState, 8>::new(MyState::new()).with_tie_break(
+            TieBreak::SecondaryKey(Box::new(|a: &MyEvent, b: &MyEvent| a.value.cmp(&b.value))),
+        );
+        buffer.update(insert_value(1, 12));
+        buffer.update(insert_value(2, 20));
+        buffer.update(insert_value(1, 11));
+        assert_eq!(buffer.state_ref().data, vec![11, 12, 20]);
+
+        buffer.update_batch([insert_value(1, 10), insert_value(1, 13)]);
+        assert_eq!(buffer.state_ref().data, vec![10, 11, 12, 13, 20]);
+    }
+}
@@ -0,0 +1,404 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::{Event, ManualLagBuffer, State, TooLateError};
+
+/// A callback that predicts a missing input from the player index and their last real input.
+pub type PredictCallback<I> = Box<dyn FnMut(usize, Option<&I>) -> I + Send>;
+
+/// Decides which input a [`RollbackSession`] uses for a player whose real input hasn't arrived yet.
+///
+/// The predictor defaults to [`InputPredictor::RepeatLast`].
+#[derive(Default)]
+pub enum InputPredictor<I> {
+    /// Repeats the player's last real input, or uses `I::default()` if there is none yet.
+    #[default]
+    RepeatLast,
+    /// Asks the callback for the input.
+    Callback(PredictCallback<I>),
+}
+
+impl<I: Clone + Default> InputPredictor<I> {
+    fn predict(&mut self, player: usize, last_input: Option<&I>) -> I {
+        match self {
+            InputPredictor::RepeatLast => last_input.cloned().unwrap_or_default(),
+            InputPredictor::Callback(callback) => callback(player, last_input),
+        }
+    }
+}
+
+/// The inputs of all players for one frame.
+///
+/// This is the event a [`RollbackSession`] applies to its state, so the simulation step is an
+/// ordinary [`State::apply`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct FrameInput<I> {
+    /// The frame the inputs belong to.
+    pub frame: usize,
+    /// The input of every player, indexed by player.
+    pub inputs: Vec<I>,
+}
+
+impl<I> Event<usize> for FrameInput<I> {
+    fn get_order_key(&self) -> usize {
+        self.frame
+    }
+}
+
+/// The result of [`RollbackSession::advance_frame`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameAdvance {
+    /// The frame that was simulated.
+    pub frame: usize,
+    /// The number of earlier frames that were simulated again because a prediction was wrong.
+    pub rollback_depth: usize,
+}
+
+/// A rollback session for multi-player input, in the style of GGPO.
+///
+/// Every player has an input queue keyed by frame. [`advance_frame`](Self::advance_frame)
+/// simulates the next frame right away, predicting the input of every player whose real input
+/// hasn't arrived. When a real input for an already simulated frame differs from its prediction,
+/// the next call rolls back to that frame and simulates the frames after it again.
+///
+/// The simulated frames are kept in a [`ManualLagBuffer`] keyed by frame, with a snapshot after
+/// every frame. A frame is confirmed once the real inputs of all players up to and including it
+/// are known, and is then compacted into the buffer's base state. Confirmed frames are never
+/// rolled back, so their state is final. Unconfirmed frames are kept until they are confirmed,
+/// so a player that stops sending inputs grows the session; compare
+/// [`predicted_frame`](Self::predicted_frame) and [`confirmed_frame`](Self::confirmed_frame) to
+/// decide when to stall.
+///
+/// # Type Parameters
+///
+/// - `S`: The type of the state. Its events are the [`FrameInput`]s of the players.
+/// - `I`: The type of a single player's input.
+pub struct RollbackSession<S: State<usize, Event = FrameInput<I>>, I: Clone + PartialEq + Default> {
+    frames: ManualLagBuffer<S, 0>,
+    // Which inputs of every unconfirmed frame are predicted, oldest frame first
+    predicted: VecDeque<Vec<bool>>,
+    frame: usize,
+    pending: Vec<BTreeMap<usize, I>>,
+    confirmed_inputs: Vec<Option<I>>,
+    rollback_from: Option<usize>,
+    predictor: InputPredictor<I>,
+}
+
+impl<S: State<usize, Event = FrameInput<I>>, I: Clone + PartialEq + Default> RollbackSession<S, I> {
+    /// Creates a new `RollbackSession` for the given number of players, starting at frame 0.
+    pub fn new(initial_state: S, players: usize) -> Self {
+        Self {
+            frames: ManualLagBuffer::new(initial_state),
+            predicted: VecDeque::new(),
+            frame: 0,
+            pending: vec![BTreeMap::new(); players],
+            confirmed_inputs: vec![None; players],
+            rollback_from: None,
+            predictor: InputPredictor::default(),
+        }
+    }
+
+    /// Sets how missing inputs are predicted.
+    pub fn with_predictor(mut self, predictor: InputPredictor<I>) -> Self {
+        self.predictor = predictor;
+        self
+    }
+
+    /// Adds the real input of a player for a frame.
+    ///
+    /// If the frame was already simulated with a different input, it is rolled back on the next
+    /// [`advance_frame`](Self::advance_frame).
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`TooLateError`] holding the input if the frame is already confirmed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `player` is not a player of this session.
+    pub fn add_input(
+        &mut self,
+        player: usize,
+        frame: usize,
+        input: I,
+    ) -> Result<(), TooLateError<I>> {
+        assert!(player < self.players(), "Unknown player {player}");
+        if frame < self.first_unconfirmed_frame() {
+            return Err(TooLateError { event: input });
+        }
+
+        if frame >= self.frame {
+            self.pending[player].insert(frame, input);
+            return Ok(());
+        }
+
+        // Corrections wait in the input queue for the rollback
+        let index = frame - self.first_unconfirmed_frame();
+        let simulated = self.frames.events().nth(index).map(|e| &e.inputs[player]);
+        if simulated == Some(&input) {
+            self.pending[player].remove(&frame);
+            self.predicted[index][player] = false;
+            self.confirm_frames();
+        } else {
+            self.pending[player].insert(frame, input);
+            self.rollback_from = Some(self.rollback_from.map_or(frame, |from| from.min(frame)));
+        }
+        Ok(())
+    }
+
+    /// Simulates the next frame.
+    ///
+    /// Rolls back and simulates the affected frames again first if a prediction turned out to be
+    /// wrong. Players without a real input for the new frame get a predicted one.
+    pub fn advance_frame(&mut self) -> FrameAdvance {
+        let mut rollback_depth = 0;
+        if let Some(from) = self.rollback_from.take() {
+            // Retracting newest first leaves the snapshot before `from` as the current state
+            let mut rolled_back: Vec<_> = (from..self.frame)
+                .rev()
+                .filter_map(|frame| self.frames.retract(&frame))
+                .collect();
+            rolled_back.reverse();
+            let predicted = self
+                .predicted
+                .split_off(from - self.first_unconfirmed_frame());
+
+            // Predictions may change with the corrected inputs
+            for (input, predicted) in rolled_back.into_iter().zip(predicted) {
+                let real_inputs = input
+                    .inputs
+                    .into_iter()
+                    .zip(predicted)
+                    .map(|(input, predicted)| (!predicted).then_some(input))
+                    .collect();
+                self.simulate(input.frame, real_inputs);
+                rollback_depth += 1;
+            }
+        }
+
+        let frame = self.frame;
+        self.simulate(frame, vec![None; self.players()]);
+        self.frame += 1;
+        self.confirm_frames();
+
+        FrameAdvance {
+            frame,
+            rollback_depth,
+        }
+    }
+
+    /// Returns the number of players.
+    pub fn players(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Returns the newest frame whose inputs are all real, or `None` if there is none yet.
+    pub fn confirmed_frame(&self) -> Option<usize> {
+        self.frames.oldest_reconcilable_key()
+    }
+
+    /// Returns the newest simulated frame, or `None` if no frame was simulated yet.
+    pub fn predicted_frame(&self) -> Option<usize> {
+        self.frame.checked_sub(1)
+    }
+
+    /// Returns the state after the [`confirmed_frame`](Self::confirmed_frame).
+    pub fn confirmed_state(&self) -> &S {
+        self.frames.base_state()
+    }
+
+    /// Returns the state after the [`predicted_frame`](Self::predicted_frame).
+    ///
+    /// A pending rollback is only applied by the next [`advance_frame`](Self::advance_frame).
+    pub fn state_ref(&self) -> &S {
+        self.frames.state_ref()
+    }
+
+    fn first_unconfirmed_frame(&self) -> usize {
+        self.confirmed_frame()
+            .map_or(0, |confirmed_frame| confirmed_frame + 1)
+    }
+
+    // Simulates a frame on top of the current state. Inputs from the input queues replace the
+    // given real inputs, missing ones are predicted.
+    fn simulate(&mut self, frame: usize, real_inputs: Vec<Option<I>>) {
+        let mut inputs = Vec::with_capacity(self.players());
+        let mut predicted = Vec::with_capacity(self.players());
+        for (player, real_input) in real_inputs.into_iter().enumerate() {
+            match self.pending[player].remove(&frame).or(real_input) {
+                Some(input) => {
+                    inputs.push(input);
+                    predicted.push(false);
+                }
+                None => {
+                    let last_input = self.last_real_input(player).cloned();
+                    inputs.push(self.predictor.predict(player, last_input.as_ref()));
+                    predicted.push(true);
+                }
+            }
+        }
+
+        self.frames.update(FrameInput { frame, inputs });
+        self.frames.take_snapshot();
+        self.predicted.push_back(predicted);
+    }
+
+    // Returns the last real input of the player in the simulated frames.
+    fn last_real_input(&self, player: usize) -> Option<&I> {
+        self.frames
+            .events()
+            .zip(&self.predicted)
+            .filter(|(_, predicted)| !predicted[player])
+            .last()
+            .map(|(input, _)| &input.inputs[player])
+            .or(self.confirmed_inputs[player].as_ref())
+    }
+
+    // Compacts the oldest frames once all their inputs are real and their state is up to date.
+    fn confirm_frames(&mut self) {
+        let first_unconfirmed_frame = self.first_unconfirmed_frame();
+        let confirmed = self
+            .predicted
+            .iter()
+            .enumerate()
+            .take_while(|(index, predicted)| {
+                let frame = first_unconfirmed_frame + index;
+                self.rollback_from.is_none_or(|from| frame < from) && !predicted.contains(&true)
+            })
+            .count();
+        let Some(last_confirmed) = confirmed.checked_sub(1) else {
+            return;
+        };
+
+        if let Some(input) = self.frames.events().nth(last_confirmed) {
+            for (confirmed_input, input) in self.confirmed_inputs.iter_mut().zip(&input.inputs) {
+                *confirmed_input = Some(input.clone());
+            }
+        }
+        self.predicted.drain(..confirmed);
+        self.frames
+            .compact_before(&(first_unconfirmed_frame + confirmed));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    // Example State and Input implementation for testing.
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct MyState {
+        pub positions: Vec<i32>,
+        pub frames: Vec<usize>,
+    }
+
+    impl MyState {
+        pub fn new(players: usize) -> Self {
+            Self {
+                positions: vec![0; players],
+                frames: Vec::new(),
+            }
+        }
+    }
+
+    impl State<usize> for MyState {
+        type Event = FrameInput<i32>;
+
+        fn apply(&mut self, event: &Self::Event) {
+            for (position, input) in self.positions.iter_mut().zip(&event.inputs) {
+                *position += input;
+            }
+            self.frames.push(event.frame);
+        }
+    }
+
+    #[test]
+    fn test_rollback_on_misprediction() {
+        let mut session = RollbackSession::new(MyState::new(2), 2);
+
+        session.add_input(0, 0, 1).unwrap();
+        session.add_input(1, 0, 1).unwrap();
+        assert_eq!(
+            session.advance_frame(),
+            FrameAdvance {
+                frame: 0,
+                rollback_depth: 0
+            }
+        );
+        assert_eq!(session.confirmed_frame(), Some(0));
+
+        // The remote player falls behind, their last input is repeated.
+        for frame in 1..=2 {
+            session.add_input(0, frame, 1).unwrap();
+            assert_eq!(session.advance_frame().rollback_depth, 0);
+        }
+        assert_eq!(session.predicted_frame(), Some(2));
+        assert_eq!(session.confirmed_frame(), Some(0));
+        assert_eq!(session.state_ref().positions, vec![3, 3]);
+
+        // The prediction for frame 1 was right, nothing to roll back.
+        session.add_input(1, 1, 1).unwrap();
+        assert_eq!(session.confirmed_frame(), Some(1));
+
+        // The prediction for frame 2 was wrong.
+        session.add_input(1, 2, -1).unwrap();
+        assert_eq!(session.confirmed_frame(), Some(1));
+        session.add_input(0, 3, 1).unwrap();
+        assert_eq!(
+            session.advance_frame(),
+            FrameAdvance {
+                frame: 3,
+                rollback_depth: 1
+            }
+        );
+        assert_eq!(session.confirmed_frame(), Some(2));
+        assert_eq!(session.confirmed_state().positions, vec![3, 1]);
+        assert_eq!(session.state_ref().positions, vec![4, 0]);
+        assert_eq!(session.state_ref().frames, vec![0, 1, 2, 3]);
+
+        assert!(session.add_input(1, 2, 0).is_err());
+    }
+
+    #[test]
+    fn test_custom_predictor() {
+        let mut session = RollbackSession::new(MyState::new(2), 2).with_predictor(
+            InputPredictor::Callback(Box::new(|player, _| player as i32 * 10)),
+        );
+
+        session.add_input(0, 0, 1).unwrap();
+        session.advance_frame();
+        assert_eq!(session.state_ref().positions, vec![1, 10]);
+
+        session.add_input(1, 0, 10).unwrap();
+        assert_eq!(session.confirmed_frame(), Some(0));
+        assert_eq!(session.advance_frame().rollback_depth, 0);
+        assert_eq!(session.state_ref().positions, vec![1, 20]);
+    }
+
+    #[test]
+    fn test_rollback_repredicts_later_frames() {
+        let mut session = RollbackSession::new(MyState::new(2), 2);
+
+        session.add_input(0, 0, 1).unwrap();
+        session.add_input(1, 0, 1).unwrap();
+        for frame in 0..=3 {
+            session.add_input(0, frame, 1).unwrap();
+            session.advance_frame();
+        }
+        assert_eq!(session.state_ref().positions, vec![4, 4]);
+
+        // The remote player stopped at frame 1, so frames 2 and 3 repeat that input instead.
+        session.add_input(1, 1, 0).unwrap();
+        session.add_input(0, 4, 1).unwrap();
+        assert_eq!(
+            session.advance_frame(),
+            FrameAdvance {
+                frame: 4,
+                rollback_depth: 3
+            }
+        );
+        assert_eq!(session.confirmed_frame(), Some(1));
+        assert_eq!(session.confirmed_state().positions, vec![2, 1]);
+        assert_eq!(session.state_ref().positions, vec![5, 1]);
+        assert_eq!(session.state_ref().frames, vec![0, 1, 2, 3, 4]);
+    }
+}
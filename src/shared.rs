@@ -0,0 +1,196 @@
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+
+use crate::{LagBufferState, State, UpdateOutcome};
+
+/// A lag buffer that can be shared between threads.
+///
+/// Writers lock the wrapped buffer and go through its usual update logic. After every update,
+/// including the ones that replay events, the new state is published as a snapshot. Readers load
+/// the latest snapshot without taking the writers' lock, so they never wait for a replay to finish
+/// and always see the state of a completed update. Reads are not lock-free, though: the snapshot
+/// sits behind a `RwLock`, which is only held to swap or clone an `Arc`, and a read may briefly
+/// wait while a writer swaps it.
+///
+/// # Type Parameters
+///
+/// - `S`: The type of the state, which must implement the [`State`](trait.State.html) trait.
+/// - `B`: The lag buffer the writers update.
+/// - `OrderKey`: The type of the event's order key. Defaults to `usize`.
+///
+/// # Examples
+///
+/// ```rust
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// use lagbuffer::{DoubleBufferedLagBuffer, Event, SharedLagBuffer, State};
+///
+/// #[derive(Clone, Debug, PartialEq)]
+/// struct Counter(i32);
+///
+/// #[derive(Clone)]
+/// struct Add(usize, i32);
+///
+/// impl Event<usize> for Add {
+///     fn get_order_key(&self) -> usize {
+///         self.0
+///     }
+/// }
+///
+/// impl State<usize> for Counter {
+///     type Event = Add;
+///
+///     fn apply(&mut self, event: &Add) {
+///         self.0 += event.1;
+///     }
+/// }
+///
+/// let shared = Arc::new(SharedLagBuffer::new(
+///     DoubleBufferedLagBuffer::<Counter, 16>::new(Counter(0)),
+/// ));
+///
+/// let writer = {
+///     let shared = shared.clone();
+///     thread::spawn(move || {
+///         for key in (1..=8).rev() {
+///             shared.update(Add(key, 1));
+///         }
+///     })
+/// };
+/// writer.join().unwrap();
+///
+/// assert_eq!(*shared.snapshot(), Counter(8));
+/// ```
+pub struct SharedLagBuffer<S, B, OrderKey = usize>
+where
+    S: State<OrderKey>,
+    B: LagBufferState<S, OrderKey>,
+    OrderKey: Ord,
+{
+    buffer: Mutex<B>,
+    snapshot: RwLock<Arc<S>>,
+    _order_key: PhantomData<fn() -> OrderKey>,
+}
+
+impl<S, B, OrderKey> SharedLagBuffer<S, B, OrderKey>
+where
+    S: State<OrderKey>,
+    B: LagBufferState<S, OrderKey>,
+    OrderKey: Ord,
+{
+    /// Creates a new `SharedLagBuffer` around the given buffer and publishes its current state.
+    pub fn new(buffer: B) -> Self {
+        Self {
+            snapshot: RwLock::new(Arc::new(buffer.state())),
+            buffer: Mutex::new(buffer),
+            _order_key: PhantomData,
+        }
+    }
+
+    /// Updates the buffer with a new event and publishes the resulting state.
+    ///
+    /// Concurrent writers are serialized. Readers keep seeing the previous snapshot until the
+    /// update, including any replay, is complete.
+    ///
+    /// # Arguments
+    ///
+    /// - `event`: The event to be added to the buffer.
+    pub fn update(&self, event: S::Event) -> UpdateOutcome {
+        self.with_buffer(|buffer| buffer.update(event))
+    }
+
+    /// Runs a closure with exclusive access to the buffer and publishes the resulting state.
+    ///
+    /// This is the way to call the methods of the wrapped buffer that change it, e.g. `clear`
+    /// or `reset`.
+    pub fn with_buffer<R>(&self, f: impl FnOnce(&mut B) -> R) -> R {
+        let mut buffer = self.lock();
+        let result = f(&mut buffer);
+        let state = Arc::new(buffer.state());
+        *self
+            .snapshot
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = state;
+        result
+    }
+
+    /// Returns the state published by the latest completed update.
+    ///
+    /// This doesn't wait for a writer that is replaying events. It may block briefly while a
+    /// writer publishes its state, since `RwLock` can prefer the pending writer over new readers.
+    pub fn snapshot(&self) -> Arc<S> {
+        // Only an `Arc` is swapped under the lock, so a poisoned lock still holds a valid snapshot
+        self.snapshot
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Consumes the `SharedLagBuffer` and returns the wrapped buffer.
+    pub fn into_inner(self) -> B {
+        self.buffer
+            .into_inner()
+            .expect("a writer panicked while updating the buffer")
+    }
+
+    fn lock(&self) -> MutexGuard<'_, B> {
+        self.buffer
+            .lock()
+            .expect("a writer panicked while updating the buffer")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::testing::{insert_value, MyState};
+    use crate::DoubleBufferedLagBuffer;
+
+    #[test]
+    fn test_concurrent_readers() {
+        let shared = Arc::new(SharedLagBuffer::new(
+            DoubleBufferedLagBuffer::<MyState, 64>::new(MyState::new()),
+        ));
+
+        let writers: Vec<_> = (0..4)
+            .map(|writer| {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    for id in (0..10).map(|i| i * 4 + writer) {
+                        shared.update(insert_value(id, id as i32));
+                    }
+                })
+            })
+            .collect();
+        let reader = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    // Every snapshot is the state of a completed update.
+                    let snapshot = shared.snapshot();
+                    assert!(snapshot.data.len() <= 40);
+                }
+            })
+        };
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        reader.join().unwrap();
+
+        assert_eq!(shared.snapshot().data, (0..40).collect::<Vec<_>>());
+
+        let old = shared.snapshot();
+        shared.with_buffer(|buffer| buffer.clear());
+        assert_eq!(old.data.len(), 40);
+        assert_eq!(
+            *shared.snapshot(),
+            shared.with_buffer(|buffer| buffer.state())
+        );
+
+        let shared = Arc::into_inner(shared).unwrap();
+        assert_eq!(shared.into_inner().len(), 0);
+    }
+}
@@ -0,0 +1,260 @@
+use std::fmt::Write;
+
+use crate::UpdateOutcome;
+
+/// The upper bounds of the buckets of the replay histogram.
+///
+/// [`LagBufferStats::replay_histogram`] has one more bucket for everything above the last bound.
+pub const REPLAY_BUCKETS: [usize; 9] = [0, 1, 2, 4, 8, 16, 32, 64, 128];
+
+/// Reconciliation metrics collected by every lag buffer.
+///
+/// Buffers count every call to `update` and `try_update`. Lateness is the distance between the
+/// newest buffered key and the key of an out-of-order event, measured with
+/// [`KeyDistance::distance_units`](crate::KeyDistance::distance_units). Since not every order key
+/// can measure distances, buffers only record it after `with_lateness_stats` was called.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LagBufferStats {
+    /// The number of events passed to the buffer.
+    pub updates: u64,
+    /// The number of accepted events that were applied without replaying any events.
+    pub in_order: u64,
+    /// The number of accepted events that required a replay.
+    pub out_of_order: u64,
+    /// `replay_histogram[i]` counts the applications that replayed at most `REPLAY_BUCKETS[i]`
+    /// events and more than the previous bound. The last bucket counts everything above. Every
+    /// accepted event is one application, except for the events of a batch that are merged with
+    /// a single replay, which count as one application together.
+    pub replay_histogram: [u64; REPLAY_BUCKETS.len() + 1],
+    /// The total number of events replayed.
+    pub replayed: u64,
+    /// The number of out-of-order events whose lateness was measured.
+    pub lateness_samples: u64,
+    /// The largest lateness measured, in key units.
+    pub max_lateness: f64,
+    /// The sum of all lateness measured, in key units.
+    pub total_lateness: f64,
+    /// The number of updates that caused the buffer to retire its oldest events. A batch whose
+    /// merged events are reconciled with a single replay counts once.
+    pub swaps: u64,
+    /// The number of events that were not accepted, e.g. because they were too late.
+    pub drops: u64,
+}
+
+impl LagBufferStats {
+    /// Returns the mean lateness in key units, or `None` if no lateness was measured.
+    pub fn mean_lateness(&self) -> Option<f64> {
+        (self.lateness_samples > 0).then(|| self.total_lateness / self.lateness_samples as f64)
+    }
+
+    /// Renders the stats in the Prometheus text exposition format.
+    ///
+    /// # Arguments
+    ///
+    /// - `name`: The prefix of every metric name, e.g. `lagbuffer`.
+    pub fn to_prometheus(&self, name: &str) -> String {
+        let mut out = String::new();
+        let counters = [
+            ("updates", "Events passed to the buffer.", self.updates),
+            (
+                "in_order",
+                "Events applied without a replay.",
+                self.in_order,
+            ),
+            (
+                "out_of_order",
+                "Events that required a replay.",
+                self.out_of_order,
+            ),
+            (
+                "swaps",
+                "Events that retired the oldest events.",
+                self.swaps,
+            ),
+            ("drops", "Events that were not accepted.", self.drops),
+        ];
+        for (metric, help, value) in counters {
+            let _ = writeln!(out, "# HELP {name}_{metric}_total {help}");
+            let _ = writeln!(out, "# TYPE {name}_{metric}_total counter");
+            let _ = writeln!(out, "{name}_{metric}_total {value}");
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP {name}_replayed_events Events replayed per application."
+        );
+        let _ = writeln!(out, "# TYPE {name}_replayed_events histogram");
+        let mut cumulative = 0;
+        for (bound, count) in REPLAY_BUCKETS.iter().zip(&self.replay_histogram) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{name}_replayed_events_bucket{{le=\"{bound}\"}} {cumulative}"
+            );
+        }
+        cumulative += self.replay_histogram[REPLAY_BUCKETS.len()];
+        let _ = writeln!(
+            out,
+            "{name}_replayed_events_bucket{{le=\"+Inf\"}} {cumulative}"
+        );
+        let _ = writeln!(out, "{name}_replayed_events_sum {}", self.replayed);
+        let _ = writeln!(out, "{name}_replayed_events_count {cumulative}");
+
+        let gauges = [
+            (
+                "lateness_max",
+                "Largest lateness in key units.",
+                self.max_lateness,
+            ),
+            (
+                "lateness_mean",
+                "Mean lateness in key units.",
+                self.mean_lateness().unwrap_or(0.0),
+            ),
+        ];
+        for (metric, help, value) in gauges {
+            let _ = writeln!(out, "# HELP {name}_{metric} {help}");
+            let _ = writeln!(out, "# TYPE {name}_{metric} gauge");
+            let _ = writeln!(out, "{name}_{metric} {value}");
+        }
+        out
+    }
+
+    fn record(&mut self, outcome: Option<UpdateOutcome>, lateness: Option<f64>) {
+        self.updates += 1;
+        let Some(outcome) = outcome.filter(UpdateOutcome::is_accepted) else {
+            self.drops += 1;
+            return;
+        };
+
+        let replayed = outcome.replayed();
+        if replayed == 0 {
+            self.in_order += 1;
+        } else {
+            self.out_of_order += 1;
+        }
+        let bucket = REPLAY_BUCKETS.partition_point(|bound| *bound < replayed);
+        self.replay_histogram[bucket] += 1;
+        self.replayed += replayed as u64;
+
+        if let Some(lateness) = lateness.filter(|_| replayed > 0) {
+            self.lateness_samples += 1;
+            self.max_lateness = self.max_lateness.max(lateness);
+            self.total_lateness += lateness;
+        }
+    }
+
+    // Records an event that was reconciled by the replay of another event in the same batch
+    fn record_merged(&mut self, lateness: Option<f64>) {
+        self.updates += 1;
+        self.out_of_order += 1;
+        if let Some(lateness) = lateness {
+            self.lateness_samples += 1;
+            self.max_lateness = self.max_lateness.max(lateness);
+            self.total_lateness += lateness;
+        }
+    }
+}
+
+/// Measures the lateness of an event from the newest buffered key and the event's key.
+pub(crate) type LatenessFn<OrderKey> = fn(&OrderKey, &OrderKey) -> f64;
+
+// The stats of a buffer, together with the way it measures lateness.
+pub(crate) struct StatsCollector<OrderKey> {
+    pub(crate) stats: LagBufferStats,
+    pub(crate) lateness: Option<LatenessFn<OrderKey>>,
+}
+
+impl<OrderKey> Default for StatsCollector<OrderKey> {
+    fn default() -> Self {
+        Self {
+            stats: LagBufferStats::default(),
+            lateness: None,
+        }
+    }
+}
+
+impl<OrderKey> StatsCollector<OrderKey> {
+    pub(crate) fn record<E>(
+        &mut self,
+        result: &Result<UpdateOutcome, E>,
+        head_key: Option<&OrderKey>,
+        key: &OrderKey,
+    ) {
+        self.stats
+            .record(result.as_ref().ok().copied(), self.lateness(head_key, key));
+    }
+
+    // Records the events of a batch that were reconciled with a single replay. The replay is
+    // attributed to the first of them, so the histogram has one observation for the batch.
+    pub(crate) fn record_merged(
+        &mut self,
+        outcome: UpdateOutcome,
+        head_key: Option<&OrderKey>,
+        keys: &[OrderKey],
+    ) {
+        for (index, key) in keys.iter().enumerate() {
+            let lateness = self.lateness(head_key, key);
+            if index == 0 {
+                self.stats.record(Some(outcome), lateness);
+            } else {
+                self.stats.record_merged(lateness);
+            }
+        }
+    }
+
+    // Counts a swap where the buffer folds its oldest events, whatever outcome it reports
+    pub(crate) fn record_swap(&mut self) {
+        self.stats.swaps += 1;
+    }
+
+    fn lateness(&self, head_key: Option<&OrderKey>, key: &OrderKey) -> Option<f64> {
+        match (self.lateness, head_key) {
+            (Some(lateness), Some(head_key)) => Some(lateness(head_key, key)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let mut stats = LagBufferStats::default();
+        stats.record(Some(UpdateOutcome::AppliedInOrder), None);
+        stats.record(Some(UpdateOutcome::Reordered { replayed: 3 }), Some(2.0));
+        stats.record(Some(UpdateOutcome::Swapped { replayed: 200 }), Some(4.0));
+        stats.record(Some(UpdateOutcome::RejectedTooLate), Some(10.0));
+        stats.record(None, None);
+
+        assert_eq!(stats.updates, 5);
+        assert_eq!(stats.in_order, 1);
+        assert_eq!(stats.out_of_order, 2);
+        // Swaps are counted by the buffer where it folds, not from the outcome
+        assert_eq!(stats.swaps, 0);
+        assert_eq!(stats.drops, 2);
+        assert_eq!(stats.replayed, 203);
+        assert_eq!(stats.replay_histogram, [1, 0, 0, 1, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(stats.max_lateness, 4.0);
+        assert_eq!(stats.mean_lateness(), Some(3.0));
+    }
+
+    #[test]
+    fn test_to_prometheus() {
+        let mut stats = LagBufferStats::default();
+        stats.record(Some(UpdateOutcome::AppliedInOrder), None);
+        stats.record(Some(UpdateOutcome::Reordered { replayed: 3 }), Some(2.0));
+
+        let text = stats.to_prometheus("lagbuffer");
+        assert!(
+            text.contains("# TYPE lagbuffer_updates_total counter\nlagbuffer_updates_total 2\n")
+        );
+        assert!(text.contains("lagbuffer_replayed_events_bucket{le=\"2\"} 1\n"));
+        assert!(text.contains("lagbuffer_replayed_events_bucket{le=\"4\"} 2\n"));
+        assert!(text.contains("lagbuffer_replayed_events_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("lagbuffer_replayed_events_sum 3\n"));
+        assert!(text.contains("lagbuffer_lateness_max 2\n"));
+    }
+}
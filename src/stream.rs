@@ -0,0 +1,334 @@
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use futures_core::Stream;
+use pin_project_lite::pin_project;
+
+use crate::{LagBufferState, State, UpdateOutcome};
+
+// The head key shared between a stream and its watches.
+struct Head<OrderKey> {
+    key: Option<OrderKey>,
+    closed: bool,
+    wakers: Vec<Waker>,
+}
+
+/// A handle to the head key of a buffer that is driven by a [`StateStream`] or an
+/// [`OutcomeStream`].
+///
+/// Watches can be cloned and moved to other tasks, which can then wait for the buffer to reach
+/// a key while the stream is consumed elsewhere.
+pub struct HeadWatch<OrderKey> {
+    head: Arc<Mutex<Head<OrderKey>>>,
+}
+
+impl<OrderKey> Clone for HeadWatch<OrderKey> {
+    fn clone(&self) -> Self {
+        Self {
+            head: self.head.clone(),
+        }
+    }
+}
+
+impl<OrderKey: Ord + Clone> HeadWatch<OrderKey> {
+    fn new(key: Option<OrderKey>) -> Self {
+        Self {
+            head: Arc::new(Mutex::new(Head {
+                key,
+                closed: false,
+                wakers: Vec::new(),
+            })),
+        }
+    }
+
+    fn publish(&self, key: Option<OrderKey>, closed: bool) {
+        let mut head = self.head.lock().expect("head watch poisoned");
+        head.key = key;
+        head.closed = closed;
+        head.wakers.drain(..).for_each(Waker::wake);
+    }
+
+    /// Returns the key of the newest event in the buffer.
+    pub fn head_key(&self) -> Option<OrderKey> {
+        self.head.lock().expect("head watch poisoned").key.clone()
+    }
+
+    /// Returns a future that resolves once the head key of the buffer reached the given key.
+    ///
+    /// The future resolves to `true` if the key was reached and to `false` if the stream of
+    /// events ended before.
+    pub fn wait_for_key(&self, key: OrderKey) -> WaitForKey<OrderKey> {
+        WaitForKey {
+            head: self.head.clone(),
+            key,
+        }
+    }
+}
+
+/// The future returned by [`HeadWatch::wait_for_key`].
+pub struct WaitForKey<OrderKey> {
+    head: Arc<Mutex<Head<OrderKey>>>,
+    key: OrderKey,
+}
+
+impl<OrderKey: Ord> Future for WaitForKey<OrderKey> {
+    type Output = bool;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<bool> {
+        let mut head = self.head.lock().expect("head watch poisoned");
+        if head.key.as_ref().is_some_and(|key| *key >= self.key) {
+            Poll::Ready(true)
+        } else if head.closed {
+            Poll::Ready(false)
+        } else {
+            head.wakers.push(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+pin_project! {
+    /// Drives a lag buffer with a stream of events and yields the state after every event.
+    ///
+    /// Late events are reconciled like with [`update`](crate::BaseLagBuffer::update), so the
+    /// yielded state is always the state of all events received so far, in order.
+    ///
+    /// # Type Parameters
+    ///
+    /// - `St`: The stream of events.
+    /// - `S`: The type of the state, which must implement the [`State`](trait.State.html) trait.
+    /// - `B`: The lag buffer the events are passed to.
+    /// - `OrderKey`: The type of the event's order key. Defaults to `usize`.
+    pub struct StateStream<St, S, B, OrderKey = usize> {
+        #[pin]
+        events: St,
+        buffer: B,
+        watch: HeadWatch<OrderKey>,
+        _state: PhantomData<fn() -> S>,
+    }
+}
+
+impl<St, S, B, OrderKey> StateStream<St, S, B, OrderKey>
+where
+    St: Stream<Item = S::Event>,
+    S: State<OrderKey>,
+    B: LagBufferState<S, OrderKey>,
+    OrderKey: Ord + Clone,
+{
+    /// Creates a new `StateStream` that passes the events to the given buffer.
+    pub fn new(events: St, buffer: B) -> Self {
+        Self {
+            watch: HeadWatch::new(buffer.head_key()),
+            events,
+            buffer,
+            _state: PhantomData,
+        }
+    }
+
+    /// Returns a handle to wait for the buffer to reach a key.
+    pub fn watch(&self) -> HeadWatch<OrderKey> {
+        self.watch.clone()
+    }
+
+    /// Returns the buffer the events are passed to.
+    pub fn buffer(&self) -> &B {
+        &self.buffer
+    }
+
+    /// Consumes the `StateStream` and returns the buffer.
+    pub fn into_buffer(self) -> B {
+        self.buffer
+    }
+}
+
+impl<St, S, B, OrderKey> Stream for StateStream<St, S, B, OrderKey>
+where
+    St: Stream<Item = S::Event>,
+    S: State<OrderKey>,
+    B: LagBufferState<S, OrderKey>,
+    OrderKey: Ord + Clone,
+{
+    type Item = S;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S>> {
+        let this = self.project();
+        let event = std::task::ready!(this.events.poll_next(cx));
+        Poll::Ready(drive(this.buffer, this.watch, event).map(|_| this.buffer.state()))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.events.size_hint()
+    }
+}
+
+pin_project! {
+    /// Drives a lag buffer with a stream of events and yields the [`UpdateOutcome`] of every
+    /// event.
+    ///
+    /// # Type Parameters
+    ///
+    /// - `St`: The stream of events.
+    /// - `S`: The type of the state, which must implement the [`State`](trait.State.html) trait.
+    /// - `B`: The lag buffer the events are passed to.
+    /// - `OrderKey`: The type of the event's order key. Defaults to `usize`.
+    pub struct OutcomeStream<St, S, B, OrderKey = usize> {
+        #[pin]
+        events: St,
+        buffer: B,
+        watch: HeadWatch<OrderKey>,
+        _state: PhantomData<fn() -> S>,
+    }
+}
+
+impl<St, S, B, OrderKey> OutcomeStream<St, S, B, OrderKey>
+where
+    St: Stream<Item = S::Event>,
+    S: State<OrderKey>,
+    B: LagBufferState<S, OrderKey>,
+    OrderKey: Ord + Clone,
+{
+    /// Creates a new `OutcomeStream` that passes the events to the given buffer.
+    pub fn new(events: St, buffer: B) -> Self {
+        Self {
+            watch: HeadWatch::new(buffer.head_key()),
+            events,
+            buffer,
+            _state: PhantomData,
+        }
+    }
+
+    /// Returns a handle to wait for the buffer to reach a key.
+    pub fn watch(&self) -> HeadWatch<OrderKey> {
+        self.watch.clone()
+    }
+
+    /// Returns the buffer the events are passed to.
+    pub fn buffer(&self) -> &B {
+        &self.buffer
+    }
+
+    /// Consumes the `OutcomeStream` and returns the buffer.
+    pub fn into_buffer(self) -> B {
+        self.buffer
+    }
+}
+
+impl<St, S, B, OrderKey> Stream for OutcomeStream<St, S, B, OrderKey>
+where
+    St: Stream<Item = S::Event>,
+    S: State<OrderKey>,
+    B: LagBufferState<S, OrderKey>,
+    OrderKey: Ord + Clone,
+{
+    type Item = UpdateOutcome;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<UpdateOutcome>> {
+        let this = self.project();
+        let event = std::task::ready!(this.events.poll_next(cx));
+        Poll::Ready(drive(this.buffer, this.watch, event))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.events.size_hint()
+    }
+}
+
+// Passes the next event of a stream to the buffer and publishes the new head key, or closes the
+// watch when the stream ended.
+fn drive<S, B, OrderKey>(
+    buffer: &mut B,
+    watch: &HeadWatch<OrderKey>,
+    event: Option<S::Event>,
+) -> Option<UpdateOutcome>
+where
+    S: State<OrderKey>,
+    B: LagBufferState<S, OrderKey>,
+    OrderKey: Ord + Clone,
+{
+    let outcome = event.map(|event| buffer.update(event));
+    watch.publish(buffer.head_key(), outcome.is_none());
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use futures::channel::mpsc;
+    use futures::executor::{block_on, LocalPool};
+    use futures::stream::{self, StreamExt};
+    use futures::task::LocalSpawnExt;
+
+    use super::*;
+    use crate::testing::{insert, MyState};
+    use crate::DoubleBufferedLagBuffer;
+
+    #[test]
+    fn test_streams() {
+        let events = || stream::iter([1, 3, 2].map(insert));
+
+        let states = StateStream::new(
+            events(),
+            DoubleBufferedLagBuffer::<MyState, 8>::new(MyState::new()),
+        );
+        let states: Vec<_> = block_on(states.map(|state| state.data).collect());
+        assert_eq!(states, vec![vec![10], vec![10, 30], vec![10, 20, 30]]);
+
+        let outcomes = OutcomeStream::new(
+            events(),
+            DoubleBufferedLagBuffer::<MyState, 8>::new(MyState::new()),
+        );
+        let outcomes: Vec<_> = block_on(outcomes.collect());
+        assert_eq!(
+            outcomes,
+            vec![
+                UpdateOutcome::AppliedInOrder,
+                UpdateOutcome::AppliedInOrder,
+                UpdateOutcome::Reordered { replayed: 3 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_wait_for_key() {
+        let mut pool = LocalPool::new();
+        let (sender, receiver) = mpsc::unbounded();
+        let states = StateStream::new(
+            receiver,
+            DoubleBufferedLagBuffer::<MyState, 8>::new(MyState::new()),
+        );
+        let watch = states.watch();
+
+        let reached = Rc::new(RefCell::new(Vec::new()));
+        for key in [2, 5] {
+            let watch = watch.clone();
+            let reached = reached.clone();
+            pool.spawner()
+                .spawn_local(async move {
+                    let result = watch.wait_for_key(key).await;
+                    reached.borrow_mut().push((key, result));
+                })
+                .unwrap();
+        }
+        pool.spawner()
+            .spawn_local(states.for_each(|_| async {}))
+            .unwrap();
+
+        sender.unbounded_send(insert(1)).unwrap();
+        pool.run_until_stalled();
+        assert!(reached.borrow().is_empty());
+
+        sender.unbounded_send(insert(3)).unwrap();
+        pool.run_until_stalled();
+        assert_eq!(*reached.borrow(), vec![(2, true)]);
+        assert_eq!(watch.head_key(), Some(3));
+
+        drop(sender);
+        pool.run_until_stalled();
+        assert_eq!(*reached.borrow(), vec![(2, true), (5, false)]);
+    }
+}
@@ -0,0 +1,116 @@
+// Example State and Event implementation shared by the tests of the buffers and adapters.
+
+use crate::{Event, LagBufferObserver, ReversibleState, State};
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct MyState {
+    pub data: Vec<i32>,
+    pub reverted: usize,
+}
+
+impl MyState {
+    pub fn new() -> Self {
+        Self {
+            data: Vec::new(),
+            reverted: 0,
+        }
+    }
+}
+
+impl<K: Ord + Clone> State<K> for MyState {
+    type Event = MyEvent<K>;
+
+    fn apply(&mut self, event: &MyEvent<K>) {
+        match event.action {
+            Action::Insert => self.data.push(event.value),
+            Action::Replace => {
+                if let Some(pos) = self.data.iter().position(|&x| x == event.target) {
+                    self.data[pos] = event.value;
+                }
+            }
+        }
+    }
+}
+
+impl<K: Ord + Clone> ReversibleState<K> for MyState {
+    fn unapply(&mut self, event: &MyEvent<K>) {
+        match event.action {
+            Action::Insert => assert_eq!(self.data.pop(), Some(event.value)),
+            Action::Replace => {
+                if let Some(pos) = self.data.iter().position(|&x| x == event.value) {
+                    self.data[pos] = event.target;
+                }
+            }
+        }
+        self.reverted += 1;
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum Action {
+    Insert,
+    Replace,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct MyEvent<K = usize> {
+    pub id: K,
+    pub value: i32,
+    pub target: i32, // Used for replacing a specific element
+    pub action: Action,
+}
+
+impl<K: Ord + Clone> Event<K> for MyEvent<K> {
+    fn get_order_key(&self) -> K {
+        self.id.clone()
+    }
+}
+
+pub(crate) fn insert(id: usize) -> MyEvent {
+    insert_value(id, id as i32 * 10)
+}
+
+pub(crate) fn insert_value<K>(id: K, value: i32) -> MyEvent<K> {
+    MyEvent {
+        id,
+        value,
+        target: 0,
+        action: Action::Insert,
+    }
+}
+
+pub(crate) fn replace<K>(id: K, target: i32, value: i32) -> MyEvent<K> {
+    MyEvent {
+        id,
+        value,
+        target,
+        action: Action::Replace,
+    }
+}
+
+// An observer that records what it was told, events by their key.
+#[derive(Default)]
+pub(crate) struct Recorder {
+    pub applied: Vec<usize>,
+    pub rollbacks: Vec<(usize, usize)>,
+    pub swaps: usize,
+    pub dropped: Vec<usize>,
+}
+
+impl<S: State<usize>> LagBufferObserver<S> for Recorder {
+    fn on_apply(&mut self, event: &S::Event) {
+        self.applied.push(event.get_order_key());
+    }
+
+    fn on_rollback(&mut self, from_key: &usize, replayed: usize) {
+        self.rollbacks.push((*from_key, replayed));
+    }
+
+    fn on_swap(&mut self, _new_base: &S) {
+        self.swaps += 1;
+    }
+
+    fn on_drop(&mut self, event: &S::Event) {
+        self.dropped.push(event.get_order_key());
+    }
+}
@@ -0,0 +1,93 @@
+use std::cmp::Ordering;
+
+use crate::key::KeyOrder;
+use crate::{Event, State};
+
+/// A function that orders two events with equal order keys.
+pub type TieBreakFn<E> = Box<dyn Fn(&E, &E) -> Ordering + Send>;
+
+/// Decides how a lag buffer orders events with equal order keys.
+///
+/// Events are applied in key order, which leaves the order of events with equal keys open. Every
+/// buffer resolves such ties with its `TieBreak`, no matter if the events arrive in order, late or
+/// in a batch, so all buffer types reach the same state from the same events.
+///
+/// With [`ArrivalOrder`](TieBreak::ArrivalOrder), the state depends on the order in which tied
+/// events arrive. [`RejectDuplicates`](TieBreak::RejectDuplicates) suits keys that identify an
+/// event, e.g. when events may be delivered more than once, and
+/// [`SecondaryKey`](TieBreak::SecondaryKey) orders ties by the events themselves. An event that
+/// ties with the last event folded into the base state is placed after it, because the base can't
+/// be reconciled anymore.
+///
+/// The policy defaults to [`TieBreak::ArrivalOrder`].
+///
+/// # Type Parameters
+///
+/// - `S`: The type of the state, which must implement the [`State`](trait.State.html) trait.
+/// - `OrderKey`: The type of the event's order key. Defaults to `usize`.
+#[derive(Default)]
+pub enum TieBreak<S: State<OrderKey>, OrderKey: Ord = usize> {
+    /// Applies events with equal keys in the order they arrived.
+    #[default]
+    ArrivalOrder,
+    /// Keeps the first event with a key and discards later ones, including events with the key of
+    /// the last folded event. `update` reports [`UpdateOutcome::Duplicate`](crate::UpdateOutcome::Duplicate).
+    RejectDuplicates,
+    /// Orders events with equal keys with the function. Events it considers equal are applied in
+    /// the order they arrived.
+    SecondaryKey(TieBreakFn<S::Event>),
+}
+
+impl<S: State<OrderKey>, OrderKey: Ord> TieBreak<S, OrderKey> {
+    // Compares two events by key in the buffer's key order, then by the policy
+    pub(crate) fn compare(
+        &self,
+        keys: &KeyOrder<OrderKey>,
+        a: &S::Event,
+        b: &S::Event,
+    ) -> Ordering {
+        keys.cmp(&a.get_order_key(), &b.get_order_key())
+            .then_with(|| match self {
+                TieBreak::SecondaryKey(compare) => compare(a, b),
+                _ => Ordering::Equal,
+            })
+    }
+
+    // Returns `true` if `event` belongs after the buffered event
+    pub(crate) fn is_after(
+        &self,
+        keys: &KeyOrder<OrderKey>,
+        buffered: &S::Event,
+        event: &S::Event,
+    ) -> bool {
+        self.compare(keys, buffered, event).is_le()
+    }
+
+    // Returns where the event at `position` belongs once it replaced the oldest buffered event with
+    // its key. Only `SecondaryKey` orders it apart from the later events with the same key.
+    pub(crate) fn reposition<'a>(
+        &self,
+        keys: &KeyOrder<OrderKey>,
+        events: impl IntoIterator<Item = &'a S::Event>,
+        position: usize,
+    ) -> usize
+    where
+        S::Event: 'a,
+    {
+        if !matches!(self, TieBreak::SecondaryKey(_)) {
+            return position;
+        }
+        let events: Vec<_> = events.into_iter().collect();
+        let event = events[position];
+        position
+            + events[position + 1..]
+                .partition_point(|buffered| self.compare(keys, buffered, event).is_lt())
+    }
+
+    // Returns `true` if an event has to be discarded because the event it would be placed after
+    // has the same key. `previous` is the key of that event, or of the last folded event if there
+    // is none. That is never a watermark, since no event with its key may have been folded.
+    pub(crate) fn is_duplicate(&self, previous: Option<&OrderKey>, key: &OrderKey) -> bool {
+        matches!(self, TieBreak::RejectDuplicates) && previous == Some(key)
+    }
+}
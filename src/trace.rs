@@ -0,0 +1,33 @@
+// Diagnostics through `tracing`, compiled out entirely without the `tracing` feature.
+//
+// Buffers don't require order keys to implement `Debug`, so keys are only recorded once a buffer
+// is given a `KeyFormat` with `with_traced_keys`.
+
+// Formats an order key for a span.
+pub(crate) type KeyFormat<OrderKey> = fn(&OrderKey) -> String;
+
+// Enters a trace-level span that lasts until the end of the enclosing block.
+macro_rules! trace_span {
+    ($guard:ident, $name:literal $(, $($fields:tt)*)?) => {
+        #[cfg(feature = "tracing")]
+        let $guard = tracing::trace_span!($name $(, $($fields)*)?).entered();
+    };
+}
+
+// Records a field that was declared as `tracing::field::Empty` on a span entered above.
+macro_rules! trace_record {
+    ($guard:ident, $field:literal, $value:expr) => {
+        #[cfg(feature = "tracing")]
+        $guard.record($field, $value);
+    };
+}
+
+// Records the order key on the `key` field of a span entered above, if the buffer formats keys.
+macro_rules! trace_key {
+    ($guard:ident, $format:expr, $key:expr) => {
+        #[cfg(feature = "tracing")]
+        if let Some(format) = $format {
+            $guard.record("key", format($key).as_str());
+        }
+    };
+}